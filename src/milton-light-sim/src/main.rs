@@ -0,0 +1,443 @@
+#![warn(clippy::missing_docs_in_private_items)]
+
+//! A stand-in for the light controller firmware that runs on a host. It opens a pseudo-terminal
+//! that milton-web can be pointed at like any other serial device, answers requests the same way
+//! the firmware does (using the same `milton_xiao` types) and draws the strip in the terminal.
+
+use clap::Parser;
+use std::io::{self, Read, Write};
+use std::os::unix::io::FromRawFd;
+
+/// The largest number of leds the simulator can display; reported as the maximum led count.
+const MAX_LED_COUNT: usize = 256;
+
+/// While animating, how long to wait between drawing frames; matches the firmware.
+const FRAME_INTERVAL_MS: u64 = 20;
+
+/// How long a partially received request may go without another byte before it is failed; matches
+/// the firmware's packet timer.
+const PACKET_TIMEOUT_MS: u64 = 500;
+
+/// While failed, how long each blink of the first led lasts.
+const FAILED_BLINK_MS: u64 = 250;
+
+/// The size of the buffer used to encode framed responses; matches the firmware.
+const RESPONSE_FRAME_LEN: usize = 64;
+
+/// The options accepted on the command line.
+#[derive(clap::Parser)]
+#[command(author, version, about, long_about = None)]
+struct CommandLineOptions {
+  /// How many leds are lit until a led count request is received.
+  #[arg(short = 'n', long, default_value_t = 12)]
+  led_count: usize,
+
+  /// Creates a symlink to the pseudo-terminal at this path, so that the same `device` can be
+  /// configured across runs.
+  #[arg(short, long)]
+  link: Option<std::path::PathBuf>,
+
+  /// Appends every frame drawn to this file, one line per frame: the milliseconds since startup
+  /// followed by the color of each lit led, e.g `1520 #ff0000#ff0000#000000`.
+  #[arg(short, long)]
+  record: Option<std::path::PathBuf>,
+}
+
+/// Everything the firmware keeps track of, along with the bytes of a request that has not been
+/// completely received yet.
+struct Simulator {
+  /// The color, brightness and effects applied to the lights.
+  state: milton_xiao::LightState<MAX_LED_COUNT>,
+
+  /// The bytes of the request currently being received.
+  buffer: Vec<u8>,
+
+  /// When the last byte of the request currently being received arrived.
+  last_byte_ms: u64,
+
+  /// The last thing that went wrong, reported in response to status requests.
+  last_error: Option<milton_protocol::ErrorCode>,
+
+  /// Like the firmware, something going wrong blinks the first led red until the next byte.
+  failed: bool,
+}
+
+impl Simulator {
+  /// Creates the simulator with only the first `led_count` leds lit.
+  fn new(led_count: usize) -> Self {
+    Self {
+      state: milton_xiao::LightState::with_led_count(led_count.min(MAX_LED_COUNT)),
+      buffer: Vec::with_capacity(milton_protocol::MAX_FRAME_LEN),
+      last_byte_ms: 0,
+      last_error: None,
+      failed: false,
+    }
+  }
+
+  /// Records the error as the last thing that went wrong and starts blinking.
+  fn fail(&mut self, error: milton_protocol::ErrorCode) {
+    self.last_error = Some(error);
+    self.failed = true;
+  }
+
+  /// Queries are answered from the light state; everything else is acknowledged and applied.
+  fn handle(&mut self, request: milton_xiao::Request, now_ms: u64) -> milton_xiao::Response {
+    match request.state {
+      milton_xiao::StateRequest::Identify => self.state.identity(),
+      milton_xiao::StateRequest::Status => self.state.status(now_ms, self.last_error),
+      _ => {
+        self.state.apply(request, now_ms);
+        milton_xiao::Response::Roger
+      }
+    }
+  }
+
+  /// Receives a single byte, returning the request it completed (for display) and the bytes that
+  /// should be written back, if any.
+  fn receive(&mut self, byte: u8, now_ms: u64) -> Option<(String, Vec<u8>)> {
+    self.last_byte_ms = now_ms;
+
+    // The first byte of a request decides whether it is framed or text.
+    if self.buffer.is_empty() {
+      self.failed = false;
+      self.buffer.push(byte);
+      return None;
+    }
+
+    if self.buffer[0] == milton_protocol::SYNC {
+      return self.receive_framed(byte, now_ms);
+    }
+
+    if byte == b'\n' || byte == b':' {
+      let request = milton_xiao::Request::from_bytes(&self.buffer);
+      self.buffer.clear();
+
+      return match request {
+        Some(request) => {
+          let description = request.state.to_string();
+          let response = self.handle(request, now_ms);
+          Some((description, response.to_string().into_bytes()))
+        }
+        None => {
+          self.fail(milton_protocol::ErrorCode::Unrecognized);
+          Some((
+            "(unrecognized)".into(),
+            milton_xiao::Response::Failed.to_string().into_bytes(),
+          ))
+        }
+      };
+    }
+
+    if self.buffer.len() < milton_protocol::MAX_FRAME_LEN {
+      self.buffer.push(byte);
+    } else {
+      self.buffer.clear();
+      self.fail(milton_protocol::ErrorCode::Unrecognized);
+    }
+
+    None
+  }
+
+  /// Adds a byte to the frame being received, answering it once it has been completely received.
+  /// Framed requests are answered with framed responses, and a bad frame does not fail the lights.
+  fn receive_framed(&mut self, byte: u8, now_ms: u64) -> Option<(String, Vec<u8>)> {
+    if self.buffer.len() >= milton_protocol::MAX_FRAME_LEN {
+      self.buffer.clear();
+      self.last_error = Some(milton_protocol::ErrorCode::BadFrame);
+      return Some(("(bad frame)".into(), encode(milton_xiao::Response::Failed)));
+    }
+
+    self.buffer.push(byte);
+
+    let request = match milton_protocol::Frame::decode(&self.buffer) {
+      Err(milton_protocol::DecodeError::Incomplete) => return None,
+      Err(_) => Err(milton_protocol::ErrorCode::BadFrame),
+      Ok((frame, _)) => milton_xiao::Request::from_frame(&frame).ok_or(milton_protocol::ErrorCode::Unrecognized),
+    };
+
+    self.buffer.clear();
+
+    match request {
+      Ok(request) => {
+        let description = request.state.to_string();
+        let response = self.handle(request, now_ms);
+        Some((description, encode(response)))
+      }
+      Err(error) => {
+        self.last_error = Some(error);
+        Some((format!("({error})"), encode(milton_xiao::Response::Failed)))
+      }
+    }
+  }
+
+  /// Fails whatever request is being received if it has gone too long without another byte.
+  fn expire(&mut self, now_ms: u64) {
+    if !self.buffer.is_empty() && now_ms.saturating_sub(self.last_byte_ms) > PACKET_TIMEOUT_MS {
+      self.buffer.clear();
+      self.fail(milton_protocol::ErrorCode::Timeout);
+    }
+  }
+
+  /// Returns the colors of the lit leds, as they would be written to the strip.
+  fn frame(&self, now_ms: u64) -> Vec<smart_leds::RGB8> {
+    if self.failed {
+      let lit = (now_ms / FAILED_BLINK_MS) % 2 == 0;
+      let color = if lit {
+        smart_leds::RGB8::new(255, 0, 0)
+      } else {
+        smart_leds::RGB8::new(0, 0, 0)
+      };
+      return vec![color];
+    }
+
+    let mut frame = self.state.frame(now_ms).to_vec();
+    frame.truncate(self.state.led_count());
+    frame
+  }
+
+  /// Returns true if the frame changes over time, even without requests.
+  fn is_animating(&self, now_ms: u64) -> bool {
+    self.failed || self.state.is_animating(now_ms)
+  }
+}
+
+/// Encodes a framed response; responses always fit in `RESPONSE_FRAME_LEN`.
+fn encode(response: milton_xiao::Response) -> Vec<u8> {
+  let mut buffer = [0u8; RESPONSE_FRAME_LEN];
+
+  match response.encode(&mut buffer) {
+    Ok(size) => buffer[..size].to_vec(),
+    Err(error) => {
+      eprintln!("unable to encode {response} - {error:?}");
+      vec![]
+    }
+  }
+}
+
+/// Draws the strip on the current line of the terminal, as a block of color per led.
+fn draw(output: &mut impl Write, frame: &[smart_leds::RGB8]) -> io::Result<()> {
+  write!(output, "\r")?;
+
+  for led in frame {
+    write!(output, "\x1b[38;2;{};{};{}m██", led.r, led.g, led.b)?;
+  }
+
+  write!(output, "\x1b[0m\x1b[K")?;
+  output.flush()
+}
+
+/// Writes a line above the strip, which is redrawn by the next call to `draw`.
+fn note(output: &mut impl Write, now_ms: u64, message: &str) -> io::Result<()> {
+  writeln!(output, "\r\x1b[K[{:>8}ms] {message}", now_ms)
+}
+
+/// Appends the frame to the recording.
+fn record(output: &mut impl Write, now_ms: u64, frame: &[smart_leds::RGB8]) -> io::Result<()> {
+  write!(output, "{now_ms} ")?;
+
+  for led in frame {
+    write!(output, "#{:02x}{:02x}{:02x}", led.r, led.g, led.b)?;
+  }
+
+  writeln!(output)
+}
+
+/// Opens a pseudo-terminal in raw mode, returning the non-blocking controlling end and the path of
+/// the device that milton-web should open.
+fn open_pty() -> io::Result<(std::fs::File, std::path::PathBuf)> {
+  use nix::fcntl::{fcntl, FcntlArg, OFlag};
+  use nix::sys::termios;
+
+  let pty = nix::pty::openpty(None, None)?;
+
+  // Bytes have to make it through untouched; frames are binary.
+  let mut attributes = termios::tcgetattr(pty.slave)?;
+  termios::cfmakeraw(&mut attributes);
+  termios::tcsetattr(pty.slave, termios::SetArg::TCSANOW, &attributes)?;
+
+  fcntl(pty.master, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+  let path = nix::unistd::ttyname(pty.slave)?;
+
+  // The device end is intentionally never closed; reads from the controlling end fail whenever no
+  // one has the device open, which would otherwise happen every time milton-web reconnects.
+  let master = unsafe { std::fs::File::from_raw_fd(pty.master) };
+
+  Ok((master, path))
+}
+
+fn main() -> io::Result<()> {
+  let options = CommandLineOptions::parse();
+  let (mut pty, path) = open_pty()?;
+
+  if let Some(link) = &options.link {
+    if link.symlink_metadata().is_ok() {
+      std::fs::remove_file(link)?;
+    }
+
+    std::os::unix::fs::symlink(&path, link)?;
+  }
+
+  let mut recording = match &options.record {
+    Some(path) => Some(std::fs::OpenOptions::new().create(true).append(true).open(path)?),
+    None => None,
+  };
+
+  println!(
+    "simulating {} of {MAX_LED_COUNT} leds on '{}'{}",
+    options.led_count.min(MAX_LED_COUNT),
+    path.display(),
+    options
+      .link
+      .as_ref()
+      .map(|link| format!(" (linked at '{}')", link.display()))
+      .unwrap_or_default()
+  );
+
+  let started = std::time::Instant::now();
+  let mut simulator = Simulator::new(options.led_count);
+  let mut stdout = io::stdout();
+  let mut chunk = [0u8; milton_protocol::MAX_FRAME_LEN];
+  let mut last_frame: Option<Vec<smart_leds::RGB8>> = None;
+  let mut last_draw = 0u64;
+
+  loop {
+    let now = started.elapsed().as_millis() as u64;
+
+    let size = match pty.read(&mut chunk) {
+      Ok(size) => size,
+      Err(error) if error.kind() == io::ErrorKind::WouldBlock => 0,
+      Err(error) => return Err(error),
+    };
+
+    let mut noted = false;
+
+    for byte in &chunk[..size] {
+      if let Some((description, response)) = simulator.receive(*byte, now) {
+        note(&mut stdout, now, &description)?;
+        pty.write_all(&response)?;
+        noted = true;
+      }
+    }
+
+    simulator.expire(now);
+
+    let due = simulator.is_animating(now) && now.saturating_sub(last_draw) >= FRAME_INTERVAL_MS;
+
+    if noted || due || last_frame.is_none() {
+      let frame = simulator.frame(now);
+      let changed = last_frame.as_ref() != Some(&frame);
+
+      // Notes are written over the strip, so it is drawn again even if nothing changed.
+      if changed || noted {
+        last_draw = now;
+        draw(&mut stdout, &frame)?;
+      }
+
+      if let (true, Some(recording)) = (changed, recording.as_mut()) {
+        record(recording, now, &frame)?;
+      }
+
+      last_frame = Some(frame);
+    }
+
+    if size == 0 {
+      std::thread::sleep(std::time::Duration::from_millis(2));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Simulator, FAILED_BLINK_MS, PACKET_TIMEOUT_MS};
+  use milton_protocol::{CommandId, ErrorCode, Frame};
+  use smart_leds::RGB8;
+
+  /// Feeds the bytes to the simulator one at a time, returning every reply it made.
+  fn feed(simulator: &mut Simulator, bytes: &[u8], now_ms: u64) -> Vec<(String, Vec<u8>)> {
+    bytes
+      .iter()
+      .filter_map(|byte| simulator.receive(*byte, now_ms))
+      .collect()
+  }
+
+  /// Encodes a request frame, as milton-web would send it.
+  fn frame(command: CommandId, payload: &[u8]) -> Vec<u8> {
+    let mut buffer = vec![0; milton_protocol::MAX_FRAME_LEN];
+    let size = Frame::new(command, payload).encode(&mut buffer).expect("frame encodes");
+    buffer.truncate(size);
+    buffer
+  }
+
+  /// Decodes the command id of a framed reply.
+  fn reply(bytes: &[u8]) -> CommandId {
+    Frame::decode(bytes).expect("reply is a frame").0.command
+  }
+
+  #[test]
+  fn answers_text_requests() {
+    let mut simulator = Simulator::new(2);
+
+    let replies = feed(&mut simulator, b"brightness=255:red:", 0);
+    assert_eq!(replies.len(), 2);
+    assert_eq!(replies[1].1, b"ok\r\n");
+    assert_eq!(simulator.frame(0), [RGB8::new(255, 0, 0); 2]);
+
+    let replies = feed(&mut simulator, b"purple\n", 0);
+    assert_eq!(replies, [("(unrecognized)".to_string(), b"failed\r\n".to_vec())]);
+    assert_eq!(simulator.last_error, Some(ErrorCode::Unrecognized));
+    assert!(simulator.failed);
+  }
+
+  #[test]
+  fn answers_framed_requests() {
+    let mut simulator = Simulator::new(2);
+
+    let replies = feed(&mut simulator, &frame(CommandId::Identify, &[]), 0);
+    assert_eq!(replies.len(), 1);
+    assert_eq!(reply(&replies[0].1), CommandId::Identity);
+
+    let replies = feed(&mut simulator, &frame(CommandId::Rgb, &[0, 0, 255]), 0);
+    assert_eq!(replies.len(), 1);
+    assert_eq!(reply(&replies[0].1), CommandId::Ack);
+    assert_eq!(simulator.frame(0), [RGB8::new(0, 0, 100); 2]);
+  }
+
+  #[test]
+  fn bad_frames_are_rejected_without_failing_the_lights() {
+    let mut simulator = Simulator::new(2);
+    let mut corrupt = frame(CommandId::Rgb, &[0, 0, 255]);
+    let last = corrupt.len() - 1;
+    corrupt[last] ^= 0xff;
+
+    let replies = feed(&mut simulator, &corrupt, 0);
+    assert_eq!(replies.len(), 1);
+    assert_eq!(reply(&replies[0].1), CommandId::Nack);
+    assert_eq!(simulator.last_error, Some(ErrorCode::BadFrame));
+    assert!(!simulator.failed);
+    assert_eq!(simulator.frame(0), [RGB8::new(0, 0, 0); 2]);
+
+    let replies = feed(&mut simulator, &frame(CommandId::On, &[]), 0);
+    assert_eq!(reply(&replies[0].1), CommandId::Ack);
+  }
+
+  #[test]
+  fn unfinished_requests_expire_and_blink() {
+    let mut simulator = Simulator::new(2);
+    assert!(feed(&mut simulator, b"re", 0).is_empty());
+
+    simulator.expire(PACKET_TIMEOUT_MS);
+    assert!(!simulator.failed);
+
+    simulator.expire(PACKET_TIMEOUT_MS + 1);
+    assert!(simulator.failed);
+    assert_eq!(simulator.last_error, Some(ErrorCode::Timeout));
+    assert!(simulator.is_animating(0));
+    assert_eq!(simulator.frame(0), [RGB8::new(255, 0, 0)]);
+    assert_eq!(simulator.frame(FAILED_BLINK_MS), [RGB8::new(0, 0, 0)]);
+    assert_eq!(simulator.frame(FAILED_BLINK_MS * 2), [RGB8::new(255, 0, 0)]);
+
+    let replies = feed(&mut simulator, b"off:", 1000);
+    assert_eq!(replies.len(), 1);
+    assert!(!simulator.failed);
+  }
+}
@@ -0,0 +1,483 @@
+#![warn(clippy::missing_docs_in_private_items)]
+#![no_std]
+
+//! This library defines the framed serial protocol spoken between `milton-web` and the light
+//! controller firmware. Every frame is laid out as:
+//!
+//! ```text
+//! +------+---------+----------------+---------+-----------------+----------------+
+//! | sync | version | length (u16le) | command | payload         | crc16 (u16le)  |
+//! | 0xa5 | 0x01    | payload length | id      | `length` bytes  | version..=end  |
+//! +------+---------+----------------+---------+-----------------+----------------+
+//! ```
+//!
+//! The sync byte is never valid ascii, which allows the firmware to continue accepting the legacy
+//! text commands (e.g `on:`) on the same connection.
+
+/// The first byte of every frame.
+pub const SYNC: u8 = 0xa5;
+
+/// The version of the frame format described by this crate.
+pub const VERSION: u8 = 1;
+
+/// The number of bytes preceding the payload: sync, version, length and command id.
+pub const HEADER_LEN: usize = 5;
+
+/// The number of bytes following the payload.
+pub const CHECKSUM_LEN: usize = 2;
+
+/// The largest payload that can be sent in a single frame.
+pub const MAX_PAYLOAD_LEN: usize = 512;
+
+/// The largest complete frame, which is the size of the buffer needed to receive one.
+pub const MAX_FRAME_LEN: usize = HEADER_LEN + MAX_PAYLOAD_LEN + CHECKSUM_LEN;
+
+/// The most leds that can be addressed by a single `CommandId::Pixels` frame.
+pub const MAX_PIXELS: usize = MAX_PAYLOAD_LEN / 5;
+
+/// The most leds that can be colored by a single `CommandId::Frame` frame.
+pub const MAX_FRAME_PIXELS: usize = MAX_PAYLOAD_LEN / 3;
+
+/// Enumerates the command ids, along with the layout of the payload for each.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandId {
+  /// Turn the lights on; empty payload.
+  On = 0x01,
+
+  /// Turn the lights off; empty payload.
+  Off = 0x02,
+
+  /// Turn the lights red; empty payload.
+  Red = 0x03,
+
+  /// Turn the lights green; empty payload.
+  Green = 0x04,
+
+  /// Turn the lights blue; empty payload.
+  Blue = 0x05,
+
+  /// Turn the lights to an arbitrary color; `[red, green, blue]`.
+  Rgb = 0x06,
+
+  /// Change the brightness; `[level]`.
+  Brightness = 0x07,
+
+  /// Start an effect; `[effect id, arguments..]`, see `EffectId`.
+  Effect = 0x08,
+
+  /// Fade into another command; `[duration ms (u32le), command id, payload..]`.
+  Transition = 0x09,
+
+  /// Ask the firmware to identify itself; empty payload. Answered with `Identity`.
+  Identify = 0x0a,
+
+  /// Ask the firmware for its current state; empty payload. Answered with `StatusReport`.
+  Status = 0x0b,
+
+  /// Change how many leds are lit; `[count (u16le)]`. Counts above the firmware maximum are
+  /// clamped to it.
+  LedCount = 0x0c,
+
+  /// Set a contiguous range of leds to a single color; `[start (u16le), end (u16le), red, green,
+  /// blue]`, where `end` is exclusive.
+  Fill = 0x0d,
+
+  /// Set individual leds; `[index (u16le), red, green, blue]` repeated for up to `MAX_PIXELS`
+  /// leds.
+  Pixels = 0x0e,
+
+  /// Set every led from the first; `[red, green, blue]` repeated for up to `MAX_FRAME_PIXELS`
+  /// leds.
+  Frame = 0x0f,
+
+  /// Apply a color, effect or brightness to a contiguous range of leds; `[start (u16le), end
+  /// (u16le), command id, payload..]`, where `end` is exclusive and the command is one of `On`,
+  /// `Off`, `Red`, `Green`, `Blue`, `Rgb`, `Brightness` or `Effect`.
+  Zone = 0x10,
+
+  /// Sent by the firmware when a frame was accepted; empty payload.
+  Ack = 0x80,
+
+  /// Sent by the firmware when a frame was rejected; empty payload.
+  Nack = 0x81,
+
+  /// Sent by the firmware in response to `Identify`; `[protocol version, max led count (u16le),
+  /// active led count (u16le), firmware version (utf8)..]`.
+  Identity = 0x82,
+
+  /// Sent by the firmware in response to `Status`; `[brightness, uptime ms (u64le), last error
+  /// (see `ErrorCode`, 0 when none), command id, payload..]`, where the trailing command id and
+  /// payload describe the current color or effect. The payload is omitted for `Pixels` and `Frame`,
+  /// which may not fit.
+  StatusReport = 0x83,
+}
+
+impl CommandId {
+  /// Returns the command id represented by a byte, if it is one we know about.
+  pub fn from_byte(byte: u8) -> Option<Self> {
+    match byte {
+      0x01 => Some(Self::On),
+      0x02 => Some(Self::Off),
+      0x03 => Some(Self::Red),
+      0x04 => Some(Self::Green),
+      0x05 => Some(Self::Blue),
+      0x06 => Some(Self::Rgb),
+      0x07 => Some(Self::Brightness),
+      0x08 => Some(Self::Effect),
+      0x09 => Some(Self::Transition),
+      0x0a => Some(Self::Identify),
+      0x0b => Some(Self::Status),
+      0x0c => Some(Self::LedCount),
+      0x0d => Some(Self::Fill),
+      0x0e => Some(Self::Pixels),
+      0x0f => Some(Self::Frame),
+      0x10 => Some(Self::Zone),
+      0x80 => Some(Self::Ack),
+      0x81 => Some(Self::Nack),
+      0x82 => Some(Self::Identity),
+      0x83 => Some(Self::StatusReport),
+      _ => None,
+    }
+  }
+}
+
+/// Enumerates the effect ids used in the payload of `CommandId::Effect` frames.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EffectId {
+  /// Fade a color in and out; `[red, green, blue]`.
+  Breathe = 0x01,
+
+  /// Cycle through the color wheel; no arguments.
+  Rainbow = 0x02,
+
+  /// A lit led with a fading tail; `[red, green, blue]`.
+  Comet = 0x03,
+
+  /// Turn a color on and off; `[red, green, blue]`.
+  Blink = 0x04,
+
+  /// Fill the strip proportionally; `[percent, red, green, blue]`.
+  Progress = 0x05,
+}
+
+impl EffectId {
+  /// Returns the effect id represented by a byte, if it is one we know about.
+  pub fn from_byte(byte: u8) -> Option<Self> {
+    match byte {
+      0x01 => Some(Self::Breathe),
+      0x02 => Some(Self::Rainbow),
+      0x03 => Some(Self::Comet),
+      0x04 => Some(Self::Blink),
+      0x05 => Some(Self::Progress),
+      _ => None,
+    }
+  }
+}
+
+/// Enumerates the errors the firmware can report as the last thing that went wrong.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+  /// A request was received that could not be understood.
+  Unrecognized = 0x01,
+
+  /// A frame was received with a bad checksum, version or length.
+  BadFrame = 0x02,
+
+  /// A request was started but not completed in time.
+  Timeout = 0x03,
+
+  /// Writing to the leds failed.
+  LedWrite = 0x04,
+}
+
+impl ErrorCode {
+  /// Returns the error code represented by a byte, if it is one we know about.
+  pub fn from_byte(byte: u8) -> Option<Self> {
+    match byte {
+      0x01 => Some(Self::Unrecognized),
+      0x02 => Some(Self::BadFrame),
+      0x03 => Some(Self::Timeout),
+      0x04 => Some(Self::LedWrite),
+      _ => None,
+    }
+  }
+}
+
+impl core::fmt::Display for ErrorCode {
+  fn fmt(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+    match self {
+      Self::Unrecognized => write!(formatter, "unrecognized"),
+      Self::BadFrame => write!(formatter, "bad-frame"),
+      Self::Timeout => write!(formatter, "timeout"),
+      Self::LedWrite => write!(formatter, "led-write"),
+    }
+  }
+}
+
+/// Enumerates the kinds of things that can go wrong when encoding a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncodeError {
+  /// The payload is larger than `MAX_PAYLOAD_LEN`.
+  PayloadTooLong,
+
+  /// The buffer provided is smaller than the encoded frame.
+  BufferTooSmall,
+}
+
+impl core::fmt::Display for EncodeError {
+  fn fmt(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+    match self {
+      Self::PayloadTooLong => write!(formatter, "payload too long"),
+      Self::BufferTooSmall => write!(formatter, "buffer too small"),
+    }
+  }
+}
+
+/// Enumerates the kinds of things that can go wrong when decoding a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+  /// More bytes are needed before the frame can be decoded.
+  Incomplete,
+
+  /// The first byte was not `SYNC`.
+  MissingSync,
+
+  /// The frame was encoded with a version of the format we do not understand.
+  UnsupportedVersion(u8),
+
+  /// The length prefix is larger than `MAX_PAYLOAD_LEN`.
+  PayloadTooLong,
+
+  /// The command id is not one we know about.
+  UnknownCommand(u8),
+
+  /// The checksum did not match the contents of the frame.
+  Checksum,
+}
+
+impl core::fmt::Display for DecodeError {
+  fn fmt(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+    match self {
+      Self::Incomplete => write!(formatter, "incomplete frame"),
+      Self::MissingSync => write!(formatter, "missing sync byte"),
+      Self::UnsupportedVersion(version) => write!(formatter, "unsupported protocol version {version}"),
+      Self::PayloadTooLong => write!(formatter, "payload too long"),
+      Self::UnknownCommand(id) => write!(formatter, "unknown command id {id:#04x}"),
+      Self::Checksum => write!(formatter, "checksum mismatch"),
+    }
+  }
+}
+
+/// Computes the CRC-16/CCITT-FALSE checksum (polynomial `0x1021`, initial value `0xffff`).
+pub fn checksum(bytes: &[u8]) -> u16 {
+  bytes.iter().fold(0xffff, |crc, byte| {
+    (0..8).fold(crc ^ (u16::from(*byte) << 8), |crc, _| {
+      if crc & 0x8000 != 0 {
+        (crc << 1) ^ 0x1021
+      } else {
+        crc << 1
+      }
+    })
+  })
+}
+
+/// A single command, borrowing its payload from whatever buffer it was built from or decoded out
+/// of.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame<'a> {
+  /// What this frame is asking for.
+  pub command: CommandId,
+
+  /// The command-specific payload; see `CommandId` for the layout of each.
+  pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+  /// Creates a frame for the command and payload.
+  pub fn new(command: CommandId, payload: &'a [u8]) -> Self {
+    Self { command, payload }
+  }
+
+  /// Returns the number of bytes this frame occupies once encoded.
+  pub fn encoded_len(&self) -> usize {
+    HEADER_LEN + self.payload.len() + CHECKSUM_LEN
+  }
+
+  /// Writes the frame into the buffer, returning the number of bytes written.
+  pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, EncodeError> {
+    if self.payload.len() > MAX_PAYLOAD_LEN {
+      return Err(EncodeError::PayloadTooLong);
+    }
+
+    let total = self.encoded_len();
+
+    if buffer.len() < total {
+      return Err(EncodeError::BufferTooSmall);
+    }
+
+    let length = (self.payload.len() as u16).to_le_bytes();
+    buffer[0..HEADER_LEN].copy_from_slice(&[SYNC, VERSION, length[0], length[1], self.command as u8]);
+    buffer[HEADER_LEN..HEADER_LEN + self.payload.len()].copy_from_slice(self.payload);
+
+    let crc = checksum(&buffer[1..HEADER_LEN + self.payload.len()]).to_le_bytes();
+    buffer[total - CHECKSUM_LEN..total].copy_from_slice(&crc);
+
+    Ok(total)
+  }
+
+  /// Attempts to decode a frame from the start of the input, returning it along with the number of
+  /// bytes it occupied. `DecodeError::Incomplete` is returned until the whole frame is available.
+  pub fn decode(input: &'a [u8]) -> Result<(Self, usize), DecodeError> {
+    match input.first() {
+      None => return Err(DecodeError::Incomplete),
+      Some(&SYNC) => (),
+      Some(_) => return Err(DecodeError::MissingSync),
+    }
+
+    if input.len() < HEADER_LEN {
+      return Err(DecodeError::Incomplete);
+    }
+
+    if input[1] != VERSION {
+      return Err(DecodeError::UnsupportedVersion(input[1]));
+    }
+
+    let length = usize::from(u16::from_le_bytes([input[2], input[3]]));
+
+    if length > MAX_PAYLOAD_LEN {
+      return Err(DecodeError::PayloadTooLong);
+    }
+
+    let total = HEADER_LEN + length + CHECKSUM_LEN;
+
+    if input.len() < total {
+      return Err(DecodeError::Incomplete);
+    }
+
+    let expected = u16::from_le_bytes([input[total - 2], input[total - 1]]);
+
+    if checksum(&input[1..HEADER_LEN + length]) != expected {
+      return Err(DecodeError::Checksum);
+    }
+
+    let command = CommandId::from_byte(input[4]).ok_or(DecodeError::UnknownCommand(input[4]))?;
+
+    Ok((
+      Self {
+        command,
+        payload: &input[HEADER_LEN..HEADER_LEN + length],
+      },
+      total,
+    ))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{checksum, CommandId, DecodeError, EncodeError, Frame, MAX_FRAME_LEN, MAX_PAYLOAD_LEN, SYNC, VERSION};
+
+  /// Encodes a frame into a fresh buffer, returning it along with the encoded length.
+  fn encoded(frame: Frame) -> ([u8; MAX_FRAME_LEN], usize) {
+    let mut buffer = [0; MAX_FRAME_LEN];
+    let len = frame.encode(&mut buffer).expect("frame encodes");
+    (buffer, len)
+  }
+
+  #[test]
+  fn checksum_matches_the_check_vector() {
+    assert_eq!(checksum(b"123456789"), 0x29b1);
+    assert_eq!(checksum(&[]), 0xffff);
+  }
+
+  #[test]
+  fn frames_round_trip() {
+    let frame = Frame::new(CommandId::Rgb, &[0x12, 0x34, 0x56]);
+    let (buffer, len) = encoded(frame);
+
+    assert_eq!(len, frame.encoded_len());
+    assert_eq!(&buffer[..5], &[SYNC, VERSION, 3, 0, CommandId::Rgb as u8]);
+    assert_eq!(Frame::decode(&buffer[..len]), Ok((frame, len)));
+  }
+
+  #[test]
+  fn decodes_only_the_first_frame() {
+    let frame = Frame::new(CommandId::Off, &[]);
+    let (mut buffer, len) = encoded(frame);
+    buffer[len] = SYNC;
+
+    assert_eq!(Frame::decode(&buffer[..len + 1]), Ok((frame, len)));
+  }
+
+  #[test]
+  fn incomplete_until_the_whole_frame_arrives() {
+    let (buffer, len) = encoded(Frame::new(CommandId::Brightness, &[42]));
+
+    for end in 0..len {
+      assert_eq!(Frame::decode(&buffer[..end]), Err(DecodeError::Incomplete));
+    }
+  }
+
+  #[test]
+  fn rejects_bad_checksums() {
+    let (mut buffer, len) = encoded(Frame::new(CommandId::Brightness, &[42]));
+    buffer[5] = 43;
+
+    assert_eq!(Frame::decode(&buffer[..len]), Err(DecodeError::Checksum));
+  }
+
+  #[test]
+  fn rejects_missing_sync() {
+    let (mut buffer, len) = encoded(Frame::new(CommandId::On, &[]));
+    buffer[0] = b'o';
+
+    assert_eq!(Frame::decode(&buffer[..len]), Err(DecodeError::MissingSync));
+  }
+
+  #[test]
+  fn rejects_unsupported_versions() {
+    let (mut buffer, len) = encoded(Frame::new(CommandId::On, &[]));
+    buffer[1] = VERSION + 1;
+
+    assert_eq!(
+      Frame::decode(&buffer[..len]),
+      Err(DecodeError::UnsupportedVersion(VERSION + 1))
+    );
+  }
+
+  #[test]
+  fn payloads_up_to_the_maximum_length() {
+    let payload = [0x5a; MAX_PAYLOAD_LEN + 1];
+    let frame = Frame::new(CommandId::Effect, &payload[..MAX_PAYLOAD_LEN]);
+    let (buffer, len) = encoded(frame);
+
+    assert_eq!(len, MAX_FRAME_LEN);
+    assert_eq!(Frame::decode(&buffer), Ok((frame, len)));
+
+    let mut buffer = [0; MAX_FRAME_LEN + 1];
+    assert_eq!(
+      Frame::new(CommandId::Effect, &payload).encode(&mut buffer),
+      Err(EncodeError::PayloadTooLong)
+    );
+  }
+
+  #[test]
+  fn rejects_payload_lengths_over_the_maximum() {
+    let length = (MAX_PAYLOAD_LEN as u16 + 1).to_le_bytes();
+    let header = [SYNC, VERSION, length[0], length[1], CommandId::Effect as u8];
+
+    assert_eq!(Frame::decode(&header), Err(DecodeError::PayloadTooLong));
+  }
+
+  #[test]
+  fn rejects_small_buffers() {
+    let mut buffer = [0; 6];
+
+    assert_eq!(
+      Frame::new(CommandId::Rgb, &[1, 2, 3]).encode(&mut buffer),
+      Err(EncodeError::BufferTooSmall)
+    );
+  }
+}
@@ -0,0 +1,343 @@
+//! Animated effects are rendered as a pure function of the time, in milliseconds, since the effect
+//! was started. Nothing here holds state between frames, which means any frame can be rendered on
+//! its own.
+
+use smart_leds::RGB8;
+
+/// How long a single inhale + exhale of the breathe effect takes.
+const BREATHE_PERIOD_MS: u64 = 4000;
+
+/// How long it takes the rainbow effect to cycle through every hue.
+const RAINBOW_PERIOD_MS: u64 = 5000;
+
+/// How long it takes the head of the comet effect to travel the length of the strip.
+const COMET_PERIOD_MS: u64 = 2000;
+
+/// How many leds (including the head) make up the comet.
+const COMET_LENGTH: usize = 4;
+
+/// How long a single on + off cycle of the blink effect takes.
+const BLINK_PERIOD_MS: u64 = 1000;
+
+/// Enumerates the animated effects that can be requested.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Effect {
+  /// Fade a color in and out.
+  Breathe(RGB8),
+
+  /// Cycle every led through the color wheel, offset from one another.
+  Rainbow,
+
+  /// A single lit led followed by a fading tail, travelling along the strip.
+  Comet(RGB8),
+
+  /// Turn a color on and off.
+  Blink(RGB8),
+
+  /// Fill the strip proportionally to a percentage (0-100).
+  Progress(u8, RGB8),
+}
+
+impl Effect {
+  /// Attempts to parse an effect, e.g `breathe=#ff0000`, `rainbow` or `progress=42,#00ff00`.
+  pub(crate) fn parse(input: &str) -> Option<Self> {
+    let (name, argument) = input.split_once('=').unwrap_or((input, ""));
+
+    match (name, argument) {
+      ("rainbow", "") => Some(Self::Rainbow),
+      ("breathe", color) => crate::parse_hex(color.strip_prefix('#')?).map(Self::Breathe),
+      ("comet", color) => crate::parse_hex(color.strip_prefix('#')?).map(Self::Comet),
+      ("blink", color) => crate::parse_hex(color.strip_prefix('#')?).map(Self::Blink),
+      ("progress", argument) => {
+        let (percent, color) = match argument.split_once(",#") {
+          Some((percent, color)) => (percent, crate::parse_hex(color)?),
+          None => (argument, RGB8::new(255, 255, 255)),
+        };
+        let percent = percent.parse::<u8>().ok()?;
+        (percent <= 100).then_some(Self::Progress(percent, color))
+      }
+      _ => None,
+    }
+  }
+
+  /// Attempts to build an effect from the id and arguments of a `CommandId::Effect` payload.
+  pub(crate) fn from_payload(id: u8, arguments: &[u8]) -> Option<Self> {
+    use milton_protocol::EffectId;
+
+    match (EffectId::from_byte(id)?, arguments) {
+      (EffectId::Breathe, [red, green, blue]) => Some(Self::Breathe(RGB8::new(*red, *green, *blue))),
+      (EffectId::Rainbow, []) => Some(Self::Rainbow),
+      (EffectId::Comet, [red, green, blue]) => Some(Self::Comet(RGB8::new(*red, *green, *blue))),
+      (EffectId::Blink, [red, green, blue]) => Some(Self::Blink(RGB8::new(*red, *green, *blue))),
+      (EffectId::Progress, [percent, red, green, blue]) if *percent <= 100 => {
+        Some(Self::Progress(*percent, RGB8::new(*red, *green, *blue)))
+      }
+      _ => None,
+    }
+  }
+
+  /// Writes the `[effect id, arguments..]` payload of a `CommandId::Effect` frame for this effect
+  /// into the buffer, returning the number of bytes written. The buffer must hold at least 5 bytes.
+  pub(crate) fn payload(&self, buffer: &mut [u8]) -> usize {
+    use milton_protocol::EffectId;
+
+    let (id, arguments, len) = match self {
+      Self::Breathe(color) => (EffectId::Breathe, [color.r, color.g, color.b, 0], 3),
+      Self::Rainbow => (EffectId::Rainbow, [0; 4], 0),
+      Self::Comet(color) => (EffectId::Comet, [color.r, color.g, color.b, 0], 3),
+      Self::Blink(color) => (EffectId::Blink, [color.r, color.g, color.b, 0], 3),
+      Self::Progress(percent, color) => (EffectId::Progress, [*percent, color.r, color.g, color.b], 4),
+    };
+
+    buffer[0] = id as u8;
+    buffer[1..=len].copy_from_slice(&arguments[..len]);
+    len + 1
+  }
+
+  /// Returns true if the frames rendered for this effect change over time.
+  pub fn is_animated(&self) -> bool {
+    !matches!(self, Self::Progress(..))
+  }
+
+  /// Renders this effect onto the leds, `t_ms` milliseconds after it was started.
+  pub fn render(&self, t_ms: u64, out: &mut [RGB8]) {
+    match self {
+      Self::Breathe(color) => breathe(*color, t_ms, out),
+      Self::Rainbow => rainbow(t_ms, out),
+      Self::Comet(color) => comet(*color, t_ms, out),
+      Self::Blink(color) => blink(*color, t_ms, out),
+      Self::Progress(percent, color) => progress(*color, *percent, out),
+    }
+  }
+}
+
+impl core::fmt::Display for Effect {
+  fn fmt(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+    match self {
+      Self::Breathe(color) => write!(formatter, "breathe=#{:02x}{:02x}{:02x}", color.r, color.g, color.b),
+      Self::Rainbow => write!(formatter, "rainbow"),
+      Self::Comet(color) => write!(formatter, "comet=#{:02x}{:02x}{:02x}", color.r, color.g, color.b),
+      Self::Blink(color) => write!(formatter, "blink=#{:02x}{:02x}{:02x}", color.r, color.g, color.b),
+      Self::Progress(percent, color) => write!(
+        formatter,
+        "progress={percent},#{:02x}{:02x}{:02x}",
+        color.r, color.g, color.b
+      ),
+    }
+  }
+}
+
+/// Scales each channel of a color by `level / 255`.
+pub(crate) fn scale(color: RGB8, level: u8) -> RGB8 {
+  let channel = |value: u8| (u16::from(value) * u16::from(level) / 255) as u8;
+  RGB8::new(channel(color.r), channel(color.g), channel(color.b))
+}
+
+/// Maps a position on the color wheel (0-255) to a fully saturated color.
+fn wheel(position: u8) -> RGB8 {
+  match position {
+    0..=84 => RGB8::new(255 - position * 3, position * 3, 0),
+    85..=169 => RGB8::new(0, 255 - (position - 85) * 3, (position - 85) * 3),
+    _ => RGB8::new((position - 170) * 3, 0, 255 - (position - 170) * 3),
+  }
+}
+
+/// Every led shows `color`, with a level that ramps up and back down once per period. The ramp is
+/// squared so that the change in perceived brightness is closer to linear.
+pub fn breathe(color: RGB8, t_ms: u64, out: &mut [RGB8]) {
+  let half = BREATHE_PERIOD_MS / 2;
+  let phase = t_ms % BREATHE_PERIOD_MS;
+  let ramp = if phase < half { phase } else { BREATHE_PERIOD_MS - phase };
+  let level = ramp * 255 / half;
+  out.fill(scale(color, (level * level / 255) as u8));
+}
+
+/// Every led cycles through the color wheel, offset so that the strip shows one full rainbow.
+pub fn rainbow(t_ms: u64, out: &mut [RGB8]) {
+  let offset = (t_ms % RAINBOW_PERIOD_MS) * 256 / RAINBOW_PERIOD_MS;
+  let count = out.len() as u64;
+
+  for (index, item) in out.iter_mut().enumerate() {
+    let position = offset + (index as u64 * 256 / count);
+    *item = wheel((position % 256) as u8);
+  }
+}
+
+/// A comet whose head travels the length of the strip once per period, wrapping around at the end,
+/// with a tail that fades out behind it.
+pub fn comet(color: RGB8, t_ms: u64, out: &mut [RGB8]) {
+  let count = out.len();
+
+  if count == 0 {
+    return;
+  }
+
+  let head = ((t_ms % COMET_PERIOD_MS) * count as u64 / COMET_PERIOD_MS) as usize;
+
+  for (index, item) in out.iter_mut().enumerate() {
+    let distance = (head + count - index) % count;
+
+    *item = if distance < COMET_LENGTH {
+      scale(color, (255 * (COMET_LENGTH - distance) / COMET_LENGTH) as u8)
+    } else {
+      RGB8::default()
+    };
+  }
+}
+
+/// Every led shows `color` for the first half of each period, and is off for the second.
+pub fn blink(color: RGB8, t_ms: u64, out: &mut [RGB8]) {
+  if t_ms % BLINK_PERIOD_MS < BLINK_PERIOD_MS / 2 {
+    out.fill(color);
+  } else {
+    out.fill(RGB8::default());
+  }
+}
+
+/// Fills the strip from the start in proportion to `percent`; the led at the edge of the fill is
+/// partially lit so that progress is visible between whole leds.
+pub fn progress(color: RGB8, percent: u8, out: &mut [RGB8]) {
+  let filled = out.len() * 255 * usize::from(percent.min(100)) / 100;
+
+  for (index, item) in out.iter_mut().enumerate() {
+    let level = filled.saturating_sub(index * 255).min(255);
+    *item = scale(color, level as u8);
+  }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+  use super::{blink, breathe, comet, progress, rainbow, Effect};
+  use smart_leds::RGB8;
+
+  const WHITE: RGB8 = RGB8::new(255, 255, 255);
+  const OFF: RGB8 = RGB8::new(0, 0, 0);
+
+  #[test]
+  fn breathe_ramps_up_and_down() {
+    let mut out = [OFF; 3];
+
+    breathe(RGB8::new(200, 100, 0), 0, &mut out);
+    assert_eq!(out, [OFF; 3]);
+
+    breathe(RGB8::new(200, 100, 0), 1000, &mut out);
+    assert_eq!(out, [RGB8::new(49, 24, 0); 3]);
+
+    breathe(RGB8::new(200, 100, 0), 2000, &mut out);
+    assert_eq!(out, [RGB8::new(200, 100, 0); 3]);
+
+    breathe(RGB8::new(200, 100, 0), 4000, &mut out);
+    assert_eq!(out, [OFF; 3]);
+  }
+
+  #[test]
+  fn rainbow_spreads_the_wheel_over_the_strip() {
+    let mut out = [OFF; 4];
+
+    rainbow(0, &mut out);
+    assert_eq!(
+      out,
+      [
+        RGB8::new(255, 0, 0),
+        RGB8::new(63, 192, 0),
+        RGB8::new(0, 126, 129),
+        RGB8::new(66, 0, 189),
+      ]
+    );
+
+    let mut later = [OFF; 4];
+    rainbow(5000, &mut later);
+    assert_eq!(later, out);
+  }
+
+  #[test]
+  fn comet_fades_out_behind_the_head() {
+    let mut out = [OFF; 8];
+
+    comet(WHITE, 0, &mut out);
+    assert_eq!(
+      out,
+      [
+        WHITE,
+        OFF,
+        OFF,
+        OFF,
+        OFF,
+        RGB8::new(63, 63, 63),
+        RGB8::new(127, 127, 127),
+        RGB8::new(191, 191, 191),
+      ]
+    );
+
+    comet(WHITE, 1000, &mut out);
+    assert_eq!(
+      out,
+      [
+        OFF,
+        RGB8::new(63, 63, 63),
+        RGB8::new(127, 127, 127),
+        RGB8::new(191, 191, 191),
+        WHITE,
+        OFF,
+        OFF,
+        OFF,
+      ]
+    );
+  }
+
+  #[test]
+  fn blink_is_on_for_the_first_half_of_each_period() {
+    let mut out = [OFF; 2];
+
+    blink(WHITE, 0, &mut out);
+    assert_eq!(out, [WHITE; 2]);
+
+    blink(WHITE, 500, &mut out);
+    assert_eq!(out, [OFF; 2]);
+
+    blink(WHITE, 1499, &mut out);
+    assert_eq!(out, [WHITE; 2]);
+  }
+
+  #[test]
+  fn progress_fills_in_proportion() {
+    let mut out = [WHITE; 4];
+
+    progress(WHITE, 0, &mut out);
+    assert_eq!(out, [OFF; 4]);
+
+    progress(WHITE, 50, &mut out);
+    assert_eq!(out, [WHITE, WHITE, OFF, OFF]);
+
+    progress(WHITE, 100, &mut out);
+    assert_eq!(out, [WHITE; 4]);
+
+    progress(WHITE, 200, &mut out);
+    assert_eq!(out, [WHITE; 4]);
+  }
+
+  #[test]
+  fn progress_partially_lights_the_edge() {
+    let mut out = [OFF; 10];
+
+    progress(WHITE, 25, &mut out);
+    assert_eq!(&out[..4], &[WHITE, WHITE, RGB8::new(127, 127, 127), OFF]);
+  }
+
+  #[test]
+  fn renders_nothing_without_leds() {
+    let effects = [
+      Effect::Breathe(WHITE),
+      Effect::Rainbow,
+      Effect::Comet(WHITE),
+      Effect::Blink(WHITE),
+      Effect::Progress(0, WHITE),
+      Effect::Progress(50, WHITE),
+      Effect::Progress(100, WHITE),
+    ];
+
+    for effect in effects {
+      effect.render(1234, &mut []);
+    }
+  }
+}
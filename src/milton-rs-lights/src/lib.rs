@@ -1,9 +1,40 @@
 #![warn(clippy::missing_docs_in_private_items)]
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 
 //! This library provides the application-level abstractions that will be used by the main runtime
 //! itself.
 
+/// Pure, time-driven rendering functions for animated effects.
+pub mod effects;
+
+/// Requests that address individual leds or ranges of them.
+pub mod pixels;
+
+/// Colors, effects and brightness scoped to a range of leds.
+pub mod zones;
+
+/// The version of this firmware, reported in response to `StateRequest::Identify`.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The brightness used until a `StateRequest::Brightness` request has been received.
+pub const DEFAULT_BRIGHTNESS: u8 = 100;
+
+/// Parses the decimal led counts provided through the environment at compile time; anything that
+/// is not a number fails the build.
+pub const fn parse_count(input: &str) -> usize {
+  let bytes = input.as_bytes();
+  let mut index = 0;
+  let mut count = 0;
+
+  while index < bytes.len() {
+    assert!(bytes[index].is_ascii_digit(), "led counts must be decimal numbers");
+    count = count * 10 + (bytes[index] - b'0') as usize;
+    index += 1;
+  }
+
+  count
+}
+
 /// Enumerates the various kinds of state requests we can receive from our serial interrupt.
 #[derive(Clone)]
 pub enum StateRequest {
@@ -21,14 +52,59 @@ pub enum StateRequest {
 
   /// Turn the lights blue.
   Blue,
+
+  /// Turn the lights to an arbitrary red, green and blue value.
+  Rgb(u8, u8, u8),
+
+  /// Change the brightness (0-255) of the lights without changing their color.
+  Brightness(u8),
+
+  /// Animate the lights with one of our effects.
+  Effect(effects::Effect),
+
+  /// Change how many leds are lit; the rest of the strip is left dark.
+  LedCount(u16),
+
+  /// Set the leds from the first index up to, but not including, the second to a color.
+  Fill(u16, u16, smart_leds::RGB8),
+
+  /// Set individual leds to individual colors.
+  Pixels(pixels::PixelList),
+
+  /// Set the leds, starting from the first, to a list of colors.
+  Frame(pixels::FramePixels),
+
+  /// Apply a color, effect or brightness to the leds from the first index up to, but not
+  /// including, the second.
+  Zone(u16, u16, zones::ZoneRequest),
+
+  /// Report the firmware version, protocol version and led count; answered with
+  /// `Response::Identity`.
+  Identify,
+
+  /// Report the current color or effect, brightness, uptime and last error; answered with
+  /// `Response::Status`.
+  Status,
 }
 
 /// Enumerates the kinds of things that can go wrong when parsing requests.
+#[derive(Debug)]
 pub enum StateRequestParseError {
   /// Whatever was present in the `str` that we attempted to parse as a request was invalid.
   Unrecognized,
 }
 
+impl core::fmt::Display for StateRequestParseError {
+  fn fmt(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+    match self {
+      Self::Unrecognized => write!(formatter, "unrecognized request"),
+    }
+  }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for StateRequestParseError {}
+
 impl core::str::FromStr for StateRequest {
   type Err = StateRequestParseError;
 
@@ -39,11 +115,71 @@ impl core::str::FromStr for StateRequest {
       "red" | "Red" | "RED" => Ok(Self::Red),
       "blue" | "Blue" | "BLUE" => Ok(Self::Blue),
       "green" | "Green" | "GREEN" => Ok(Self::Green),
-      _ => Err(StateRequestParseError::Unrecognized),
+      "identify" => Ok(Self::Identify),
+      "status" => Ok(Self::Status),
+      level if level.starts_with("brightness=") => level["brightness=".len()..]
+        .parse()
+        .map(Self::Brightness)
+        .map_err(|_| StateRequestParseError::Unrecognized),
+      count if count.starts_with("leds=") => count["leds=".len()..]
+        .parse()
+        .map(Self::LedCount)
+        .map_err(|_| StateRequestParseError::Unrecognized),
+      fill if fill.starts_with("fill=") => {
+        parse_fill(&fill["fill=".len()..]).ok_or(StateRequestParseError::Unrecognized)
+      }
+      list if list.starts_with("pixels=") => pixels::PixelList::parse(&list["pixels=".len()..])
+        .map(Self::Pixels)
+        .ok_or(StateRequestParseError::Unrecognized),
+      frame if frame.starts_with("frame=") => pixels::FramePixels::parse(&frame["frame=".len()..])
+        .map(Self::Frame)
+        .ok_or(StateRequestParseError::Unrecognized),
+      zone if zone.starts_with("zone=") => {
+        parse_zone(&zone["zone=".len()..]).ok_or(StateRequestParseError::Unrecognized)
+      }
+      hex if hex.starts_with('#') => parse_hex(&hex[1..])
+        .map(|color| Self::Rgb(color.r, color.g, color.b))
+        .ok_or(StateRequestParseError::Unrecognized),
+      other => effects::Effect::parse(other)
+        .map(Self::Effect)
+        .ok_or(StateRequestParseError::Unrecognized),
     }
   }
 }
 
+/// Ranges are sent over the wire as `<start>..<end>,#rrggbb`.
+fn parse_fill(input: &str) -> Option<StateRequest> {
+  let (range, color) = input.split_once(",#")?;
+  let (start, end) = range.split_once("..")?;
+  Some(StateRequest::Fill(
+    start.parse().ok()?,
+    end.parse().ok()?,
+    parse_hex(color)?,
+  ))
+}
+
+/// Zoned requests are sent over the wire as `<start>..<end>,<request>`, e.g `0..5,breathe=#ff0000`.
+fn parse_zone(input: &str) -> Option<StateRequest> {
+  let (range, request) = input.split_once(',')?;
+  let (start, end) = range.split_once("..")?;
+  let request = zones::ZoneRequest::from_state(&request.parse().ok()?)?;
+  Some(StateRequest::Zone(start.parse().ok()?, end.parse().ok()?, request))
+}
+
+/// Arbitrary colors are sent over the wire as `#rrggbb`; this function is responsible for parsing
+/// the six hex digits following the `#` into a color.
+fn parse_hex(input: &str) -> Option<smart_leds::RGB8> {
+  if input.len() != 6 || !input.is_ascii() {
+    return None;
+  }
+
+  let red = u8::from_str_radix(&input[0..2], 16).ok()?;
+  let green = u8::from_str_radix(&input[2..4], 16).ok()?;
+  let blue = u8::from_str_radix(&input[4..6], 16).ok()?;
+
+  Some(smart_leds::RGB8::new(red, green, blue))
+}
+
 impl StateRequest {
   /// Attempts to build the state request from a slice of bytes.
   pub fn from_bytes(input: &[u8]) -> Option<Self> {
@@ -51,33 +187,536 @@ impl StateRequest {
     utf8_representation.parse().ok()
   }
 
+  /// Attempts to build the state request from a decoded frame, validating the payload layout.
+  pub fn from_frame(frame: &milton_protocol::Frame) -> Option<Self> {
+    use milton_protocol::CommandId;
+
+    match (frame.command, frame.payload) {
+      (CommandId::On, []) => Some(Self::On),
+      (CommandId::Off, []) => Some(Self::Off),
+      (CommandId::Red, []) => Some(Self::Red),
+      (CommandId::Green, []) => Some(Self::Green),
+      (CommandId::Blue, []) => Some(Self::Blue),
+      (CommandId::Rgb, [red, green, blue]) => Some(Self::Rgb(*red, *green, *blue)),
+      (CommandId::Brightness, [level]) => Some(Self::Brightness(*level)),
+      (CommandId::Effect, [id, arguments @ ..]) => effects::Effect::from_payload(*id, arguments).map(Self::Effect),
+      (CommandId::LedCount, [low, high]) => Some(Self::LedCount(u16::from_le_bytes([*low, *high]))),
+      (CommandId::Fill, [a, b, c, d, red, green, blue]) => Some(Self::Fill(
+        u16::from_le_bytes([*a, *b]),
+        u16::from_le_bytes([*c, *d]),
+        smart_leds::RGB8::new(*red, *green, *blue),
+      )),
+      (CommandId::Pixels, payload) => pixels::PixelList::from_payload(payload).map(Self::Pixels),
+      (CommandId::Frame, payload) => pixels::FramePixels::from_payload(payload).map(Self::Frame),
+      (CommandId::Zone, [a, b, c, d, id, payload @ ..]) => {
+        let inner = Self::from_frame(&milton_protocol::Frame::new(CommandId::from_byte(*id)?, payload))?;
+        Some(Self::Zone(
+          u16::from_le_bytes([*a, *b]),
+          u16::from_le_bytes([*c, *d]),
+          zones::ZoneRequest::from_state(&inner)?,
+        ))
+      }
+      (CommandId::Identify, []) => Some(Self::Identify),
+      (CommandId::Status, []) => Some(Self::Status),
+      _ => None,
+    }
+  }
+
+  /// Writes the payload of the frame that would carry this request into the buffer, returning the
+  /// command id along with the number of bytes written. The buffer must hold at least 10 bytes, or
+  /// `MAX_PAYLOAD_LEN` bytes for pixel and frame requests.
+  pub fn payload(&self, buffer: &mut [u8]) -> (milton_protocol::CommandId, usize) {
+    use milton_protocol::CommandId;
+
+    match self {
+      Self::On => (CommandId::On, 0),
+      Self::Off => (CommandId::Off, 0),
+      Self::Red => (CommandId::Red, 0),
+      Self::Green => (CommandId::Green, 0),
+      Self::Blue => (CommandId::Blue, 0),
+      Self::Rgb(red, green, blue) => {
+        buffer[0..3].copy_from_slice(&[*red, *green, *blue]);
+        (CommandId::Rgb, 3)
+      }
+      Self::Brightness(level) => {
+        buffer[0] = *level;
+        (CommandId::Brightness, 1)
+      }
+      Self::Effect(effect) => (CommandId::Effect, effect.payload(buffer)),
+      Self::LedCount(count) => {
+        buffer[0..2].copy_from_slice(&count.to_le_bytes());
+        (CommandId::LedCount, 2)
+      }
+      Self::Fill(start, end, color) => {
+        buffer[0..2].copy_from_slice(&start.to_le_bytes());
+        buffer[2..4].copy_from_slice(&end.to_le_bytes());
+        buffer[4..7].copy_from_slice(&[color.r, color.g, color.b]);
+        (CommandId::Fill, 7)
+      }
+      Self::Pixels(list) => (CommandId::Pixels, list.payload(buffer)),
+      Self::Frame(frame) => (CommandId::Frame, frame.payload(buffer)),
+      Self::Zone(start, end, request) => {
+        buffer[0..2].copy_from_slice(&start.to_le_bytes());
+        buffer[2..4].copy_from_slice(&end.to_le_bytes());
+        let (id, len) = request.state().payload(&mut buffer[5..]);
+        buffer[4] = id as u8;
+        (CommandId::Zone, 5 + len)
+      }
+      Self::Identify => (CommandId::Identify, 0),
+      Self::Status => (CommandId::Status, 0),
+    }
+  }
+
+  /// Returns true for requests that paint onto whatever the lights were showing, rather than
+  /// replacing it.
+  pub fn is_paint(&self) -> bool {
+    matches!(self, Self::Fill(..) | Self::Pixels(_) | Self::Frame(_))
+  }
+
+  /// Paints the leds addressed by a fill, pixel or frame request; other requests leave the leds
+  /// alone.
+  pub fn paint(&self, out: &mut [smart_leds::RGB8]) {
+    match self {
+      Self::Fill(start, end, color) => pixels::fill(*start, *end, *color, out),
+      Self::Pixels(list) => list.paint(out),
+      Self::Frame(frame) => frame.paint(out),
+      _ => (),
+    }
+  }
+
+  /// Returns true for requests that ask the firmware about itself rather than change the lights.
+  pub fn is_query(&self) -> bool {
+    matches!(self, Self::Identify | Self::Status)
+  }
+
   /// Given a constant number of leds to fill, this method will return an _array_ of colors for
-  /// each one corresponding to its matching value in the request.
-  pub fn colors<const M: usize>(&self) -> smart_leds::Brightness<<[smart_leds::RGB8; M] as IntoIterator>::IntoIter> {
+  /// each one corresponding to its matching value in the request. Only the first `active` leds are
+  /// lit; effects are rendered across those, `t_ms` milliseconds after they were requested.
+  pub fn frame<const M: usize>(&self, t_ms: u64, active: usize) -> [smart_leds::RGB8; M] {
     let mut out = [smart_leds::RGB8::new(0, 0, 0); M];
+    let lit = &mut out[..active.min(M)];
+
+    match self {
+      Self::On => lit.fill(smart_leds::RGB8::new(255, 255, 255)),
+      Self::Off => (),
+      Self::Red => lit.fill(smart_leds::RGB8::new(255, 0, 0)),
+      Self::Green => lit.fill(smart_leds::RGB8::new(0, 255, 0)),
+      Self::Blue => lit.fill(smart_leds::RGB8::new(0, 0, 255)),
+      Self::Rgb(red, green, blue) => lit.fill(smart_leds::RGB8::new(*red, *green, *blue)),
+      Self::Effect(effect) => effect.render(t_ms, lit),
+      // On their own, painting requests are painted onto dark leds.
+      Self::Fill(..) | Self::Pixels(_) | Self::Frame(_) => self.paint(lit),
+      // Neither brightness, led count nor queries carry a color of their own, and are never applied
+      // as the color of the light state; see `LightState::apply`.
+      Self::Brightness(_) | Self::LedCount(_) | Self::Zone(..) | Self::Identify | Self::Status => (),
+    }
+
+    out
+  }
+
+  /// Returns true if the frame for this request changes over time.
+  pub fn is_animated(&self) -> bool {
+    matches!(self, Self::Effect(effect) if effect.is_animated())
+  }
+}
+
+impl core::fmt::Display for StateRequest {
+  fn fmt(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+    match self {
+      Self::On => write!(formatter, "on"),
+      Self::Off => write!(formatter, "off"),
+      Self::Red => write!(formatter, "red"),
+      Self::Green => write!(formatter, "green"),
+      Self::Blue => write!(formatter, "blue"),
+      Self::Rgb(red, green, blue) => write!(formatter, "#{red:02x}{green:02x}{blue:02x}"),
+      Self::Brightness(level) => write!(formatter, "brightness={level}"),
+      Self::Effect(effect) => write!(formatter, "{effect}"),
+      Self::LedCount(count) => write!(formatter, "leds={count}"),
+      Self::Fill(start, end, color) => write!(
+        formatter,
+        "fill={start}..{end},#{:02x}{:02x}{:02x}",
+        color.r, color.g, color.b
+      ),
+      Self::Pixels(list) => write!(formatter, "pixels={list}"),
+      Self::Frame(frame) => write!(formatter, "frame={frame}"),
+      Self::Zone(start, end, request) => write!(formatter, "zone={start}..{end},{}", request.state()),
+      Self::Identify => write!(formatter, "identify"),
+      Self::Status => write!(formatter, "status"),
+    }
+  }
+}
+
+/// Requests received over serial may optionally be followed by `,transition=<ms>`, which is the
+/// amount of time the lights should take to fade from their current colors into the new ones.
+#[derive(Clone)]
+pub struct Request {
+  /// The actual state being requested.
+  pub state: StateRequest,
+
+  /// The duration of the fade into the requested state; zero means it is applied immediately.
+  pub transition_ms: u32,
+}
+
+impl core::str::FromStr for Request {
+  type Err = StateRequestParseError;
+
+  fn from_str(input: &str) -> Result<Self, Self::Err> {
+    let (state, transition_ms) = match input.split_once(",transition=") {
+      Some((state, duration)) => (
+        state,
+        duration.parse().map_err(|_| StateRequestParseError::Unrecognized)?,
+      ),
+      None => (input, 0),
+    };
+
+    Ok(Self {
+      state: state.parse()?,
+      transition_ms,
+    })
+  }
+}
+
+impl Request {
+  /// Attempts to build the request from a slice of bytes.
+  pub fn from_bytes(input: &[u8]) -> Option<Self> {
+    let utf8_representation = core::str::from_utf8(input).ok()?;
+    utf8_representation.parse().ok()
+  }
+
+  /// Attempts to build the request from a decoded frame. Transition frames wrap the id and payload
+  /// of the command being faded into.
+  pub fn from_frame(frame: &milton_protocol::Frame) -> Option<Self> {
+    match (frame.command, frame.payload) {
+      (milton_protocol::CommandId::Transition, [a, b, c, d, id, payload @ ..]) => {
+        let command = milton_protocol::CommandId::from_byte(*id)?;
+
+        if command == milton_protocol::CommandId::Transition {
+          return None;
+        }
+
+        Some(Self {
+          state: StateRequest::from_frame(&milton_protocol::Frame::new(command, payload))?,
+          transition_ms: u32::from_le_bytes([*a, *b, *c, *d]),
+        })
+      }
+      _ => Some(Self {
+        state: StateRequest::from_frame(frame)?,
+        transition_ms: 0,
+      }),
+    }
+  }
+}
+
+/// Linearly interpolates every led between two frames, where `elapsed_ms` of `duration_ms` has
+/// passed. Once the elapsed time reaches the duration, the result is the `to` frame.
+pub fn interpolate<const M: usize>(
+  from: &[smart_leds::RGB8; M],
+  to: &[smart_leds::RGB8; M],
+  elapsed_ms: u32,
+  duration_ms: u32,
+) -> [smart_leds::RGB8; M] {
+  if elapsed_ms >= duration_ms {
+    return *to;
+  }
+
+  let channel = |start: u8, end: u8| -> u8 {
+    let delta = (i64::from(end) - i64::from(start)) * i64::from(elapsed_ms) / i64::from(duration_ms);
+    (i64::from(start) + delta) as u8
+  };
+
+  let mut out = *to;
+
+  for (item, (start, end)) in out.iter_mut().zip(from.iter().zip(to.iter())) {
+    *item = smart_leds::RGB8::new(
+      channel(start.r, end.r),
+      channel(start.g, end.g),
+      channel(start.b, end.b),
+    );
+  }
+
+  out
+}
+
+/// An in-progress fade from some previously displayed frame into the current light state.
+#[derive(Clone)]
+struct Transition<const M: usize> {
+  /// The frame that was being displayed when the transition started.
+  from: [smart_leds::RGB8; M],
+
+  /// The time, in milliseconds, at which the transition started.
+  started_ms: u64,
+
+  /// How long the transition should take.
+  duration_ms: u32,
+}
+
+/// The light state keeps the most recent color request separate from the brightness, so that
+/// changing one does not reset the other. It also tracks any transition currently in progress.
+#[derive(Clone)]
+pub struct LightState<const M: usize> {
+  /// The last request that set the color of the lights.
+  color: StateRequest,
+
+  /// The time, in milliseconds, at which the color was requested; effects are rendered relative to
+  /// this.
+  color_started_ms: u64,
+
+  /// The last requested brightness.
+  brightness: u8,
+
+  /// How many of the `M` leds are lit.
+  active: usize,
+
+  /// Once a painting request has been applied, the leds it was painted onto; this is displayed
+  /// instead of the frame for `color`.
+  canvas: Option<[smart_leds::RGB8; M]>,
 
-    for item in out.iter_mut().take(M) {
-      *item = match self {
-        Self::On => smart_leds::RGB8::new(255, 255, 255),
-        Self::Off => smart_leds::RGB8::new(0, 0, 0),
-        Self::Red => smart_leds::RGB8::new(255, 0, 0),
-        Self::Green => smart_leds::RGB8::new(0, 255, 0),
-        Self::Blue => smart_leds::RGB8::new(0, 0, 255),
+  /// Colors, effects and brightness applied to parts of the strip, on top of the above.
+  zones: zones::Zones,
+
+  /// The fade into the current color and brightness, if one is in progress.
+  transition: Option<Transition<M>>,
+}
+
+impl<const M: usize> Default for LightState<M> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<const M: usize> LightState<M> {
+  /// Creates the initial light state: off, at the default brightness, with every led active. This
+  /// is a `const fn` so that the state can be used to initialize statics.
+  pub const fn new() -> Self {
+    Self::with_led_count(M)
+  }
+
+  /// Creates the initial light state with only the first `active` leds lit.
+  pub const fn with_led_count(active: usize) -> Self {
+    Self {
+      color: StateRequest::Off,
+      color_started_ms: 0,
+      brightness: DEFAULT_BRIGHTNESS,
+      active: if active < M { active } else { M },
+      canvas: None,
+      zones: zones::Zones::new(),
+      transition: None,
+    }
+  }
+
+  /// Updates either the color or brightness, depending on the kind of request. Painting requests
+  /// are painted onto whatever color or effect was displayed at `now_ms`, which stops any
+  /// animation. Zoned requests are layered on top of the rest of the strip until a color or
+  /// painting request replaces them. If the request has a transition, the lights will fade from
+  /// whatever was displayed at `now_ms`.
+  pub fn apply(&mut self, request: Request, now_ms: u64) {
+    if request.state.is_query() {
+      return;
+    }
+
+    let from = self.frame(now_ms);
+
+    match request.state {
+      StateRequest::Brightness(level) => self.brightness = level,
+      StateRequest::LedCount(count) => self.active = usize::from(count).min(M),
+      StateRequest::Zone(start, end, request) => self.zones.apply(start, end, request, now_ms),
+      paint if paint.is_paint() => {
+        let mut canvas = self.unscaled(now_ms);
+        paint.paint(&mut canvas[..self.active]);
+        self.canvas = Some(canvas);
+        self.zones.clear();
+        self.color = paint;
+        self.color_started_ms = now_ms;
+      }
+      color => {
+        self.color = color;
+        self.color_started_ms = now_ms;
+        self.canvas = None;
+        self.zones.clear();
       }
     }
 
-    smart_leds::brightness(out.into_iter(), 100)
+    self.transition = match request.transition_ms {
+      0 => None,
+      duration_ms => Some(Transition {
+        from,
+        started_ms: now_ms,
+        duration_ms,
+      }),
+    };
+  }
+
+  /// Returns the current brightness.
+  pub fn brightness(&self) -> u8 {
+    self.brightness
+  }
+
+  /// Returns the number of leds currently lit.
+  pub fn led_count(&self) -> usize {
+    self.active
+  }
+
+  /// Builds the response to a `StateRequest::Identify` request from this state.
+  pub fn identity(&self) -> Response {
+    Response::Identity {
+      max_led_count: M as u16,
+      led_count: self.active as u16,
+    }
+  }
+
+  /// Builds the response to a `StateRequest::Status` request from this state.
+  pub fn status(&self, uptime_ms: u64, last_error: Option<milton_protocol::ErrorCode>) -> Response {
+    Response::Status {
+      state: self.color.clone(),
+      brightness: self.brightness,
+      uptime_ms,
+      last_error,
+    }
+  }
+
+  /// Returns true while the displayed frame is changing over time, either because of a transition
+  /// or an effect.
+  pub fn is_animating(&self, now_ms: u64) -> bool {
+    (self.canvas.is_none() && self.color.is_animated()) || self.zones.is_animated() || self.is_transitioning(now_ms)
+  }
+
+  /// Returns true while a transition has not yet reached its target frame.
+  pub fn is_transitioning(&self, now_ms: u64) -> bool {
+    self
+      .transition
+      .as_ref()
+      .map(|transition| now_ms.saturating_sub(transition.started_ms) < u64::from(transition.duration_ms))
+      .unwrap_or(false)
+  }
+
+  /// Returns the frame the lights would show at `now_ms`, before brightness is applied; only the
+  /// active leds are lit.
+  fn unscaled(&self, now_ms: u64) -> [smart_leds::RGB8; M] {
+    let mut out = match self.canvas {
+      Some(canvas) => canvas,
+      None => self
+        .color
+        .frame::<M>(now_ms.saturating_sub(self.color_started_ms), self.active),
+    };
+
+    self.zones.render(now_ms, &mut out[..self.active]);
+    out[self.active..].fill(smart_leds::RGB8::new(0, 0, 0));
+    out
+  }
+
+  /// Returns the frame, scaled by the current brightness, that the lights would show at `now_ms`
+  /// if there were no transition in progress.
+  pub fn target(&self, now_ms: u64) -> [smart_leds::RGB8; M] {
+    let frame = self.unscaled(now_ms);
+    let mut out = [smart_leds::RGB8::new(0, 0, 0); M];
+
+    for (item, color) in out
+      .iter_mut()
+      .zip(smart_leds::brightness(frame.into_iter(), self.brightness))
+    {
+      *item = color;
+    }
+
+    out
+  }
+
+  /// Returns the frame that should be displayed at `now_ms`.
+  pub fn frame(&self, now_ms: u64) -> [smart_leds::RGB8; M] {
+    let target = self.target(now_ms);
+
+    match &self.transition {
+      Some(transition) => {
+        let elapsed_ms = now_ms.saturating_sub(transition.started_ms).min(u64::from(u32::MAX)) as u32;
+        interpolate(&transition.from, &target, elapsed_ms, transition.duration_ms)
+      }
+      None => target,
+    }
   }
 }
 
 /// The response type implements `core::fmt::Display` and enumerates the possible strings that we
 /// will send back over our serial connection to the client.
+// Responses are encoded as soon as they are built and never stored, so the size of a status report
+// carrying a pixel list is not a concern.
+#[allow(clippy::large_enum_variant)]
 pub enum Response {
   /// The requested action succeeded.
   Roger,
 
   /// The requested action failed.
   Failed,
+
+  /// The answer to `StateRequest::Identify`; the firmware and protocol versions are constants.
+  Identity {
+    /// The largest number of leds this firmware was built to drive.
+    max_led_count: u16,
+
+    /// The number of leds currently lit.
+    led_count: u16,
+  },
+
+  /// The answer to `StateRequest::Status`.
+  Status {
+    /// The last request that set the color of the lights.
+    state: StateRequest,
+
+    /// The current brightness.
+    brightness: u8,
+
+    /// The number of milliseconds since the firmware started.
+    uptime_ms: u64,
+
+    /// The last thing that went wrong, if anything has.
+    last_error: Option<milton_protocol::ErrorCode>,
+  },
+}
+
+/// The size of the largest payload sent in a `Response` frame; a status report with a fill.
+const MAX_RESPONSE_PAYLOAD_LEN: usize = 24;
+
+impl Response {
+  /// Framed requests are answered with framed responses; this writes the frame for this response
+  /// into the buffer, returning the number of bytes written.
+  pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, milton_protocol::EncodeError> {
+    use milton_protocol::CommandId;
+
+    let mut payload = [0u8; MAX_RESPONSE_PAYLOAD_LEN + VERSION.len()];
+
+    let (command, len) = match self {
+      Self::Roger => (CommandId::Ack, 0),
+      Self::Failed => (CommandId::Nack, 0),
+      Self::Identity {
+        max_led_count,
+        led_count,
+      } => {
+        payload[0] = milton_protocol::VERSION;
+        payload[1..3].copy_from_slice(&max_led_count.to_le_bytes());
+        payload[3..5].copy_from_slice(&led_count.to_le_bytes());
+        payload[5..5 + VERSION.len()].copy_from_slice(VERSION.as_bytes());
+        (CommandId::Identity, 5 + VERSION.len())
+      }
+      Self::Status {
+        state,
+        brightness,
+        uptime_ms,
+        last_error,
+      } => {
+        payload[0] = *brightness;
+        payload[1..9].copy_from_slice(&uptime_ms.to_le_bytes());
+        payload[9] = last_error.map(|code| code as u8).unwrap_or(0);
+        let (id, len) = match state {
+          // These do not fit in a status report; only their command id is sent.
+          StateRequest::Pixels(_) => (CommandId::Pixels, 0),
+          StateRequest::Frame(_) => (CommandId::Frame, 0),
+          state => state.payload(&mut payload[11..]),
+        };
+        payload[10] = id as u8;
+        (CommandId::StatusReport, 11 + len)
+      }
+    };
+
+    milton_protocol::Frame::new(command, &payload[..len]).encode(buffer)
+  }
 }
 
 impl core::fmt::Display for Response {
@@ -85,6 +724,238 @@ impl core::fmt::Display for Response {
     match self {
       Self::Roger => write!(formatter, "ok\r\n"),
       Self::Failed => write!(formatter, "failed\r\n"),
+      Self::Identity {
+        max_led_count,
+        led_count,
+      } => write!(
+        formatter,
+        "identity version={VERSION} protocol={} leds={led_count} max={max_led_count}\r\n",
+        milton_protocol::VERSION
+      ),
+      Self::Status {
+        state,
+        brightness,
+        uptime_ms,
+        last_error,
+      } => {
+        write!(
+          formatter,
+          "status state={state} brightness={brightness} uptime={uptime_ms}"
+        )?;
+
+        match last_error {
+          Some(error) => write!(formatter, " error={error}\r\n"),
+          None => write!(formatter, " error=none\r\n"),
+        }
+      }
+    }
+  }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+  use super::pixels::{FramePixels, PixelList};
+  use super::{interpolate, parse_count, LightState, Request, StateRequest, DEFAULT_BRIGHTNESS};
+  use smart_leds::RGB8;
+
+  const FROM: [RGB8; 2] = [RGB8::new(0, 0, 0), RGB8::new(200, 100, 50)];
+  const TO: [RGB8; 2] = [RGB8::new(255, 128, 10), RGB8::new(0, 100, 250)];
+
+  /// Returns a request for the state that is applied immediately.
+  fn immediately(state: StateRequest) -> Request {
+    Request {
+      state,
+      transition_ms: 0,
     }
   }
+
+  #[test]
+  fn parses_brightness() {
+    assert!(matches!("brightness=42".parse(), Ok(StateRequest::Brightness(42))));
+    assert!("brightness=256".parse::<StateRequest>().is_err());
+    assert!("brightness=".parse::<StateRequest>().is_err());
+  }
+
+  #[test]
+  fn starts_at_the_default_brightness() {
+    let state = LightState::<2>::default();
+
+    assert_eq!(state.brightness(), DEFAULT_BRIGHTNESS);
+    assert_eq!(state.target(0), [RGB8::new(0, 0, 0); 2]);
+  }
+
+  #[test]
+  fn brightness_scales_the_color() {
+    let mut state = LightState::<2>::default();
+    state.apply(immediately(StateRequest::Rgb(255, 128, 0)), 0);
+    state.apply(immediately(StateRequest::Brightness(127)), 0);
+
+    assert_eq!(state.target(0), [RGB8::new(127, 64, 0); 2]);
+  }
+
+  #[test]
+  fn brightness_survives_color_changes() {
+    let mut state = LightState::<2>::default();
+    state.apply(immediately(StateRequest::Brightness(127)), 0);
+    state.apply(immediately(StateRequest::Red), 0);
+
+    assert_eq!(state.brightness(), 127);
+    assert_eq!(state.target(0), [RGB8::new(127, 0, 0); 2]);
+
+    state.apply(immediately(StateRequest::Rgb(0, 0, 255)), 0);
+    assert_eq!(state.brightness(), 127);
+    assert_eq!(state.target(0), [RGB8::new(0, 0, 127); 2]);
+  }
+
+  #[test]
+  fn color_survives_brightness_changes() {
+    let mut state = LightState::<2>::default();
+    state.apply(immediately(StateRequest::Green), 0);
+    state.apply(immediately(StateRequest::Brightness(255)), 0);
+
+    assert_eq!(state.target(0), [RGB8::new(0, 255, 0); 2]);
+  }
+
+  #[test]
+  fn interpolation_starts_at_the_first_frame() {
+    assert_eq!(interpolate(&FROM, &TO, 0, 1000), FROM);
+  }
+
+  #[test]
+  fn interpolation_ends_at_the_second_frame() {
+    assert_eq!(interpolate(&FROM, &TO, 1000, 1000), TO);
+    assert_eq!(interpolate(&FROM, &TO, 5000, 1000), TO);
+  }
+
+  #[test]
+  fn interpolation_is_halfway_at_the_midpoint() {
+    assert_eq!(
+      interpolate(&FROM, &TO, 500, 1000),
+      [RGB8::new(127, 64, 5), RGB8::new(100, 100, 150)]
+    );
+  }
+
+  #[test]
+  fn zero_length_interpolation_is_the_second_frame() {
+    assert_eq!(interpolate(&FROM, &TO, 0, 0), TO);
+  }
+
+  #[test]
+  fn parses_transitions() {
+    let request = "#ff0000,transition=250".parse::<Request>().ok();
+    assert_eq!(request.map(|request| request.transition_ms), Some(250));
+    assert_eq!(
+      "on".parse::<Request>().ok().map(|request| request.transition_ms),
+      Some(0)
+    );
+    assert!("on,transition=soon".parse::<Request>().is_err());
+  }
+
+  /// Returns a light state at full brightness, so frames are not scaled.
+  fn full_brightness() -> LightState<2> {
+    let mut state = LightState::<2>::default();
+    state.apply(immediately(StateRequest::Brightness(255)), 0);
+    state
+  }
+
+  #[test]
+  fn transitions_fade_from_the_displayed_frame() {
+    let mut state = full_brightness();
+    state.apply(
+      Request {
+        state: StateRequest::Rgb(200, 0, 100),
+        transition_ms: 100,
+      },
+      1000,
+    );
+
+    assert_eq!(state.frame(1000), [RGB8::new(0, 0, 0); 2]);
+    assert_eq!(state.frame(1050), [RGB8::new(100, 0, 50); 2]);
+    assert!(state.is_transitioning(1099));
+    assert_eq!(state.frame(1100), [RGB8::new(200, 0, 100); 2]);
+    assert!(!state.is_transitioning(1100));
+  }
+
+  #[test]
+  fn zero_length_transitions_apply_immediately() {
+    let mut state = full_brightness();
+    state.apply(immediately(StateRequest::Rgb(200, 0, 100)), 1000);
+
+    assert!(!state.is_transitioning(1000));
+    assert_eq!(state.frame(1000), [RGB8::new(200, 0, 100); 2]);
+  }
+
+  #[test]
+  fn parses_led_counts() {
+    assert_eq!(parse_count("0"), 0);
+    assert_eq!(parse_count("60"), 60);
+    assert_eq!(parse_count("1024"), 1024);
+    assert!(matches!("leds=30".parse(), Ok(StateRequest::LedCount(30))));
+    assert!("leds=-1".parse::<StateRequest>().is_err());
+    assert!("leds=65536".parse::<StateRequest>().is_err());
+  }
+
+  #[test]
+  #[should_panic(expected = "led counts must be decimal numbers")]
+  fn refuses_led_counts_that_are_not_numbers() {
+    parse_count("6O");
+  }
+
+  #[test]
+  fn led_counts_are_clamped_to_the_strip() {
+    let mut state = LightState::<4>::with_led_count(10);
+    assert_eq!(state.led_count(), 4);
+
+    state.apply(immediately(StateRequest::LedCount(2)), 0);
+    assert_eq!(state.led_count(), 2);
+
+    state.apply(immediately(StateRequest::LedCount(u16::MAX)), 0);
+    assert_eq!(state.led_count(), 4);
+  }
+
+  #[test]
+  fn only_active_leds_are_lit() {
+    const RED: RGB8 = RGB8::new(255, 0, 0);
+    const OFF: RGB8 = RGB8::new(0, 0, 0);
+
+    let mut state = LightState::<4>::with_led_count(2);
+    state.apply(immediately(StateRequest::Brightness(255)), 0);
+    state.apply(immediately(StateRequest::Red), 0);
+    assert_eq!(state.target(0), [RED, RED, OFF, OFF]);
+
+    state.apply(immediately(StateRequest::LedCount(3)), 0);
+    assert_eq!(state.target(0), [RED, RED, RED, OFF]);
+    assert_eq!(state.frame(0), [RED, RED, RED, OFF]);
+
+    state.apply(immediately(StateRequest::LedCount(0)), 0);
+    assert_eq!(state.target(0), [OFF; 4]);
+  }
+
+  #[test]
+  fn paints_stop_at_the_active_leds() {
+    const RED: RGB8 = RGB8::new(255, 0, 0);
+    const BLUE: RGB8 = RGB8::new(0, 0, 255);
+    const OFF: RGB8 = RGB8::new(0, 0, 0);
+
+    let mut state = LightState::<4>::with_led_count(3);
+    state.apply(immediately(StateRequest::Brightness(255)), 0);
+    state.apply(immediately(StateRequest::Fill(1, 10, RED)), 0);
+    assert_eq!(state.target(0), [OFF, RED, RED, OFF]);
+
+    state.apply(
+      immediately(StateRequest::Pixels(
+        PixelList::parse("0#0000ff,3#0000ff").expect("valid pixels"),
+      )),
+      0,
+    );
+    assert_eq!(state.target(0), [BLUE, RED, RED, OFF]);
+
+    state.apply(
+      immediately(StateRequest::Frame(
+        FramePixels::parse("#0000ff#0000ff#0000ff#0000ff").expect("valid frame"),
+      )),
+      0,
+    );
+    assert_eq!(state.target(0), [BLUE, BLUE, BLUE, OFF]);
+  }
 }
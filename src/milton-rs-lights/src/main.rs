@@ -18,13 +18,23 @@ use smart_leds::SmartLedsWrite;
 
 // -- CONFIG
 
-// [todo] once we can `and_then` parse this into a const u8, lets do that.
-/// Configuration value: how many leds are we working with
-const LED_COUNT: usize = match option_env!("LED_COUNT") {
-  Some(_) => 12,
+/// Configuration value: the largest number of leds this build can drive; this determines the size
+/// of the buffers used by the led adapter.
+const MAX_LED_COUNT: usize = match option_env!("MAX_LED_COUNT") {
+  Some(value) => milton_xiao::parse_count(value),
+  None => 64,
+};
+
+/// Configuration value: how many leds are lit until a `StateRequest::LedCount` request is
+/// received.
+const DEFAULT_LED_COUNT: usize = match option_env!("LED_COUNT") {
+  Some(value) => milton_xiao::parse_count(value),
   None => 1,
 };
 
+/// Configuration value: while animating, how long to wait between writing frames.
+const FRAME_INTERVAL_MS: u64 = 20;
+
 // -- Types
 
 /// This type enumerates the possible states our LED control can be in.
@@ -32,11 +42,11 @@ const LED_COUNT: usize = match option_env!("LED_COUNT") {
 enum LedState {
   /// When `Requesting`, we have received at least some serial data and are waiting to figure out
   /// how to interpret it.
-  Requesting(([u8; 128], usize)),
+  Requesting(([u8; milton_protocol::MAX_FRAME_LEN], usize)),
 
   /// Once we have received a valid message, we'll move into the `Requested` state, with the
   /// concrete request kind included.
-  Requested(milton_xiao::StateRequest),
+  Requested(milton_xiao::Request),
 
   /// If we've encountered an error, make everything red, and blink on/off via countup.
   Failed(u8),
@@ -61,7 +71,7 @@ type LightAdapter = SmartLedsAdapter<
     hal::gpio::InputOutputAnalogPinType,
     2,
   >,
-  { LED_COUNT * 24 + 1 }, // LED_COUNT * (channels * pulses) + 1
+  { MAX_LED_COUNT * 24 + 1 }, // MAX_LED_COUNT * (channels * pulses) + 1
 >;
 
 /// This static holds the usb interface that we will use for writing and reading serial data.
@@ -71,6 +81,16 @@ static USB: GlobalMut<Option<hal::UsbSerialJtag<pac::USB_DEVICE>>> =
 /// The gloal state wrapped in a mutex; this will be manipulated across interrupt/main.
 static STATE: GlobalMut<LedState> = critical_section::Mutex::new(core::cell::RefCell::new(LedState::Empty));
 
+/// A copy of the light state, refreshed by the main loop whenever a request is applied, that the
+/// usb interrupt can use to answer status requests.
+static SNAPSHOT: GlobalMut<milton_xiao::LightState<MAX_LED_COUNT>> = critical_section::Mutex::new(
+  core::cell::RefCell::new(milton_xiao::LightState::with_led_count(DEFAULT_LED_COUNT)),
+);
+
+/// The last thing that went wrong, reported in response to status requests.
+static LAST_ERROR: GlobalMut<Option<milton_protocol::ErrorCode>> =
+  critical_section::Mutex::new(core::cell::RefCell::new(None));
+
 /// A timer that will be reset across interrupt/main boundaries.
 static PACKET_TIMER: GlobalMut<Option<hal::timer::Timer<hal::timer::Timer0<pac::TIMG0>>>> =
   critical_section::Mutex::new(core::cell::RefCell::new(None));
@@ -146,16 +166,26 @@ fn main() -> ! {
 
   led.write(&mut [smart_leds::RGB8::new(0, 0, 0)].into_iter()).unwrap();
 
+  // The color and brightness currently applied to the lights; only ever touched by the main loop.
+  let mut light_state = milton_xiao::LightState::<MAX_LED_COUNT>::with_led_count(DEFAULT_LED_COUNT);
+
+  // The last time we wrote a frame; used to pace writes while animating.
+  let mut last_frame = 0u64;
+
   loop {
+    let now = milliseconds();
+
     critical_section::with(|cs| {
       STATE.replace_with(cs, |state_reference| {
         match state_reference {
-          // If we've received a 'off' request, turn the lights off.
+          // If we've received a request, apply it to our light state and write the result.
           LedState::Requested(ref request) => {
-            let mut colors = request.colors::<LED_COUNT>();
-            match led.write(&mut colors) {
+            light_state.apply(request.clone(), now);
+            SNAPSHOT.replace(cs, light_state.clone());
+            last_frame = now;
+            match led.write(light_state.frame(now).into_iter()) {
               Ok(_) => LedState::Empty,
-              Err(_) => LedState::Failed(0),
+              Err(_) => fail(cs, milton_protocol::ErrorCode::LedWrite),
             }
           }
           LedState::Failed(mut value) => {
@@ -172,6 +202,16 @@ fn main() -> ! {
               Ok(_) => LedState::Failed(value + 1),
             }
           }
+          // While an effect or transition is in progress, keep writing frames.
+          LedState::Requesting(_) | LedState::Empty
+            if light_state.is_animating(now) && now.saturating_sub(last_frame) >= FRAME_INTERVAL_MS =>
+          {
+            last_frame = now;
+            match led.write(light_state.frame(now).into_iter()) {
+              Ok(_) => core::mem::take(state_reference),
+              Err(_) => fail(cs, milton_protocol::ErrorCode::LedWrite),
+            }
+          }
           LedState::Requesting(_) | LedState::Empty => core::mem::take(state_reference),
         }
       });
@@ -179,6 +219,38 @@ fn main() -> ! {
   }
 }
 
+/// Returns the number of milliseconds since boot, based on the system timer.
+fn milliseconds() -> u64 {
+  hal::systimer::SystemTimer::now() / (hal::systimer::SystemTimer::TICKS_PER_SECOND / 1000)
+}
+
+/// Records the error as the last thing that went wrong, returning the failed state.
+fn fail(cs: critical_section::CriticalSection, error: milton_protocol::ErrorCode) -> LedState {
+  LAST_ERROR.replace(cs, Some(error));
+  LedState::Failed(0)
+}
+
+/// Queries are answered by the usb interrupt itself, using the snapshot of the light state kept by
+/// the main loop; everything else is acknowledged and handed to the main loop to apply.
+fn answer(cs: critical_section::CriticalSection, request: &milton_xiao::Request) -> milton_xiao::Response {
+  match request.state {
+    milton_xiao::StateRequest::Identify => SNAPSHOT.borrow_ref(cs).identity(),
+    milton_xiao::StateRequest::Status => SNAPSHOT
+      .borrow_ref(cs)
+      .status(milliseconds(), *LAST_ERROR.borrow_ref(cs)),
+    _ => milton_xiao::Response::Roger,
+  }
+}
+
+/// Returns the state to move into once a request has been answered.
+fn next_state(request: milton_xiao::Request) -> LedState {
+  if request.state.is_query() {
+    LedState::Empty
+  } else {
+    LedState::Requested(request)
+  }
+}
+
 /// Both the usb interrtup and the timeout interrupt will need to restart the packet timer. This
 /// helper function really only exists to provide a thin layer of ergonomics and make sure we're
 /// resetting it to the same interval.
@@ -209,7 +281,7 @@ fn TG0_T0_LEVEL() {
 
     // Check the state to see if we're currently in the middle of parsing a request.
     STATE.replace_with(cs, |state_reference| match state_reference {
-      LedState::Requesting(_) => LedState::Failed(0),
+      LedState::Requesting(_) => fail(cs, milton_protocol::ErrorCode::Timeout),
       _ => LedState::Empty,
     });
   });
@@ -241,31 +313,60 @@ fn USB_SERIAL_JTAG() {
     // Read bytes from our serial connection
     while let nb::Result::Ok(maybe_char) = usb_serial.read_byte() {
       STATE.replace_with(cs, |state_reference| match (maybe_char, state_reference.deref()) {
+        // If we are buffering a frame, add the byte and see if we have enough to decode it. Framed
+        // requests are answered with framed responses, and a bad frame does not fail the lights.
+        (other, LedState::Requesting((buffer, cursor))) if buffer[0] == milton_protocol::SYNC => {
+          let (mut buffer, cursor) = (*buffer, *cursor);
+
+          if cursor >= buffer.len() {
+            LAST_ERROR.replace(cs, Some(milton_protocol::ErrorCode::BadFrame));
+            return respond_framed(cs, usb_serial, milton_xiao::Response::Failed, LedState::Empty);
+          }
+
+          buffer[cursor] = other;
+
+          match milton_protocol::Frame::decode(&buffer[0..cursor + 1]) {
+            Err(milton_protocol::DecodeError::Incomplete) => LedState::Requesting((buffer, cursor + 1)),
+            Err(_) => {
+              LAST_ERROR.replace(cs, Some(milton_protocol::ErrorCode::BadFrame));
+              respond_framed(cs, usb_serial, milton_xiao::Response::Failed, LedState::Empty)
+            }
+            Ok((frame, _)) => match milton_xiao::Request::from_frame(&frame) {
+              Some(req) => respond_framed(cs, usb_serial, answer(cs, &req), next_state(req)),
+              None => {
+                LAST_ERROR.replace(cs, Some(milton_protocol::ErrorCode::Unrecognized));
+                respond_framed(cs, usb_serial, milton_xiao::Response::Failed, LedState::Empty)
+              }
+            },
+          }
+        }
+
         // If we've reached a terminal character and are currently buffering, move our state into
         // the requested/failed based on a parse attempt.
         (b'\n', LedState::Requesting((buffer, cursor))) | (b':', LedState::Requesting((buffer, cursor))) => {
-          if let Some(req) = milton_xiao::StateRequest::from_bytes(&buffer[0..*cursor]) {
-            match write!(usb_serial, "{}", milton_xiao::Response::Roger) {
+          if let Some(req) = milton_xiao::Request::from_bytes(&buffer[0..*cursor]) {
+            match write!(usb_serial, "{}", answer(cs, &req)) {
               Err(_) => LedState::Failed(0),
-              Ok(_) => LedState::Requested(req),
+              Ok(_) => next_state(req),
             }
           } else {
-            match write!(usb_serial, "{}", milton_xiao::Response::Failed) {
-              Err(_) => LedState::Failed(0),
-              Ok(_) => LedState::Failed(0),
-            }
+            let _ = write!(usb_serial, "{}", milton_xiao::Response::Failed);
+            fail(cs, milton_protocol::ErrorCode::Unrecognized)
           }
         }
 
         // If we took a character and are buffering data, put it in there.
-        (other, LedState::Requesting((mut buffer, cursor))) => {
+        (other, LedState::Requesting((mut buffer, cursor))) if *cursor < buffer.len() => {
           buffer[*cursor] = other;
           LedState::Requesting((buffer, cursor + 1))
         }
 
+        // A text request that does not fit in our buffer is not one we understand.
+        (_, LedState::Requesting(_)) => fail(cs, milton_protocol::ErrorCode::Unrecognized),
+
         // Otherwise, start buffering data.
         (other, _) => {
-          let mut initial_buffer = [0; 128];
+          let mut initial_buffer = [0; milton_protocol::MAX_FRAME_LEN];
           initial_buffer[0] = other;
           LedState::Requesting((initial_buffer, 1))
         }
@@ -277,6 +378,28 @@ fn USB_SERIAL_JTAG() {
   });
 }
 
+/// The size of the buffer used to encode framed responses; large enough for a status report.
+const RESPONSE_FRAME_LEN: usize = 64;
+
+/// Encodes and writes the framed version of a response, returning the state we should move into
+/// if the write succeeded and recording a `BadFrame` error otherwise.
+fn respond_framed(
+  cs: critical_section::CriticalSection,
+  usb_serial: &mut hal::UsbSerialJtag<pac::USB_DEVICE>,
+  response: milton_xiao::Response,
+  next: LedState,
+) -> LedState {
+  let mut buffer = [0u8; RESPONSE_FRAME_LEN];
+
+  match response.encode(&mut buffer) {
+    Ok(size) => match usb_serial.write_bytes(&buffer[0..size]) {
+      Ok(_) => next,
+      Err(_) => fail(cs, milton_protocol::ErrorCode::BadFrame),
+    },
+    Err(_) => fail(cs, milton_protocol::ErrorCode::BadFrame),
+  }
+}
+
 /// [todo] We're using the JTAG/USB device for user input, so it is not clear what options are
 /// available to us when we panic. Maybe we can store some stuff in flash or something.
 #[panic_handler]
@@ -0,0 +1,302 @@
+//! Requests that address individual leds rather than the whole strip. These paint onto whatever
+//! the strip was showing when they were received; see `LightState::apply`.
+
+use smart_leds::RGB8;
+
+/// A fixed-capacity list of individually addressed leds, used by `StateRequest::Pixels`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PixelList {
+  /// The index and color of each led; only the first `len` are meaningful.
+  items: [(u16, RGB8); milton_protocol::MAX_PIXELS],
+
+  /// How many of `items` are in use.
+  len: usize,
+}
+
+impl PixelList {
+  /// Returns the addressed leds.
+  pub fn items(&self) -> &[(u16, RGB8)] {
+    &self.items[..self.len]
+  }
+
+  /// Attempts to parse a list of leds, e.g `3#ff0000,5#00ff00`.
+  pub(crate) fn parse(input: &str) -> Option<Self> {
+    let mut out = Self {
+      items: [(0, RGB8::default()); milton_protocol::MAX_PIXELS],
+      len: 0,
+    };
+
+    for item in input.split(',') {
+      let (index, color) = item.split_once('#')?;
+      *out.items.get_mut(out.len)? = (index.parse().ok()?, crate::parse_hex(color)?);
+      out.len += 1;
+    }
+
+    Some(out)
+  }
+
+  /// Attempts to build the list from a `CommandId::Pixels` payload.
+  pub(crate) fn from_payload(payload: &[u8]) -> Option<Self> {
+    if payload.is_empty()
+      || !payload.chunks_exact(5).remainder().is_empty()
+      || payload.len() / 5 > milton_protocol::MAX_PIXELS
+    {
+      return None;
+    }
+
+    let mut out = Self {
+      items: [(0, RGB8::default()); milton_protocol::MAX_PIXELS],
+      len: payload.len() / 5,
+    };
+
+    for (item, chunk) in out.items.iter_mut().zip(payload.chunks_exact(5)) {
+      *item = (
+        u16::from_le_bytes([chunk[0], chunk[1]]),
+        RGB8::new(chunk[2], chunk[3], chunk[4]),
+      );
+    }
+
+    Some(out)
+  }
+
+  /// Writes the `CommandId::Pixels` payload for this list into the buffer, returning the number of
+  /// bytes written.
+  pub(crate) fn payload(&self, buffer: &mut [u8]) -> usize {
+    for ((index, color), chunk) in self.items().iter().zip(buffer.chunks_exact_mut(5)) {
+      let index = index.to_le_bytes();
+      chunk.copy_from_slice(&[index[0], index[1], color.r, color.g, color.b]);
+    }
+
+    self.len * 5
+  }
+
+  /// Paints each addressed led that falls within `out`.
+  pub fn paint(&self, out: &mut [RGB8]) {
+    for (index, color) in self.items() {
+      if let Some(item) = out.get_mut(usize::from(*index)) {
+        *item = *color;
+      }
+    }
+  }
+}
+
+impl core::fmt::Display for PixelList {
+  fn fmt(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+    for (position, (index, color)) in self.items().iter().enumerate() {
+      let separator = if position == 0 { "" } else { "," };
+      write!(
+        formatter,
+        "{separator}{index}#{:02x}{:02x}{:02x}",
+        color.r, color.g, color.b
+      )?;
+    }
+
+    Ok(())
+  }
+}
+
+/// A fixed-capacity list of colors for consecutive leds starting at the first, used by
+/// `StateRequest::Frame`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FramePixels {
+  /// The color of each led; only the first `len` are meaningful.
+  colors: [RGB8; milton_protocol::MAX_FRAME_PIXELS],
+
+  /// How many of `colors` are in use.
+  len: usize,
+}
+
+impl FramePixels {
+  /// Returns the color of each led, starting at the first.
+  pub fn colors(&self) -> &[RGB8] {
+    &self.colors[..self.len]
+  }
+
+  /// Attempts to parse a frame of colors, e.g `#ff0000#00ff00#0000ff`.
+  pub(crate) fn parse(input: &str) -> Option<Self> {
+    let mut out = Self {
+      colors: [RGB8::default(); milton_protocol::MAX_FRAME_PIXELS],
+      len: 0,
+    };
+
+    for color in input.strip_prefix('#')?.split('#') {
+      *out.colors.get_mut(out.len)? = crate::parse_hex(color)?;
+      out.len += 1;
+    }
+
+    Some(out)
+  }
+
+  /// Attempts to build the frame from a `CommandId::Frame` payload.
+  pub(crate) fn from_payload(payload: &[u8]) -> Option<Self> {
+    if payload.is_empty()
+      || !payload.chunks_exact(3).remainder().is_empty()
+      || payload.len() / 3 > milton_protocol::MAX_FRAME_PIXELS
+    {
+      return None;
+    }
+
+    let mut out = Self {
+      colors: [RGB8::default(); milton_protocol::MAX_FRAME_PIXELS],
+      len: payload.len() / 3,
+    };
+
+    for (color, chunk) in out.colors.iter_mut().zip(payload.chunks_exact(3)) {
+      *color = RGB8::new(chunk[0], chunk[1], chunk[2]);
+    }
+
+    Some(out)
+  }
+
+  /// Writes the `CommandId::Frame` payload for this frame into the buffer, returning the number of
+  /// bytes written.
+  pub(crate) fn payload(&self, buffer: &mut [u8]) -> usize {
+    for (color, chunk) in self.colors().iter().zip(buffer.chunks_exact_mut(3)) {
+      chunk.copy_from_slice(&[color.r, color.g, color.b]);
+    }
+
+    self.len * 3
+  }
+
+  /// Paints the leds from the first; leds past the end of the frame are left alone.
+  pub fn paint(&self, out: &mut [RGB8]) {
+    for (item, color) in out.iter_mut().zip(self.colors()) {
+      *item = *color;
+    }
+  }
+}
+
+impl core::fmt::Display for FramePixels {
+  fn fmt(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+    for color in self.colors() {
+      write!(formatter, "#{:02x}{:02x}{:02x}", color.r, color.g, color.b)?;
+    }
+
+    Ok(())
+  }
+}
+
+/// Paints the leds from `start` up to, but not including, `end` with a single color. The range is
+/// clamped to the leds in `out`.
+pub fn fill(start: u16, end: u16, color: RGB8, out: &mut [RGB8]) {
+  let end = usize::from(end).min(out.len());
+  let start = usize::from(start).min(end);
+  out[start..end].fill(color);
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+  use super::{fill, FramePixels, PixelList};
+  use smart_leds::RGB8;
+
+  const RED: RGB8 = RGB8::new(255, 0, 0);
+  const BLUE: RGB8 = RGB8::new(0, 0, 255);
+  const OFF: RGB8 = RGB8::new(0, 0, 0);
+
+  #[test]
+  fn pixels_round_trip() {
+    let pixels = PixelList::parse("3#ff0000,0#0000ff").expect("valid pixels");
+    assert_eq!(pixels.items(), &[(3, RED), (0, BLUE)]);
+
+    let mut buffer = [0; 16];
+    let size = pixels.payload(&mut buffer);
+    assert_eq!(size, 10);
+    assert_eq!(PixelList::from_payload(&buffer[..size]), Some(pixels));
+    assert_eq!(format!("{pixels}"), "3#ff0000,0#0000ff");
+  }
+
+  #[test]
+  fn pixels_outside_the_strip_are_ignored() {
+    let pixels = PixelList::parse("1#ff0000,4#ff0000,65535#ff0000").expect("valid pixels");
+    let mut out = [OFF; 4];
+
+    pixels.paint(&mut out);
+    assert_eq!(out, [OFF, RED, OFF, OFF]);
+
+    pixels.paint(&mut []);
+  }
+
+  #[test]
+  fn refuses_malformed_pixels() {
+    assert_eq!(PixelList::parse(""), None);
+    assert_eq!(PixelList::parse("1#ff00"), None);
+    assert_eq!(PixelList::parse("one#ff0000"), None);
+    assert_eq!(PixelList::parse("65536#ff0000"), None);
+
+    let too_many = vec!["1#ff0000"; milton_protocol::MAX_PIXELS + 1].join(",");
+    assert_eq!(PixelList::parse(&too_many), None);
+  }
+
+  #[test]
+  fn refuses_truncated_pixel_payloads() {
+    let payload = [3, 0, 255, 0, 0, 4, 0, 255];
+
+    assert_eq!(PixelList::from_payload(&[]), None);
+    for end in 1..payload.len() {
+      if end != 5 {
+        assert_eq!(PixelList::from_payload(&payload[..end]), None, "{end} bytes");
+      }
+    }
+
+    let too_many = vec![0; (milton_protocol::MAX_PIXELS + 1) * 5];
+    assert_eq!(PixelList::from_payload(&too_many), None);
+  }
+
+  #[test]
+  fn frames_round_trip() {
+    let frame = FramePixels::parse("#ff0000#0000ff").expect("valid frame");
+    assert_eq!(frame.colors(), &[RED, BLUE]);
+
+    let mut buffer = [0; 8];
+    let size = frame.payload(&mut buffer);
+    assert_eq!(size, 6);
+    assert_eq!(FramePixels::from_payload(&buffer[..size]), Some(frame));
+    assert_eq!(format!("{frame}"), "#ff0000#0000ff");
+  }
+
+  #[test]
+  fn frames_longer_than_the_strip_are_cut_short() {
+    let frame = FramePixels::parse("#ff0000#ff0000#ff0000").expect("valid frame");
+    let mut out = [BLUE; 2];
+
+    frame.paint(&mut out);
+    assert_eq!(out, [RED; 2]);
+
+    let mut out = [BLUE; 4];
+    frame.paint(&mut out);
+    assert_eq!(out, [RED, RED, RED, BLUE]);
+  }
+
+  #[test]
+  fn refuses_malformed_frames() {
+    assert_eq!(FramePixels::parse(""), None);
+    assert_eq!(FramePixels::parse("ff0000"), None);
+    assert_eq!(FramePixels::parse("#ff0000#"), None);
+
+    let too_many = "#ff0000".repeat(milton_protocol::MAX_FRAME_PIXELS + 1);
+    assert_eq!(FramePixels::parse(&too_many), None);
+
+    assert_eq!(FramePixels::from_payload(&[]), None);
+    assert_eq!(FramePixels::from_payload(&[255, 0]), None);
+    assert_eq!(FramePixels::from_payload(&[255, 0, 0, 255]), None);
+
+    let too_many = vec![0; (milton_protocol::MAX_FRAME_PIXELS + 1) * 3];
+    assert_eq!(FramePixels::from_payload(&too_many), None);
+  }
+
+  #[test]
+  fn fills_are_clamped_to_the_strip() {
+    let mut out = [OFF; 4];
+
+    fill(1, 3, RED, &mut out);
+    assert_eq!(out, [OFF, RED, RED, OFF]);
+
+    fill(2, 10, BLUE, &mut out);
+    assert_eq!(out, [OFF, RED, BLUE, BLUE]);
+
+    fill(3, 1, RED, &mut out);
+    fill(8, 12, RED, &mut out);
+    fill(0, u16::MAX, RED, &mut []);
+    assert_eq!(out, [OFF, RED, BLUE, BLUE]);
+  }
+}
@@ -0,0 +1,162 @@
+//! Zones scope a color, effect or brightness to a range of leds. They are layered on top of
+//! whatever the rest of the strip is showing, and are cleared by any request that sets the whole
+//! strip; see `LightState::apply`.
+
+use smart_leds::RGB8;
+
+/// The most zones that can be displayed at once; applying another replaces the oldest.
+pub const MAX_ZONES: usize = 8;
+
+/// Enumerates what can be requested of a zone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ZoneRequest {
+  /// Show a single color across the zone.
+  Color(RGB8),
+
+  /// Render an effect across the zone.
+  Effect(crate::effects::Effect),
+
+  /// Scale whatever the zone is showing by a level (0-255).
+  Brightness(u8),
+}
+
+impl ZoneRequest {
+  /// Converts a whole-strip request into its zoned equivalent, if it has one.
+  pub(crate) fn from_state(state: &crate::StateRequest) -> Option<Self> {
+    use crate::StateRequest;
+
+    match state {
+      StateRequest::On => Some(Self::Color(RGB8::new(255, 255, 255))),
+      StateRequest::Off => Some(Self::Color(RGB8::new(0, 0, 0))),
+      StateRequest::Red => Some(Self::Color(RGB8::new(255, 0, 0))),
+      StateRequest::Green => Some(Self::Color(RGB8::new(0, 255, 0))),
+      StateRequest::Blue => Some(Self::Color(RGB8::new(0, 0, 255))),
+      StateRequest::Rgb(red, green, blue) => Some(Self::Color(RGB8::new(*red, *green, *blue))),
+      StateRequest::Brightness(level) => Some(Self::Brightness(*level)),
+      StateRequest::Effect(effect) => Some(Self::Effect(*effect)),
+      _ => None,
+    }
+  }
+
+  /// Returns the whole-strip request that this is the zoned equivalent of; this is what is sent
+  /// over the wire.
+  pub(crate) fn state(&self) -> crate::StateRequest {
+    match self {
+      Self::Color(color) => crate::StateRequest::Rgb(color.r, color.g, color.b),
+      Self::Effect(effect) => crate::StateRequest::Effect(*effect),
+      Self::Brightness(level) => crate::StateRequest::Brightness(*level),
+    }
+  }
+}
+
+/// A single zone, as displayed.
+#[derive(Clone, Copy, Debug)]
+struct Zone {
+  /// The first led in the zone.
+  start: u16,
+
+  /// The led after the last one in the zone.
+  end: u16,
+
+  /// The color or effect shown across the zone; when absent, the zone only scales whatever is
+  /// beneath it.
+  fill: Option<ZoneRequest>,
+
+  /// The time, in milliseconds, at which the fill was requested; effects are rendered relative to
+  /// this.
+  started_ms: u64,
+
+  /// The level that the zone is scaled by.
+  brightness: u8,
+}
+
+/// The zones currently displayed, oldest first.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Zones {
+  /// Each zone; unused slots are always at the end.
+  items: [Option<Zone>; MAX_ZONES],
+}
+
+impl Zones {
+  /// Creates an empty set of zones.
+  pub(crate) const fn new() -> Self {
+    Self {
+      items: [None; MAX_ZONES],
+    }
+  }
+
+  /// Removes every zone.
+  pub(crate) fn clear(&mut self) {
+    self.items = [None; MAX_ZONES];
+  }
+
+  /// Applies the request to the zone covering exactly `start..end`, creating it if necessary.
+  pub(crate) fn apply(&mut self, start: u16, end: u16, request: ZoneRequest, now_ms: u64) {
+    let existing = self
+      .items
+      .iter()
+      .position(|zone| matches!(zone, Some(zone) if zone.start == start && zone.end == end));
+
+    let index = match existing {
+      Some(index) => index,
+      None => {
+        let index = match self.items.iter().position(Option::is_none) {
+          Some(index) => index,
+          None => {
+            self.items.rotate_left(1);
+            MAX_ZONES - 1
+          }
+        };
+
+        self.items[index] = Some(Zone {
+          start,
+          end,
+          fill: None,
+          started_ms: now_ms,
+          brightness: 255,
+        });
+        index
+      }
+    };
+
+    if let Some(zone) = self.items[index].as_mut() {
+      match request {
+        ZoneRequest::Brightness(level) => zone.brightness = level,
+        fill => {
+          zone.fill = Some(fill);
+          zone.started_ms = now_ms;
+        }
+      }
+    }
+  }
+
+  /// Returns true if any zone is rendering an animated effect.
+  pub(crate) fn is_animated(&self) -> bool {
+    self
+      .items
+      .iter()
+      .flatten()
+      .any(|zone| matches!(zone.fill, Some(ZoneRequest::Effect(effect)) if effect.is_animated()))
+  }
+
+  /// Renders every zone, oldest first, on top of the leds in `out`.
+  pub(crate) fn render(&self, now_ms: u64, out: &mut [RGB8]) {
+    for zone in self.items.iter().flatten() {
+      let end = usize::from(zone.end).min(out.len());
+      let start = usize::from(zone.start).min(end);
+      let leds = &mut out[start..end];
+
+      match zone.fill {
+        Some(ZoneRequest::Color(color)) => leds.fill(color),
+        Some(ZoneRequest::Effect(effect)) => effect.render(now_ms.saturating_sub(zone.started_ms), leds),
+        Some(ZoneRequest::Brightness(_)) | None => (),
+      }
+
+      if zone.brightness < 255 {
+        for led in leds.iter_mut() {
+          *led = crate::effects::scale(*led, zone.brightness);
+        }
+      }
+    }
+  }
+}
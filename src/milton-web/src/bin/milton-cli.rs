@@ -14,12 +14,17 @@ struct CliConfiguration {
 
 #[derive(Deserialize, Debug)]
 struct RuntimeConfiguration {
-  cli: CliConfiguration,
+  /// Only needed by the commands that talk to redis.
+  cli: Option<CliConfiguration>,
 }
 
 #[derive(clap::Subcommand, Deserialize)]
 enum CliCommand {
   CreateAdminToken,
+
+  /// Lists the serial ports that a light controller may be attached to, along with the usb vendor
+  /// id, product id and serial number that can be used to select them in the `[[lights]]` config.
+  ListDevices,
 }
 
 #[derive(Deserialize, clap::Parser)]
@@ -32,34 +37,58 @@ struct CommandLineOptions {
   command: CliCommand,
 }
 
-async fn run(args: CommandLineOptions, config: RuntimeConfiguration) -> io::Result<()> {
-  let mut client = async_std::net::TcpStream::connect(&config.cli.redis_addr)
-    .await
-    .map_err(|error| io::Error::new(io::ErrorKind::Other, format!("unable to connect to redis - {error}")))?;
+fn list_devices() -> io::Result<()> {
+  let devices = milton::devices::available()?;
+
+  if devices.is_empty() {
+    println!("no serial ports found");
+    return Ok(());
+  }
+
+  for device in devices {
+    match device.usb {
+      Some(usb) => println!(
+        "{}\tvid=0x{:04x} pid=0x{:04x} serial_number={} ({} {})",
+        device.path,
+        usb.vid,
+        usb.pid,
+        usb.serial_number.as_deref().unwrap_or("-"),
+        usb.manufacturer.as_deref().unwrap_or("unknown"),
+        usb.product.as_deref().unwrap_or("device"),
+      ),
+      None => println!("{}\t(not a usb device)", device.path),
+    }
+  }
+
+  Ok(())
+}
 
+async fn run(args: CommandLineOptions, config: RuntimeConfiguration) -> io::Result<()> {
   match args.command {
+    CliCommand::ListDevices => list_devices()?,
     CliCommand::CreateAdminToken => {
+      let config = config
+        .cli
+        .ok_or_else(|| io::Error::other("missing [cli] configuration"))?;
+      let mut client = async_std::net::TcpStream::connect(&config.redis_addr)
+        .await
+        .map_err(|error| io::Error::other(format!("unable to connect to redis - {error}")))?;
+
       // Start by getting the list of our current tokens
       let get_command = kramer::Command::Hashes::<&str, &str>(kramer::HashCommand::Get(
-        &config.cli.token_store,
+        &config.token_store,
         Some(kramer::Arity::One("_admin")),
       ));
-      let result = kramer::execute(&mut client, get_command).await.map_err(|error| {
-        io::Error::new(
-          io::ErrorKind::Other,
-          format!("unable to get current admin tokens - {error}"),
-        )
-      })?;
+      let result = kramer::execute(&mut client, get_command)
+        .await
+        .map_err(|error| io::Error::other(format!("unable to get current admin tokens - {error}")))?;
 
       let mut current_tokens = match &result {
         kramer::Response::Item(kramer::ResponseValue::String(content)) => serde_json::from_str::<Vec<String>>(content)?,
         kramer::Response::Item(kramer::ResponseValue::Empty) => vec![],
         response => {
           log::warn!("unrecognized response from admin token lookup");
-          return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("bad lookup - {response:?}"),
-          ));
+          return Err(io::Error::other(format!("bad lookup - {response:?}")));
         }
       };
       log::info!("{result:?}");
@@ -69,7 +98,7 @@ async fn run(args: CommandLineOptions, config: RuntimeConfiguration) -> io::Resu
       let new_contents = serde_json::to_string(&current_tokens)?;
 
       let command = kramer::Command::Hashes::<&str, &str>(kramer::HashCommand::Set(
-        &config.cli.token_store,
+        &config.token_store,
         kramer::Arity::One(("_admin", &new_contents)),
         kramer::Insertion::Always,
       ));
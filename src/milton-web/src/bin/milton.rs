@@ -4,16 +4,19 @@ use std::io::Result;
 
 use async_std::channel;
 use async_std::prelude::FutureExt;
-use async_std::stream::StreamExt;
 
 #[derive(Deserialize, Debug)]
 struct RuntimeConfiguration {
-  #[allow(unused)]
-  lights: milton::lights::LightConfiguration,
+  lights: Vec<milton::lights::LightConfiguration>,
 
   oauth: milton::oauth::AuthZeroConfig,
 
   server: milton::server::Configuration,
+
+  printer: Option<milton::server::automation::AutomationConfiguration>,
+
+  #[serde(default)]
+  schedules: std::collections::BTreeMap<String, milton::schedule::Rule>,
 }
 
 #[derive(Deserialize, clap::Parser)]
@@ -25,64 +28,129 @@ struct CommandLineOptions {
 
 async fn manage_effects(
   server_effects: channel::Receiver<milton::server::effects::Effects>,
-  light_commands: channel::Sender<milton::lights::Command>,
+  light_commands: milton::lights::Router,
 ) -> Result<()> {
   log::debug!("managing effects");
-  let mut interval = async_std::stream::interval(std::time::Duration::from_millis(100));
 
-  loop {
-    let server_result: Result<Option<&str>> = match server_effects.try_recv() {
-      Ok(milton::server::effects::Effects::Lights(command)) => {
+  while let Ok(effect) = server_effects.recv().await {
+    match effect {
+      milton::server::effects::Effects::Lights(command) => {
         if let Err(error) = light_commands.send(command).await {
           log::warn!("unable to propagate command - {error}");
         }
-
-        Ok(Some(""))
-      }
-      Err(error) if error.is_closed() => {
-        log::warn!("effect loop closed");
-        break;
       }
-      _ => Ok(None),
-    };
-
-    if let Err(error) = server_result {
-      log::warn!("problem with result - {error}");
     }
+  }
+
+  log::warn!("effect loop closed");
+  Err(std::io::Error::other("closed effect loop"))
+}
 
-    interval.next().await;
+async fn audit_effects(server_effects: channel::Receiver<milton::server::effects::Effects>) {
+  while let Ok(effect) = server_effects.recv().await {
+    log::info!("[audit] {effect}");
   }
+}
 
-  Err(std::io::Error::new(std::io::ErrorKind::Other, "closed effect loop"))
+async fn persist_light_states(
+  server: milton::server::State,
+  states: channel::Receiver<(String, milton::lights::LightState)>,
+) {
+  while let Ok((name, state)) = states.recv().await {
+    if let Err(error) = server.save_light_state(&name, &state).await {
+      log::warn!("unable to save light state of '{name}' - {error}");
+    }
+  }
 }
 
 async fn serve(config: RuntimeConfiguration) -> Result<()> {
   log::info!("thread running, preparing channels");
-  let server_effects = channel::bounded(1);
-  let light_effects = channel::bounded(10);
+  let server_effects = milton::server::effects::Bus::default();
+
+  // The light runtimes must see every command, so publishers wait for them to make room; the audit
+  // log would rather miss an effect than slow anyone down.
+  let light_subscription = server_effects.subscribe("lights", 16, milton::server::effects::Overflow::Wait);
+  let audit_subscription = server_effects.subscribe("audit", 64, milton::server::effects::Overflow::Drop);
+
+  if config.lights.is_empty() {
+    return Err(std::io::Error::other("no light controllers configured"));
+  }
+
+  let mut light_router = milton::lights::Router::default();
+  let mut light_statuses = milton::lights::SharedStatuses::new();
+  let mut light_runtimes = Vec::with_capacity(config.lights.len());
+
+  for lights in config.lights {
+    if light_statuses.contains_key(&lights.name) {
+      return Err(std::io::Error::other(format!(
+        "light controller '{}' configured more than once",
+        lights.name
+      )));
+    }
+
+    let light_effects = channel::bounded(10);
+    let light_status = milton::lights::SharedStatus::default();
+    let name = lights.name.clone();
+
+    light_effects
+      .0
+      .send(milton::lights::Command::Configure(lights).into())
+      .await
+      .map_err(|error| {
+        log::error!("unable to populate initial light effect manager initial config - {error}");
+        std::io::Error::other(error)
+      })?;
+
+    light_runtimes.push((name.clone(), light_effects.1, light_status.clone()));
+    light_statuses.insert(name.clone(), light_status);
+    light_router.add(name, light_effects.0);
+  }
 
   log::info!("initializing server...");
   let server = milton::server::State::builder()
     .oauth(config.oauth)
     .version(option_env!("MILTON_VERSION").unwrap_or_else(|| "dev").to_string())
     .config(config.server)
-    .sender(server_effects.0.clone())
+    .effects(server_effects)
+    .lights(light_statuses)
+    .schedules(config.schedules)
     .build()?;
 
-  light_effects
-    .0
-    .send(milton::lights::Command::Configure(config.lights))
-    .await
-    .map_err(|error| {
-      log::error!("unable to populate initial light effect manager initial config - {error}");
-      std::io::Error::new(std::io::ErrorKind::Other, error)
-    })?;
+  // Each light runtime starts out with whatever state its lights were last left in, restoring it
+  // once connected; changes are saved as they are acknowledged.
+  let light_states = channel::unbounded();
+  let mut light_threads = Vec::with_capacity(light_runtimes.len());
+
+  for (name, light_effects, light_status) in light_runtimes {
+    if let Some(saved) = server.saved_light_state(&name).await {
+      log::info!("restoring saved light state for '{name}' - {saved:?}");
+      light_status.write().await.state = saved;
+    }
+
+    log::info!("spawing blinker channel worker thread for '{name}'");
+    light_threads.push(async_std::task::spawn(milton::lights::run(
+      light_effects,
+      light_status,
+      light_states.0.clone(),
+    )));
+  }
+
+  async_std::task::spawn(persist_light_states(server.clone(), light_states.1));
 
   log::info!("spawing effect management thread");
-  let effect_thread = async_std::task::spawn(manage_effects(server_effects.1, light_effects.0));
+  let effect_thread = async_std::task::spawn(manage_effects(light_subscription, light_router));
+  async_std::task::spawn(audit_effects(audit_subscription));
+
+  log::info!("spawing light scheduler thread");
+  async_std::task::spawn(milton::server::schedules::run(server.clone()));
+
+  if let Some(printer) = config.printer {
+    log::info!("spawing printer automation thread");
+    async_std::task::spawn(milton::server::automation::run(server.clone(), printer));
+  }
 
-  log::info!("spawing blinker channel worker thread");
-  let light_thread = async_std::task::spawn(milton::lights::run(light_effects.1));
+  // The first light runtime to stop takes the rest of the server down with it.
+  let light_thread = async { futures::future::select_all(light_threads).await.0 };
 
   let addr = std::env::var("WEBHOOK_LISTENER_ADDR").unwrap_or_else(|_| "0.0.0.0:8081".into());
   log::info!("preparing web thread on addr '{}'", addr);
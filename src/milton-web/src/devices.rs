@@ -0,0 +1,297 @@
+//! Discovery of the serial ports that light controllers may be attached to. Device nodes like
+//! `/dev/ttyACM0` are handed out in whatever order the kernel sees devices, so light controllers
+//! can instead be configured by the usb vendor id, product id and/or serial number they report.
+
+use serde::{Deserialize, Serialize};
+use std::io::{self, Result};
+
+/// What a usb serial port reports about the device attached to it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UsbInfo {
+  /// The usb vendor id.
+  pub vid: u16,
+
+  /// The usb product id.
+  pub pid: u16,
+
+  /// The serial number of the device, if it has one.
+  pub serial_number: Option<String>,
+
+  /// The name of the device manufacturer, if it reports one.
+  pub manufacturer: Option<String>,
+
+  /// The name of the device, if it reports one.
+  pub product: Option<String>,
+}
+
+/// A serial port that a light controller may be attached to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Device {
+  /// The path of the device node, e.g `/dev/ttyACM0`.
+  pub path: String,
+
+  /// What the device reports about itself, if it is attached over usb.
+  pub usb: Option<UsbInfo>,
+}
+
+/// Identifies a usb device by any combination of vendor id, product id and serial number; every
+/// field that is provided must match.
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct UsbSelector {
+  /// The usb vendor id, e.g `0x303a` for espressif.
+  pub vid: Option<u16>,
+
+  /// The usb product id.
+  pub pid: Option<u16>,
+
+  /// The serial number of the device.
+  pub serial_number: Option<String>,
+}
+
+impl UsbSelector {
+  /// Returns true if the usb device matches every field of this selector.
+  pub fn matches(&self, usb: &UsbInfo) -> bool {
+    self.vid.map(|vid| vid == usb.vid).unwrap_or(true)
+      && self.pid.map(|pid| pid == usb.pid).unwrap_or(true)
+      && self
+        .serial_number
+        .as_ref()
+        .map(|serial| usb.serial_number.as_ref() == Some(serial))
+        .unwrap_or(true)
+  }
+}
+
+impl std::fmt::Display for UsbSelector {
+  fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+    let vid = self.vid.map(|vid| format!("{vid:04x}")).unwrap_or_else(|| "*".into());
+    let pid = self.pid.map(|pid| format!("{pid:04x}")).unwrap_or_else(|| "*".into());
+    write!(formatter, "{vid}:{pid}")?;
+
+    if let Some(serial) = &self.serial_number {
+      write!(formatter, " ({serial})")?;
+    }
+
+    Ok(())
+  }
+}
+
+/// Reads a single usb attribute out of sysfs, e.g `idVendor`.
+fn read_attribute(directory: &std::path::Path, name: &str) -> Option<String> {
+  std::fs::read_to_string(directory.join(name))
+    .ok()
+    .map(|value| value.trim().to_string())
+    .filter(|value| !value.is_empty())
+}
+
+/// Without libudev, `serialport` only reports the sysfs entry of each tty (e.g
+/// `/sys/class/tty/ttyACM0`). The usb device that owns the tty is the closest ancestor of its
+/// `device` link that has an `idVendor` attribute.
+fn sysfs_usb(entry: &std::path::Path) -> Option<UsbInfo> {
+  let device = std::fs::canonicalize(entry.join("device")).ok()?;
+  let directory = device
+    .ancestors()
+    .find(|directory| directory.join("idVendor").is_file())?;
+
+  Some(UsbInfo {
+    vid: u16::from_str_radix(&read_attribute(directory, "idVendor")?, 16).ok()?,
+    pid: u16::from_str_radix(&read_attribute(directory, "idProduct")?, 16).ok()?,
+    serial_number: read_attribute(directory, "serial"),
+    manufacturer: read_attribute(directory, "manufacturer"),
+    product: read_attribute(directory, "product"),
+  })
+}
+
+/// Returns every serial port currently available.
+pub fn available() -> Result<Vec<Device>> {
+  let ports = serialport::available_ports().map_err(|error| io::Error::other(error.to_string()))?;
+
+  let devices = ports
+    .into_iter()
+    .map(|port| match port.port_type {
+      serialport::SerialPortType::UsbPort(usb) => Device {
+        path: port.port_name,
+        usb: Some(UsbInfo {
+          vid: usb.vid,
+          pid: usb.pid,
+          serial_number: usb.serial_number,
+          manufacturer: usb.manufacturer,
+          product: usb.product,
+        }),
+      },
+      _ => {
+        let entry = std::path::Path::new(&port.port_name);
+
+        match (entry.starts_with("/sys"), entry.file_name()) {
+          (true, Some(name)) => Device {
+            path: format!("/dev/{}", name.to_string_lossy()),
+            usb: sysfs_usb(entry),
+          },
+          _ => Device {
+            path: port.port_name,
+            usb: None,
+          },
+        }
+      }
+    })
+    .collect();
+
+  Ok(devices)
+}
+
+/// Returns the path of the only available serial port whose usb device matches the selector. An
+/// error is returned if there is no such port, or if there are several and we cannot tell which
+/// one is meant.
+pub fn find(selector: &UsbSelector) -> Result<String> {
+  select(selector, available()?)
+}
+
+/// Picks the path of the only device whose usb device matches the selector; see `find`.
+fn select(selector: &UsbSelector, devices: Vec<Device>) -> Result<String> {
+  let mut matches = devices
+    .into_iter()
+    .filter(|device| device.usb.as_ref().map(|usb| selector.matches(usb)).unwrap_or(false))
+    .map(|device| device.path)
+    .collect::<Vec<String>>();
+
+  match matches.len() {
+    1 => Ok(matches.remove(0)),
+    0 => Err(io::Error::new(
+      io::ErrorKind::NotFound,
+      format!("no serial port found for usb device {selector}"),
+    )),
+    _ => Err(io::Error::other(format!(
+      "usb device {selector} matches several serial ports ({}); add a serial number to tell them apart",
+      matches.join(", ")
+    ))),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{select, sysfs_usb, Device, UsbInfo, UsbSelector};
+
+  /// Returns a usb serial port at the path.
+  fn device(path: &str, vid: u16, pid: u16, serial_number: Option<&str>) -> Device {
+    Device {
+      path: path.to_string(),
+      usb: Some(UsbInfo {
+        vid,
+        pid,
+        serial_number: serial_number.map(String::from),
+        manufacturer: None,
+        product: None,
+      }),
+    }
+  }
+
+  /// Returns a selector for the vendor id, product id and serial number.
+  fn selector(vid: Option<u16>, pid: Option<u16>, serial_number: Option<&str>) -> UsbSelector {
+    UsbSelector {
+      vid,
+      pid,
+      serial_number: serial_number.map(String::from),
+    }
+  }
+
+  #[test]
+  fn matches_every_provided_field() {
+    let usb = device("/dev/ttyACM0", 0x303a, 0x1001, Some("abc"))
+      .usb
+      .expect("usb device");
+
+    assert!(selector(None, None, None).matches(&usb));
+    assert!(selector(Some(0x303a), None, None).matches(&usb));
+    assert!(selector(Some(0x303a), Some(0x1001), None).matches(&usb));
+    assert!(selector(Some(0x303a), Some(0x1001), Some("abc")).matches(&usb));
+
+    assert!(!selector(Some(0x1a86), None, None).matches(&usb));
+    assert!(!selector(Some(0x303a), Some(0x1002), None).matches(&usb));
+    assert!(!selector(Some(0x303a), Some(0x1001), Some("abd")).matches(&usb));
+  }
+
+  #[test]
+  fn serial_numbers_require_a_device_that_reports_one() {
+    let usb = device("/dev/ttyACM0", 0x303a, 0x1001, None).usb.expect("usb device");
+
+    assert!(selector(Some(0x303a), None, None).matches(&usb));
+    assert!(!selector(Some(0x303a), None, Some("abc")).matches(&usb));
+  }
+
+  #[test]
+  fn hex_ids_are_case_insensitive() {
+    let lower: UsbSelector = toml::from_str("vid = 0x303a\npid = 0x100f").expect("valid selector");
+    let upper: UsbSelector = toml::from_str("vid = 0x303A\npid = 0x100F").expect("valid selector");
+
+    assert_eq!(lower, upper);
+    assert_eq!(lower, selector(Some(0x303a), Some(0x100f), None));
+    assert_eq!(upper.to_string(), "303a:100f");
+  }
+
+  #[test]
+  fn reads_usb_ids_from_sysfs_in_either_case() {
+    let root = std::env::temp_dir().join(format!("milton-devices-{}", std::process::id()));
+    let usb = root.join("usb1/1-1");
+    let interface = usb.join("1-1:1.0");
+    let tty = root.join("class/tty/ttyACM0");
+    std::fs::create_dir_all(&interface).expect("created usb device");
+    std::fs::create_dir_all(&tty).expect("created tty");
+    std::fs::write(usb.join("idVendor"), "303A\n").expect("wrote vendor id");
+    std::fs::write(usb.join("idProduct"), "100f\n").expect("wrote product id");
+    std::fs::write(usb.join("serial"), "abc\n").expect("wrote serial number");
+    std::os::unix::fs::symlink(&interface, tty.join("device")).expect("linked tty to its device");
+
+    let found = sysfs_usb(&tty);
+    std::fs::remove_dir_all(&root).expect("removed sysfs");
+
+    let found = found.expect("usb device");
+    assert_eq!((found.vid, found.pid), (0x303a, 0x100f));
+    assert_eq!(found.serial_number.as_deref(), Some("abc"));
+    assert_eq!(found.manufacturer, None);
+  }
+
+  #[test]
+  fn finds_the_only_matching_port() {
+    let devices = vec![
+      Device {
+        path: "/dev/ttyS0".to_string(),
+        usb: None,
+      },
+      device("/dev/ttyACM0", 0x303a, 0x1001, Some("abc")),
+      device("/dev/ttyUSB0", 0x1a86, 0x7523, None),
+    ];
+
+    assert_eq!(
+      select(&selector(Some(0x303a), None, None), devices.clone()).ok(),
+      Some("/dev/ttyACM0".to_string())
+    );
+    assert_eq!(
+      select(&selector(Some(0x1a86), Some(0x7523), None), devices).ok(),
+      Some("/dev/ttyUSB0".to_string())
+    );
+  }
+
+  #[test]
+  fn refuses_missing_ports() {
+    let devices = vec![device("/dev/ttyACM0", 0x303a, 0x1001, Some("abc"))];
+
+    let error = select(&selector(Some(0x303a), None, Some("xyz")), devices).expect_err("no port matches");
+    assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+    assert_eq!(error.to_string(), "no serial port found for usb device 303a:* (xyz)");
+  }
+
+  #[test]
+  fn refuses_ambiguous_ports() {
+    let devices = vec![
+      device("/dev/ttyACM0", 0x303a, 0x1001, Some("abc")),
+      device("/dev/ttyACM1", 0x303a, 0x1001, Some("def")),
+    ];
+
+    let error = select(&selector(Some(0x303a), Some(0x1001), None), devices.clone()).expect_err("several ports match");
+    assert!(error.to_string().contains("/dev/ttyACM0, /dev/ttyACM1"), "{error}");
+
+    assert_eq!(
+      select(&selector(Some(0x303a), Some(0x1001), Some("def")), devices).ok(),
+      Some("/dev/ttyACM1".to_string())
+    );
+  }
+}
@@ -2,6 +2,12 @@
 
 //! The general "library" code used across all applications living in this cargo/rust application.
 
+/// Parsing for the color strings accepted by the control api.
+pub mod colors;
+
+/// Discovery of the serial ports that light controllers may be attached to.
+pub mod devices;
+
 /// Exposes functionality for controlling the light firmware.
 pub mod lights;
 
@@ -11,5 +17,11 @@ pub mod oauth;
 /// Octoprint types and functionality.
 pub(crate) mod octoprint;
 
+/// Time-based rules that control the lights.
+pub mod schedule;
+
 /// This module contains all of the web/http server types and logic.
 pub mod server;
+
+/// The links that bytes travel over between the light runtime and a light controller.
+pub mod transport;
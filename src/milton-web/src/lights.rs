@@ -1,18 +1,125 @@
 use async_std::channel;
-use async_std::stream::StreamExt;
-use serde::Deserialize;
+use async_std::prelude::FutureExt;
+use serde::{Deserialize, Serialize};
 use std::io::{self, Result};
 
+/// How long to wait for the light controller to identify itself after connecting.
+const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// How long to wait for the light controller to acknowledge a command before giving up on it.
+pub const ACK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// How long to wait before the first attempt to reconnect to a light controller; doubled after
+/// every attempt that fails.
+const MIN_RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// The longest we wait between attempts to reconnect to a light controller.
+const MAX_RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// How often the light controller is asked for its status while connected.
+const STATUS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// The wire format used when talking to the light controller. Text is only kept around for
+/// controllers running firmware that predates the framed protocol.
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+  /// Length-prefixed, checksummed frames; see the `milton_protocol` crate.
+  #[default]
+  Framed,
+
+  /// The legacy `:`-terminated text commands, e.g `on:`.
+  Text,
+}
+
 #[allow(clippy::missing_docs_in_private_items)]
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct LightConfiguration {
-  pub device: String,
+  /// The name that commands use to target this light controller; see `Command::Target`.
+  pub name: String,
+
+  /// How to reach the light controller; defaults to a serial port.
+  #[serde(default)]
+  pub transport: crate::transport::TransportKind,
+
+  /// Where to reach the light controller when using the tcp (e.g `192.168.1.20:3333`) or memory
+  /// transports.
+  pub address: Option<String>,
+
+  /// The path of the serial device, e.g `/dev/ttyACM0`; ignored when `usb` is provided.
+  #[serde(default)]
+  pub device: Option<String>,
 
+  /// Identifies the serial device by what its usb device reports instead of by path; the path is
+  /// looked up again on every connection attempt.
+  pub usb: Option<crate::devices::UsbSelector>,
+
+  #[serde(default = "default_baud")]
   pub baud: u32,
+
+  #[serde(default)]
+  pub protocol: Protocol,
+
+  /// How many leds the strip attached to this controller has; sent every time we connect. When
+  /// absent, the firmware keeps whatever count it was built with.
+  pub led_count: Option<u16>,
+
+  /// Named ranges of leds that commands can be scoped to; see `Command::Zone`.
+  #[serde(default)]
+  pub zones: std::collections::HashMap<String, Zone>,
+
+  /// The most commands written to the light controller per second. Commands arriving faster than
+  /// this wait their turn, and are coalesced with whatever later commands supersede them; once a
+  /// second's worth from the same requester are waiting, further commands from it are refused as
+  /// `Outcome::RateLimited`. See `Request::requester`.
+  #[serde(default = "default_max_rate")]
+  pub max_rate: u32,
+}
+
+/// The baud rate of the serial connection when none is configured; this is what the firmware uses.
+fn default_baud() -> u32 {
+  115200
+}
+
+/// The command rate used when none is configured; comfortably within what the firmware keeps up
+/// with.
+fn default_max_rate() -> u32 {
+  20
+}
+
+/// A named range of leds, written in configuration as an inclusive range, e.g `"0..4"` for the
+/// first five leds.
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(try_from = "String")]
+pub struct Zone {
+  /// The first led in the zone.
+  pub first: u16,
+
+  /// The last led in the zone.
+  pub last: u16,
+}
+
+impl TryFrom<String> for Zone {
+  type Error = String;
+
+  fn try_from(input: String) -> std::result::Result<Self, Self::Error> {
+    let invalid = || format!("invalid zone '{input}', expected an inclusive range like '0..4'");
+    let (first, last) = input.split_once("..").ok_or_else(invalid)?;
+    let first = first.trim().parse::<u16>().map_err(|_| invalid())?;
+    let last = last.trim().parse::<u16>().map_err(|_| invalid())?;
+
+    if first > last || last == u16::MAX {
+      return Err(invalid());
+    }
+
+    Ok(Self { first, last })
+  }
 }
 
+/// The colors built into the firmware, each at full intensity; `Green` is `#00ff00`, which is css
+/// `lime` rather than css `green` (see `crate::colors`).
 #[allow(clippy::missing_docs_in_private_items)]
-#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum BasicColor {
   Red,
@@ -30,142 +137,1951 @@ impl std::fmt::Display for BasicColor {
   }
 }
 
+/// Enumerates the animated effects rendered by the light controller firmware.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Effect {
+  /// Fade a color in and out.
+  Breathe(u8, u8, u8),
+
+  /// Cycle through the color wheel.
+  Rainbow,
+
+  /// A lit led with a fading tail travelling along the strip.
+  Comet(u8, u8, u8),
+
+  /// Turn a color on and off.
+  Blink(u8, u8, u8),
+
+  /// Fill the strip proportionally to a percentage (0-100).
+  Progress(u8, u8, u8, u8),
+}
+
+impl Effect {
+  /// Returns the payload of a `CommandId::Effect` frame for this effect.
+  fn payload(&self) -> Vec<u8> {
+    use milton_protocol::EffectId;
+
+    match self {
+      Self::Breathe(red, green, blue) => vec![EffectId::Breathe as u8, *red, *green, *blue],
+      Self::Rainbow => vec![EffectId::Rainbow as u8],
+      Self::Comet(red, green, blue) => vec![EffectId::Comet as u8, *red, *green, *blue],
+      Self::Blink(red, green, blue) => vec![EffectId::Blink as u8, *red, *green, *blue],
+      Self::Progress(percent, red, green, blue) => vec![EffectId::Progress as u8, *percent, *red, *green, *blue],
+    }
+  }
+}
+
+impl Effect {
+  /// Attempts to build an effect from the `[effect id, arguments..]` payload of a
+  /// `CommandId::Effect` frame.
+  fn from_payload(payload: &[u8]) -> Option<Self> {
+    use milton_protocol::EffectId;
+
+    let (id, arguments) = payload.split_first()?;
+
+    match (EffectId::from_byte(*id)?, arguments) {
+      (EffectId::Breathe, [red, green, blue]) => Some(Self::Breathe(*red, *green, *blue)),
+      (EffectId::Rainbow, []) => Some(Self::Rainbow),
+      (EffectId::Comet, [red, green, blue]) => Some(Self::Comet(*red, *green, *blue)),
+      (EffectId::Blink, [red, green, blue]) => Some(Self::Blink(*red, *green, *blue)),
+      (EffectId::Progress, [percent, red, green, blue]) => Some(Self::Progress(*percent, *red, *green, *blue)),
+      _ => None,
+    }
+  }
+}
+
+impl std::fmt::Display for Effect {
+  fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Self::Breathe(red, green, blue) => write!(formatter, "breathe=#{red:02x}{green:02x}{blue:02x}"),
+      Self::Rainbow => write!(formatter, "rainbow"),
+      Self::Comet(red, green, blue) => write!(formatter, "comet=#{red:02x}{green:02x}{blue:02x}"),
+      Self::Blink(red, green, blue) => write!(formatter, "blink=#{red:02x}{green:02x}{blue:02x}"),
+      Self::Progress(percent, red, green, blue) => {
+        write!(formatter, "progress={percent},#{red:02x}{green:02x}{blue:02x}")
+      }
+    }
+  }
+}
+
 #[allow(clippy::missing_docs_in_private_items)]
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Command {
+  #[serde(skip)]
   Configure(LightConfiguration),
   On,
   BasicColor(BasicColor),
+  Rgb(u8, u8, u8),
+  Brightness(u8),
+  Effect(Effect),
   Off,
+  Transition(Box<Command>, u32),
+  LedCount(u16),
+  Fill(u16, u16, u8, u8, u8),
+  Pixels(Vec<(u16, u8, u8, u8)>),
+  Frame(Vec<(u8, u8, u8)>),
+  /// Scopes a color, brightness or effect command to a zone named in the `LightConfiguration`;
+  /// resolved into a `Segment` before it is sent.
+  Zone(String, Box<Command>),
+  /// Scopes a color, brightness or effect command to the leds from the first index up to, but not
+  /// including, the second.
+  Segment(u16, u16, Box<Command>),
+  /// Directs a command at the light controller with this name; unwrapped by the `Router` before
+  /// the command reaches the runtime of that controller.
+  Target(String, Box<Command>),
+  Identify,
+  Status,
 }
 
 impl std::fmt::Display for Command {
   fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
     match self {
-      Self::On => write!(formatter, "on:"),
-      Self::Off => write!(formatter, "off:"),
-      Self::BasicColor(color) => write!(formatter, "{color}:"),
-      _ => Ok(()),
+      Self::On => write!(formatter, "on"),
+      Self::Off => write!(formatter, "off"),
+      Self::BasicColor(color) => write!(formatter, "{color}"),
+      Self::Rgb(red, green, blue) => write!(formatter, "#{red:02x}{green:02x}{blue:02x}"),
+      Self::Brightness(level) => write!(formatter, "brightness={level}"),
+      Self::Effect(effect) => write!(formatter, "{effect}"),
+      Self::Transition(command, duration) => write!(formatter, "{command},transition={duration}"),
+      Self::LedCount(count) => write!(formatter, "leds={count}"),
+      Self::Fill(start, end, red, green, blue) => {
+        write!(formatter, "fill={start}..{end},#{red:02x}{green:02x}{blue:02x}")
+      }
+      Self::Pixels(pixels) => {
+        let pixels = pixels
+          .iter()
+          .map(|(index, red, green, blue)| format!("{index}#{red:02x}{green:02x}{blue:02x}"))
+          .collect::<Vec<String>>();
+        write!(formatter, "pixels={}", pixels.join(","))
+      }
+      Self::Frame(colors) => {
+        write!(formatter, "frame=")?;
+        colors
+          .iter()
+          .try_for_each(|(red, green, blue)| write!(formatter, "#{red:02x}{green:02x}{blue:02x}"))
+      }
+      Self::Zone(name, command) => write!(formatter, "zone={name},{command}"),
+      Self::Segment(start, end, command) => write!(formatter, "zone={start}..{end},{command}"),
+      Self::Target(name, command) => write!(formatter, "target={name},{command}"),
+      Self::Identify => write!(formatter, "identify"),
+      Self::Status => write!(formatter, "status"),
+      Self::Configure(_) => Ok(()),
     }
   }
 }
 
-/// Helper method that will attempt to pull a message off our channel and handle returning an err
-/// based on the correct conditions when that should occur.
-fn next(channel: &mut channel::Receiver<Command>) -> Result<Option<Command>> {
-  if channel.is_closed() {
-    return Err(io::Error::new(io::ErrorKind::Other, "message channel has been closed"));
+impl Command {
+  /// Returns the command id and payload of the frame for this command, if it is one that is sent to
+  /// the light controller.
+  fn payload(&self) -> Option<(milton_protocol::CommandId, Vec<u8>)> {
+    use milton_protocol::CommandId;
+
+    let payload = match self {
+      Self::Configure(_) => return None,
+      Self::On => (CommandId::On, vec![]),
+      Self::Off => (CommandId::Off, vec![]),
+      Self::BasicColor(BasicColor::Red) => (CommandId::Red, vec![]),
+      Self::BasicColor(BasicColor::Green) => (CommandId::Green, vec![]),
+      Self::BasicColor(BasicColor::Blue) => (CommandId::Blue, vec![]),
+      Self::Rgb(red, green, blue) => (CommandId::Rgb, vec![*red, *green, *blue]),
+      Self::Brightness(level) => (CommandId::Brightness, vec![*level]),
+      Self::Effect(effect) => (CommandId::Effect, effect.payload()),
+      Self::Transition(command, duration) => {
+        let (id, inner) = command.payload()?;
+        let mut payload = duration.to_le_bytes().to_vec();
+        payload.push(id as u8);
+        payload.extend_from_slice(&inner);
+        (CommandId::Transition, payload)
+      }
+      Self::LedCount(count) => (CommandId::LedCount, count.to_le_bytes().to_vec()),
+      Self::Fill(start, end, red, green, blue) => {
+        let mut payload = start.to_le_bytes().to_vec();
+        payload.extend_from_slice(&end.to_le_bytes());
+        payload.extend_from_slice(&[*red, *green, *blue]);
+        (CommandId::Fill, payload)
+      }
+      Self::Pixels(pixels) => (
+        CommandId::Pixels,
+        pixels
+          .iter()
+          .flat_map(|(index, red, green, blue)| {
+            let index = index.to_le_bytes();
+            [index[0], index[1], *red, *green, *blue]
+          })
+          .collect(),
+      ),
+      Self::Frame(colors) => (
+        CommandId::Frame,
+        colors
+          .iter()
+          .flat_map(|(red, green, blue)| [*red, *green, *blue])
+          .collect(),
+      ),
+      Self::Zone(..) | Self::Target(..) => return None,
+      Self::Segment(start, end, command) => {
+        let (id, inner) = command.payload()?;
+        let mut payload = start.to_le_bytes().to_vec();
+        payload.extend_from_slice(&end.to_le_bytes());
+        payload.push(id as u8);
+        payload.extend_from_slice(&inner);
+        (CommandId::Zone, payload)
+      }
+      Self::Identify => (CommandId::Identify, vec![]),
+      Self::Status => (CommandId::Status, vec![]),
+    };
+
+    Some(payload)
+  }
+
+  /// Returns true for the commands that can be scoped to a zone.
+  pub fn is_zonable(&self) -> bool {
+    matches!(
+      self,
+      Self::On | Self::Off | Self::BasicColor(_) | Self::Rgb(..) | Self::Brightness(_) | Self::Effect(_)
+    )
+  }
+
+  /// Returns the command without any transition it is wrapped in.
+  fn untransitioned(&self) -> &Self {
+    match self {
+      Self::Transition(command, _) => command.untransitioned(),
+      other => other,
+    }
+  }
+
+  /// Returns true if the lights end up the same whether or not the earlier command is sent before
+  /// this one, in which case an earlier command that has not been written yet can be skipped.
+  /// Colors and effects replace everything painted on the strip (zones included), while
+  /// brightness, led counts and zones only replace earlier commands of their own kind.
+  pub fn supersedes(&self, earlier: &Self) -> bool {
+    match (self.untransitioned(), earlier.untransitioned()) {
+      (Self::On | Self::Off | Self::BasicColor(_) | Self::Rgb(..) | Self::Effect(_), earlier) => matches!(
+        earlier,
+        Self::On
+          | Self::Off
+          | Self::BasicColor(_)
+          | Self::Rgb(..)
+          | Self::Effect(_)
+          | Self::Fill(..)
+          | Self::Pixels(_)
+          | Self::Frame(_)
+          | Self::Segment(..)
+      ),
+      (Self::Brightness(_), Self::Brightness(_)) | (Self::LedCount(_), Self::LedCount(_)) => true,
+      (Self::Segment(start, end, command), Self::Segment(earlier_start, earlier_end, earlier)) => {
+        start == earlier_start && end == earlier_end && command.supersedes(earlier)
+      }
+      _ => false,
+    }
+  }
+
+  /// Replaces any named zones in this command with the range of leds they cover.
+  pub fn resolve(self, zones: &std::collections::HashMap<String, Zone>) -> Result<Self> {
+    match self {
+      Self::Zone(name, command) => {
+        let zone = zones
+          .get(&name)
+          .ok_or_else(|| io::Error::other(format!("unknown zone '{name}'")))?;
+
+        if !command.is_zonable() {
+          return Err(io::Error::other(format!("{command:?} cannot be scoped to a zone")));
+        }
+
+        Ok(Self::Segment(zone.first, zone.last + 1, command))
+      }
+      Self::Transition(command, duration) => Ok(Self::Transition(Box::new(command.resolve(zones)?), duration)),
+      other => Ok(other),
+    }
   }
 
-  match channel.try_recv() {
-    Err(error) if error.is_empty() => Ok(None),
-    Err(other) => Err(io::Error::new(io::ErrorKind::Other, format!("{other}"))),
-    Ok(cmd) => Ok(Some(cmd)),
+  /// Attempts to build the command that a frame with this id and payload would carry; this is the
+  /// inverse of `payload`, used to make sense of the state reported by the light controller.
+  fn from_payload(id: milton_protocol::CommandId, payload: &[u8]) -> Option<Self> {
+    use milton_protocol::CommandId;
+
+    match (id, payload) {
+      (CommandId::On, []) => Some(Self::On),
+      (CommandId::Off, []) => Some(Self::Off),
+      (CommandId::Red, []) => Some(Self::BasicColor(BasicColor::Red)),
+      (CommandId::Green, []) => Some(Self::BasicColor(BasicColor::Green)),
+      (CommandId::Blue, []) => Some(Self::BasicColor(BasicColor::Blue)),
+      (CommandId::Rgb, [red, green, blue]) => Some(Self::Rgb(*red, *green, *blue)),
+      (CommandId::Brightness, [level]) => Some(Self::Brightness(*level)),
+      (CommandId::Effect, payload) => Effect::from_payload(payload).map(Self::Effect),
+      (CommandId::LedCount, [low, high]) => Some(Self::LedCount(u16::from_le_bytes([*low, *high]))),
+      (CommandId::Fill, [a, b, c, d, red, green, blue]) => Some(Self::Fill(
+        u16::from_le_bytes([*a, *b]),
+        u16::from_le_bytes([*c, *d]),
+        *red,
+        *green,
+        *blue,
+      )),
+      // Status reports omit the payload of pixel and frame commands.
+      (CommandId::Pixels, payload) if payload.len() % 5 == 0 => Some(Self::Pixels(
+        payload
+          .chunks_exact(5)
+          .map(|chunk| (u16::from_le_bytes([chunk[0], chunk[1]]), chunk[2], chunk[3], chunk[4]))
+          .collect(),
+      )),
+      (CommandId::Frame, payload) if payload.len() % 3 == 0 => Some(Self::Frame(
+        payload
+          .chunks_exact(3)
+          .map(|chunk| (chunk[0], chunk[1], chunk[2]))
+          .collect(),
+      )),
+      _ => None,
+    }
+  }
+
+  /// Returns the bytes that should be written to the light controller for this command.
+  pub fn encode(&self, protocol: Protocol) -> Result<Vec<u8>> {
+    if protocol == Protocol::Text {
+      // Every text message sent to the firmware is terminated by a `:`.
+      return Ok(format!("{self}:").into_bytes());
+    }
+
+    let (id, payload) = self
+      .payload()
+      .ok_or_else(|| io::Error::other(format!("{self:?} is not sent to the light controller")))?;
+    let frame = milton_protocol::Frame::new(id, &payload);
+    let mut buffer = vec![0; frame.encoded_len()];
+    frame
+      .encode(&mut buffer)
+      .map_err(|error| io::Error::other(format!("unable to encode {self:?} - {error}")))?;
+
+    Ok(buffer)
   }
 }
 
-#[allow(clippy::missing_docs_in_private_items)]
-pub async fn run(mut receiver: channel::Receiver<Command>) -> Result<()> {
-  log::debug!("starting light effect manager runtime");
-  let mut timer = async_std::stream::interval(std::time::Duration::from_millis(10));
+/// How many fill, pixel and frame commands the light state holds on to; the oldest are forgotten
+/// first.
+const MAX_PAINT_COMMANDS: usize = 32;
+
+/// How the lights should currently look, built up from every command the light controller has
+/// acknowledged. The light runtime sends it again after every (re)connect, so that the strip comes
+/// back the way it was left; see `LightState::commands`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LightState {
+  /// The color or effect of the whole strip, e.g `{"rgb": [255, 0, 0]}` or `"off"`.
+  pub color: Option<Command>,
+
+  /// Fill, pixel and frame commands painted over the color since it was set, oldest first.
+  #[serde(default)]
+  pub paint: Vec<Command>,
+
+  /// Colors and effects scoped to ranges of leds since the color was set, oldest first.
+  #[serde(default)]
+  pub zones: Vec<Command>,
+
+  /// The brightness of the strip, if it has been set.
+  pub brightness: Option<u8>,
+}
+
+impl LightState {
+  /// Updates the state with a command the light controller has acknowledged, mirroring how the
+  /// firmware applies it. Transitions are dropped; they have long finished by the time the state
+  /// is restored.
+  pub fn apply(&mut self, command: &Command) {
+    match command.untransitioned() {
+      color @ (Command::On | Command::Off | Command::BasicColor(_) | Command::Rgb(..) | Command::Effect(_)) => {
+        self.color = Some(color.clone());
+        self.paint.clear();
+        self.zones.clear();
+      }
+      paint @ (Command::Fill(..) | Command::Pixels(_) | Command::Frame(_)) => {
+        if self.paint.len() >= MAX_PAINT_COMMANDS {
+          self.paint.remove(0);
+        }
+
+        self.paint.push(paint.clone());
+        self.zones.clear();
+      }
+      zone @ Command::Segment(..) => {
+        self.zones.retain(|earlier| !zone.supersedes(earlier));
+        self.zones.push(zone.clone());
+      }
+      Command::Brightness(level) => self.brightness = Some(*level),
+      _ => (),
+    }
+  }
+
+  /// Returns the commands that bring a freshly (re)connected light controller to this state, in
+  /// the order they should be sent.
+  pub fn commands(&self) -> Vec<Command> {
+    self
+      .color
+      .iter()
+      .chain(self.paint.iter())
+      .chain(self.zones.iter())
+      .cloned()
+      .chain(self.brightness.map(Command::Brightness))
+      .collect()
+  }
+
+  /// Returns the state that sending `commands` would leave the lights in; used to tidy up states
+  /// that did not come from acknowledged commands (e.g scenes saved through the api), so that they
+  /// hold no more than `MAX_PAINT_COMMANDS` and nothing that is no longer visible.
+  pub fn normalized(&self) -> Self {
+    let mut state = Self::default();
+    self.commands().iter().for_each(|command| state.apply(command));
+    state
+  }
+}
 
-  // The connection will hold our serialport ttyport.
-  let mut connection = None;
+/// Enumerates what can become of a command sent to the light runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+  /// The light controller replied with `ok`.
+  Acknowledged,
 
-  let mut empty_reads = 0;
-  let mut last_debug = std::time::Instant::now();
-  let mut last_configuration: Option<LightConfiguration> = None;
-  let mut force_reconnect = false;
+  /// The light controller replied with `failed`.
+  Rejected,
 
-  // A bit of a hack, we could use an `Option<Instant>` instead. The goal here is to allow the
-  // first configuration message to kick in immediately, while forcing others to wait a short
-  // period.
-  let mut last_connection_attempt = std::ops::Sub::sub(std::time::Instant::now(), std::time::Duration::from_secs(10));
+  /// There is no connection to the light controller, or it was lost before a reply arrived.
+  Disconnected,
+
+  /// The light controller did not reply within `ACK_TIMEOUT`.
+  Unanswered,
+
+  /// The command could not be sent to this light controller, e.g because it names a zone that is
+  /// not configured.
+  Invalid,
+
+  /// A later command superseded this one before it was written; the lights end up as though it
+  /// had been sent. See `Command::supersedes`.
+  Superseded,
+
+  /// Too many commands from the same requester are already waiting to be written to the light
+  /// controller; see `LightConfiguration::max_rate`.
+  RateLimited,
+}
+
+/// A command, along with the channel that its outcome will be sent on once the light controller
+/// has replied to it. Clones share the reply channel, so only one of them should be sent on to a
+/// light controller.
+#[derive(Debug, Clone)]
+pub struct Request {
+  /// The command to send.
+  pub command: Command,
+
+  /// Where to send the outcome; untracked requests are fire-and-forget.
+  pub reply: Option<channel::Sender<Outcome>>,
+
+  /// Who sent the command, e.g a session or a schedule. Each requester gets its own share of the
+  /// commands allowed to wait, so that one busy client cannot crowd the others out; requests
+  /// without one share theirs.
+  pub requester: Option<String>,
+}
+
+impl Request {
+  /// Creates a request whose outcome will be sent on the returned receiver.
+  pub fn tracked(command: Command) -> (Self, channel::Receiver<Outcome>) {
+    let (sender, receiver) = channel::bounded(1);
+    let request = Self {
+      command,
+      reply: Some(sender),
+      requester: None,
+    };
+    (request, receiver)
+  }
+
+  /// Attributes the request to the requester; see `Request::requester`.
+  pub fn requested_by(self, requester: String) -> Self {
+    Self {
+      requester: Some(requester),
+      ..self
+    }
+  }
+}
+
+impl From<Command> for Request {
+  fn from(command: Command) -> Self {
+    Self {
+      command,
+      reply: None,
+      requester: None,
+    }
+  }
+}
+
+/// Hands requests off to the runtime of the light controller that they target.
+#[derive(Debug, Clone, Default)]
+pub struct Router {
+  /// The name of each light controller and the channel into its runtime, in the order they were
+  /// configured.
+  controllers: Vec<(String, channel::Sender<Request>)>,
+}
+
+impl Router {
+  /// Adds a light controller; the first one added receives any requests without a target.
+  pub fn add(&mut self, name: String, sender: channel::Sender<Request>) {
+    self.controllers.push((name, sender));
+  }
+
+  /// Sends the request to the runtime of the light controller it targets. Requests targeting a
+  /// light controller we do not know of are resolved as `Outcome::Invalid`.
+  pub async fn send(&self, request: Request) -> Result<()> {
+    let Request {
+      command,
+      reply,
+      requester,
+    } = request;
+
+    let (target, command) = match command {
+      Command::Target(name, command) => (Some(name), *command),
+      command => (None, command),
+    };
+
+    let controller = match target {
+      Some(ref name) => self.controllers.iter().find(|(candidate, _)| candidate == name),
+      None => self.controllers.first(),
+    };
+
+    match controller {
+      Some((_, sender)) => sender
+        .send(Request {
+          command,
+          reply,
+          requester,
+        })
+        .await
+        .map_err(|error| io::Error::other(format!("unable to send light command - {error}"))),
+      None => {
+        log::warn!("dropping light command for unknown light controller {target:?}");
+        resolve(reply, Outcome::Invalid);
+        Ok(())
+      }
+    }
+  }
+}
+
+/// Sends the outcome to whoever is waiting on it, if anyone still is.
+fn resolve(reply: Option<channel::Sender<Outcome>>, outcome: Outcome) {
+  if let Some(Err(error)) = reply.map(|reply| reply.try_send(outcome)) {
+    log::debug!("nobody waiting on light command outcome ({outcome:?}) - {error}");
+  }
+}
+
+/// A command that has been written to the light controller but not yet replied to. The firmware
+/// handles requests one at a time, so replies arrive in the order commands were written.
+#[derive(Debug)]
+struct Pending {
+  /// Set for status requests, which are answered with a status report rather than an ack; they
+  /// still take up a slot, so that a nack is matched with the request it was meant for.
+  status: bool,
+
+  /// The command that was written, if acknowledging it changes the light state.
+  command: Option<Command>,
+
+  /// Where to send the outcome, if anyone is waiting on it.
+  reply: Option<channel::Sender<Outcome>>,
+
+  /// When to give up waiting for the reply.
+  deadline: std::time::Instant,
+}
+
+/// A command that has been accepted by the light runtime but not yet written to the light
+/// controller, waiting for the previous command to be replied to or for the rate limit to allow it.
+#[derive(Debug)]
+struct Waiting {
+  /// The command, with any zones resolved; used to tell whether later commands supersede it.
+  command: Command,
+
+  /// What will be written to the light controller.
+  bytes: Vec<u8>,
+
+  /// Where to send the outcome, if anyone is waiting on it.
+  reply: Option<channel::Sender<Outcome>>,
+
+  /// Who sent the command; see `Request::requester`.
+  requester: Option<String>,
+
+  /// Set for commands that restore the light state after connecting; they are already part of it,
+  /// so acknowledging them does not change it.
+  restoring: bool,
+}
+
+/// Resolves the oldest pending command with the outcome of the reply we just received, returning
+/// the command if the light controller acknowledged it.
+fn resolve_oldest(pending: &mut std::collections::VecDeque<Pending>, outcome: Outcome) -> Option<Command> {
+  match pending.pop_front() {
+    Some(oldest) => {
+      resolve(oldest.reply, outcome);
+      oldest.command.filter(|_| outcome == Outcome::Acknowledged)
+    }
+    None => {
+      log::debug!("received {outcome:?} reply without a pending command");
+      None
+    }
+  }
+}
+
+/// The answer to `Command::Identify`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Identity {
+  /// The version of the firmware running on the light controller.
+  pub firmware_version: String,
+
+  /// The version of the framed protocol spoken by the firmware.
+  pub protocol_version: u8,
+
+  /// The largest number of leds the firmware was built to drive.
+  pub max_led_count: u16,
+
+  /// The number of leds currently lit.
+  pub led_count: u16,
+}
+
+impl Identity {
+  /// Attempts to decode the payload of a `CommandId::Identity` frame.
+  fn from_payload(payload: &[u8]) -> Option<Self> {
+    match payload {
+      [protocol_version, max_low, max_high, low, high, version @ ..] => Some(Self {
+        firmware_version: String::from_utf8_lossy(version).into_owned(),
+        protocol_version: *protocol_version,
+        max_led_count: u16::from_le_bytes([*max_low, *max_high]),
+        led_count: u16::from_le_bytes([*low, *high]),
+      }),
+      _ => None,
+    }
+  }
+}
+
+/// The answer to `Command::Status`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FirmwareStatus {
+  /// The current color or effect, in the same form as the text protocol (e.g `breathe=#ff0000`).
+  pub state: Option<String>,
+
+  /// The current brightness.
+  pub brightness: u8,
+
+  /// How long the firmware has been running.
+  pub uptime_ms: u64,
+
+  /// The last thing that went wrong on the firmware, if anything has.
+  pub last_error: Option<String>,
+}
+
+impl FirmwareStatus {
+  /// Attempts to decode the payload of a `CommandId::StatusReport` frame.
+  fn from_payload(payload: &[u8]) -> Option<Self> {
+    match payload {
+      [brightness, a, b, c, d, e, f, g, h, error, id, state @ ..] => Some(Self {
+        state: milton_protocol::CommandId::from_byte(*id)
+          .and_then(|id| Command::from_payload(id, state))
+          .map(|command| command.to_string()),
+        brightness: *brightness,
+        uptime_ms: u64::from_le_bytes([*a, *b, *c, *d, *e, *f, *g, *h]),
+        last_error: milton_protocol::ErrorCode::from_byte(*error).map(|error| error.to_string()),
+      }),
+      _ => None,
+    }
+  }
+}
+
+/// Everything we know about the light controller; updated by the runtime in `run` and exposed by
+/// the `GET /lights/status` route.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ControllerStatus {
+  /// Whether or not we currently have a serial connection to the light controller.
+  pub connected: bool,
+
+  /// What the light controller said about itself when we last connected.
+  pub identity: Option<Identity>,
+
+  /// The most recent status report from the light controller.
+  pub firmware: Option<FirmwareStatus>,
+
+  /// The reason the last connection attempt was refused, if it was.
+  pub error: Option<String>,
+
+  /// How the lights should currently look; exposed on its own by the `GET /lights/state` route.
+  #[serde(skip)]
+  pub state: LightState,
+
+  /// The zones configured for the light controller, by name.
+  #[serde(skip)]
+  pub zones: std::collections::HashMap<String, Zone>,
+
+  /// The led count configured for the light controller, which is sent after the handshake and so
+  /// is not reflected in `identity`.
+  #[serde(skip)]
+  pub led_count: Option<u16>,
+
+  /// When any of the above last changed.
+  pub updated: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// The controller status is shared between the light runtime and the web server.
+pub type SharedStatus = async_std::sync::Arc<async_std::sync::RwLock<ControllerStatus>>;
+
+/// The status of every light controller, by name.
+pub type SharedStatuses = std::collections::BTreeMap<String, SharedStatus>;
+
+/// Applies a change to the shared controller status, bumping its timestamp.
+async fn update_status<F>(status: &SharedStatus, change: F)
+where
+  F: FnOnce(&mut ControllerStatus),
+{
+  let mut status = status.write().await;
+  change(&mut status);
+  status.updated = Some(chrono::Utc::now());
+}
+
+/// An open link to a light controller. The transport is read from and written to on dedicated
+/// threads, so the light runtime only wakes up when bytes arrive or it has something to send.
+struct Connection {
+  /// Bytes waiting to be written by the writing thread.
+  outbound: std::sync::mpsc::Sender<Vec<u8>>,
+
+  /// Bytes read by the reading thread, along with the error that ended either thread.
+  inbound: channel::Receiver<Result<Vec<u8>>>,
+
+  /// Cleared when the connection is dropped, letting the reading thread know to stop.
+  open: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl Connection {
+  /// Starts the threads that read from and write to the transport. The writing thread stops once
+  /// the connection is dropped; the reading thread notices within `READ_TIMEOUT`.
+  fn spawn(transport: Box<dyn crate::transport::Transport>) -> Result<Self> {
+    let mut reader = transport.try_clone()?;
+    let mut writer = transport;
+    let (outbound, queued) = std::sync::mpsc::channel::<Vec<u8>>();
+    let (received, inbound) = channel::unbounded();
+    let open = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
+
+    let failed = received.clone();
+    std::thread::spawn(move || {
+      for bytes in queued {
+        if let Err(error) = writer.write_all(&bytes) {
+          if failed.send_blocking(Err(error)).is_err() {
+            log::debug!("light controller write failed after its connection was dropped");
+          }
+          break;
+        }
+      }
+    });
+
+    let reading = open.clone();
+    std::thread::spawn(move || {
+      let mut chunk = [0; milton_protocol::MAX_FRAME_LEN];
+
+      while reading.load(std::sync::atomic::Ordering::Relaxed) {
+        let result = match reader.read(&mut chunk) {
+          Ok(0) => continue,
+          Ok(size) => Ok(chunk[..size].to_vec()),
+          Err(error) => Err(error),
+        };
+        let failed = result.is_err();
+
+        if received.send_blocking(result).is_err() || failed {
+          break;
+        }
+      }
+    });
+
+    Ok(Self {
+      outbound,
+      inbound,
+      open,
+    })
+  }
+
+  /// Queues the bytes to be written to the light controller.
+  fn send(&self, bytes: Vec<u8>) -> Result<()> {
+    self
+      .outbound
+      .send(bytes)
+      .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "light controller connection closed"))
+  }
+
+  /// Waits for the next bytes read from the light controller. An error means the connection has
+  /// been lost.
+  async fn receive(&self) -> Result<Vec<u8>> {
+    self
+      .inbound
+      .recv()
+      .await
+      .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "light controller connection closed"))?
+  }
+}
+
+impl Drop for Connection {
+  fn drop(&mut self) {
+    self.open.store(false, std::sync::atomic::Ordering::Relaxed);
+  }
+}
+
+/// Sends an identify request over a freshly opened connection and waits for the answer. An error
+/// is returned if the firmware speaks a version of the protocol we do not; firmware that does not
+/// answer in time is allowed through, since it may simply predate the identify command.
+async fn handshake(connection: &Connection) -> Result<Option<Identity>> {
+  connection.send(Command::Identify.encode(Protocol::Framed)?)?;
+
+  let deadline = std::time::Instant::now() + HANDSHAKE_TIMEOUT;
+  let mut buffer = Vec::new();
 
   loop {
-    connection = match (connection, last_configuration.as_ref(), force_reconnect) {
-      (None, Some(configuration), _) | (_, Some(configuration), true) => {
-        if std::time::Instant::now()
-          .duration_since(last_connection_attempt)
-          .as_secs()
-          > 5
-        {
-          log::info!("attempting to establish serial connection to light controller: {configuration:?}");
-          last_connection_attempt = std::time::Instant::now();
-
-          let connection = serialport::new(&configuration.device, configuration.baud)
-            .open()
-            .map_err(|error| {
-              log::warn!("unable to connect - {error}");
-              error
-            })
-            .ok();
-
-          if connection.is_some() {
-            log::info!("serial connection to light controller suceeded");
+    let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+
+    match async_std::future::timeout(remaining, connection.receive()).await {
+      Err(_) => return Ok(None),
+      Ok(chunk) => buffer.extend_from_slice(&chunk?),
+    }
+
+    loop {
+      match milton_protocol::Frame::decode(&buffer) {
+        Err(milton_protocol::DecodeError::Incomplete) => break,
+        Err(milton_protocol::DecodeError::UnsupportedVersion(version)) => {
+          return Err(io::Error::other(format!(
+            "light controller speaks protocol version {version}, expected {}",
+            milton_protocol::VERSION
+          )));
+        }
+        Err(milton_protocol::DecodeError::MissingSync) => {
+          let size = buffer
+            .iter()
+            .skip(1)
+            .position(|byte| *byte == milton_protocol::SYNC)
+            .map(|position| position + 1)
+            .unwrap_or(buffer.len());
+          log::debug!("skipping bytes - {:?}", String::from_utf8_lossy(&buffer[..size]));
+          buffer.drain(..size);
+        }
+        Err(error) => {
+          log::warn!("unable to decode handshake reply - {error}");
+          buffer.clear();
+          break;
+        }
+        Ok((frame, size)) => {
+          let (command, payload) = (frame.command, frame.payload.to_vec());
+          buffer.drain(..size);
+
+          match command {
+            milton_protocol::CommandId::Identity => {
+              let identity = Identity::from_payload(&payload)
+                .ok_or_else(|| io::Error::other("malformed identity reply from light controller"))?;
+
+              if identity.protocol_version != milton_protocol::VERSION {
+                return Err(io::Error::other(format!(
+                  "light controller speaks protocol version {}, expected {}",
+                  identity.protocol_version,
+                  milton_protocol::VERSION
+                )));
+              }
+
+              return Ok(Some(identity));
+            }
+            milton_protocol::CommandId::Nack => return Ok(None),
+            other => log::debug!("ignoring {other:?} frame during handshake"),
+          }
+        }
+      }
+    }
+  }
+}
+
+/// The light controller will reply to every message with either a frame or, for legacy text
+/// messages, a line of text. This helper logs whatever has been received so far, resolving pending
+/// commands and keeping track of any identity or status reports in the shared status. Replies can
+/// be split across reads, so only whole frames and lines are removed from the buffer; the rest is
+/// left for the next read to complete. Acknowledged commands are applied to the light state;
+/// returns true if that changed it.
+async fn handle_replies(
+  buffer: &mut Vec<u8>,
+  status: &SharedStatus,
+  pending: &mut std::collections::VecDeque<Pending>,
+) -> bool {
+  let mut acknowledged = Vec::new();
+
+  loop {
+    match milton_protocol::Frame::decode(buffer) {
+      Ok((frame, size)) => {
+        log::debug!("received {:?} frame from light controller", frame.command);
+
+        match frame.command {
+          milton_protocol::CommandId::Identity => {
+            let identity = Identity::from_payload(frame.payload);
+            update_status(status, |status| status.identity = identity).await;
+          }
+          milton_protocol::CommandId::StatusReport => {
+            if pending.front().map(|oldest| oldest.status).unwrap_or(false) {
+              pending.pop_front();
+            }
+
+            let firmware = FirmwareStatus::from_payload(frame.payload);
+            update_status(status, |status| status.firmware = firmware).await;
           }
+          milton_protocol::CommandId::Ack => acknowledged.extend(resolve_oldest(pending, Outcome::Acknowledged)),
+          milton_protocol::CommandId::Nack => acknowledged.extend(resolve_oldest(pending, Outcome::Rejected)),
+          _ => (),
+        }
+
+        buffer.drain(..size);
+      }
+      Err(milton_protocol::DecodeError::Incomplete) => break,
+      Err(milton_protocol::DecodeError::MissingSync) => {
+        // Text runs until the next frame; without one, only lines that have been terminated are
+        // complete.
+        let size = match buffer.iter().position(|byte| *byte == milton_protocol::SYNC) {
+          Some(position) => position,
+          None => match buffer.iter().rposition(|byte| *byte == b'\n') {
+            Some(position) => position + 1,
+            None if buffer.len() > milton_protocol::MAX_FRAME_LEN => buffer.len(),
+            None => break,
+          },
+        };
+        let text = String::from_utf8_lossy(&buffer[..size]).into_owned();
+        log::debug!("read bytes - {text:?}");
 
-          connection
-        } else {
-          None
+        for line in text.lines() {
+          match line.trim() {
+            "ok" => acknowledged.extend(resolve_oldest(pending, Outcome::Acknowledged)),
+            "failed" => acknowledged.extend(resolve_oldest(pending, Outcome::Rejected)),
+            _ => (),
+          }
         }
+
+        buffer.drain(..size);
       }
-      (Some(con), _, _) => Some(con),
-      (None, None, _) => None,
+      Err(error) => {
+        // Skip the sync byte, so that decoding picks up again at whatever comes next.
+        log::warn!("unable to decode reply from light controller - {error}");
+        buffer.drain(..1);
+      }
+    }
+  }
+
+  if acknowledged.is_empty() {
+    return false;
+  }
+
+  let mut changed = false;
+  update_status(status, |status| {
+    let before = status.state.clone();
+    acknowledged.iter().for_each(|command| status.state.apply(command));
+    changed = status.state != before;
+  })
+  .await;
+
+  changed
+}
+
+impl LightConfiguration {
+  /// Returns the path of the serial device that the light controller is attached to.
+  fn device_path(&self) -> Result<String> {
+    match (&self.usb, &self.device) {
+      (Some(selector), _) => crate::devices::find(selector),
+      (None, Some(device)) => Ok(device.clone()),
+      (None, None) => Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("light controller '{}' has neither a device nor usb selector", self.name),
+      )),
+    }
+  }
+
+  /// Opens the transport to the light controller; called for every connection attempt.
+  fn open(&self) -> Result<Box<dyn crate::transport::Transport>> {
+    use crate::transport::{Memory, Serial, Tcp, TransportKind};
+
+    let address = || {
+      self.address.as_deref().ok_or_else(|| {
+        io::Error::new(
+          io::ErrorKind::NotFound,
+          format!("light controller '{}' has no address", self.name),
+        )
+      })
     };
 
-    if force_reconnect {
-      force_reconnect = false;
+    match self.transport {
+      TransportKind::Serial => {
+        let path = self.device_path()?;
+        log::debug!("opening serial device '{path}' for light controller '{}'", self.name);
+        Ok(Box::new(Serial::open(&path, self.baud)?))
+      }
+      TransportKind::Tcp => Ok(Box::new(Tcp::open(address()?)?)),
+      TransportKind::Memory => Ok(Box::new(Memory::connect(address()?)?)),
+    }
+  }
+}
+
+/// Opens the connection described by the configuration. When using the framed protocol, the
+/// light controller is asked to identify itself before the connection is used, and connections to
+/// firmware speaking an incompatible protocol version are refused.
+async fn connect(configuration: &LightConfiguration, status: &SharedStatus) -> Result<Connection> {
+  let connection = match configuration.open().and_then(Connection::spawn) {
+    Ok(connection) => connection,
+    Err(error) => {
+      let message = error.to_string();
+      update_status(status, |status| status.error = Some(message)).await;
+      return Err(error);
     }
+  };
 
-    let bytes_to_send = match next(&mut receiver)? {
-      Some(command @ Command::Off) | Some(command @ Command::On) | Some(command @ Command::BasicColor(_)) => {
-        Some(format!("{command}"))
+  if configuration.protocol == Protocol::Text {
+    log::warn!("skipping light controller handshake; the text protocol does not support it");
+    update_status(status, |status| {
+      status.identity = None;
+      status.error = None;
+    })
+    .await;
+    return Ok(connection);
+  }
+
+  let identity = handshake(&connection).await;
+
+  match identity {
+    Ok(Some(ref identity)) if configuration.led_count > Some(identity.max_led_count) => log::warn!(
+      "configured led count {:?} exceeds the firmware maximum of {}; it will be clamped",
+      configuration.led_count,
+      identity.max_led_count
+    ),
+    Ok(Some(ref identity)) => log::info!("light controller identified itself - {identity:?}"),
+    Ok(None) => log::warn!("light controller did not identify itself, continuing anyway"),
+    Err(ref error) => log::error!("refusing light controller connection - {error}"),
+  }
+
+  let refused = identity.as_ref().err().map(|error| error.to_string());
+  update_status(status, |status| {
+    status.identity = identity.as_ref().ok().cloned().flatten();
+    status.error = refused;
+  })
+  .await;
+
+  identity.map(|_| connection)
+}
+
+/// Whatever woke the light runtime up.
+enum Event {
+  /// A request arrived on the channel; `None` once every sender has been dropped.
+  Request(Option<Request>),
+
+  /// Bytes arrived from the light controller, or the connection to it was lost.
+  Received(Result<Vec<u8>>),
+
+  /// A reconnect attempt, reply deadline or status request is due.
+  Due,
+}
+
+/// The main light runtime; receives commands on the channel and writes them to the light
+/// controller, keeping the shared status up to date along the way. The runtime sleeps until a
+/// request arrives, the light controller replies, or one of its timers is due.
+///
+/// The light state in the shared status is restored after every (re)connect, and sent on `states`
+/// (along with the name of the light controller) whenever it changes, so that it can be persisted.
+pub async fn run(
+  receiver: channel::Receiver<Request>,
+  status: SharedStatus,
+  states: channel::Sender<(String, LightState)>,
+) -> Result<()> {
+  log::debug!("starting light effect manager runtime");
+
+  let mut configuration: Option<LightConfiguration> = None;
+  let mut connection: Option<Connection> = None;
+  let mut connected = false;
+  let mut pending = std::collections::VecDeque::<Pending>::new();
+  let mut waiting = std::collections::VecDeque::<Waiting>::new();
+
+  // Commands are written one at a time, no sooner than the configured rate allows.
+  let mut next_write = std::time::Instant::now();
+
+  // Bytes read from the light controller that do not yet make up a whole reply.
+  let mut inbound = Vec::new();
+
+  // While disconnected, when to next try to connect; the delay doubles after every failed attempt
+  // and is reset once an attempt succeeds.
+  let mut next_attempt = std::time::Instant::now();
+  let mut reconnect_delay = MIN_RECONNECT_DELAY;
+  let mut next_status = std::time::Instant::now() + STATUS_INTERVAL;
+
+  loop {
+    let now = std::time::Instant::now();
+
+    if let (None, Some(config), true) = (&connection, &configuration, next_attempt <= now) {
+      log::info!("attempting to establish connection to light controller: {config:?}");
+
+      match connect(config, &status).await {
+        Ok(opened) => {
+          log::info!("connection to light controller suceeded");
+          reconnect_delay = MIN_RECONNECT_DELAY;
+          next_status = std::time::Instant::now() + STATUS_INTERVAL;
+          inbound.clear();
+
+          // The led count is sent like any other command, so that its reply is not mistaken for
+          // the reply to whatever is sent next.
+          if let Some(count) = config.led_count {
+            match Command::LedCount(count)
+              .encode(config.protocol)
+              .and_then(|bytes| opened.send(bytes))
+            {
+              Ok(()) => pending.push_back(Pending {
+                status: false,
+                command: None,
+                reply: None,
+                deadline: std::time::Instant::now() + ACK_TIMEOUT,
+              }),
+              Err(error) => log::warn!("unable to send led count to light controller - {error}"),
+            }
+          }
+
+          // Bring the lights back to the way they were left; anything requested in the meantime
+          // gets a chance to supersede these.
+          for command in status.read().await.state.commands() {
+            match command.encode(config.protocol) {
+              Ok(bytes) => waiting.push_back(Waiting {
+                command,
+                bytes,
+                reply: None,
+                requester: None,
+                restoring: true,
+              }),
+              Err(error) => log::warn!("unable to restore '{command}' - {error}"),
+            }
+          }
+
+          connection = Some(opened);
+        }
+        Err(error) => {
+          log::warn!("unable to connect - {error}; retrying in {reconnect_delay:?}");
+          next_attempt = std::time::Instant::now() + reconnect_delay;
+          reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+        }
       }
-      Some(Command::Configure(config)) => {
-        log::info!("received updated light-controller serial configuration to apply");
-        last_configuration = Some(config);
-        continue;
+    }
+
+    if connected != connection.is_some() {
+      connected = connection.is_some();
+      update_status(&status, |status| status.connected = connected).await;
+    }
+
+    // Nothing written to a connection we no longer have will be replied to, and nothing waiting
+    // will be written.
+    if connection.is_none() {
+      for written in pending.drain(..) {
+        resolve(written.reply, Outcome::Disconnected);
+      }
+
+      for unwritten in waiting.drain(..) {
+        resolve(unwritten.reply, Outcome::Disconnected);
+      }
+    }
+
+    // Replies are matched to commands by their order alone, so once one goes missing a late reply
+    // would be credited to whatever was written next. Rather than keep writing, the connection is
+    // re-established; the handshake skips over anything still in flight.
+    let expired = pending
+      .front()
+      .map(|oldest| oldest.deadline <= std::time::Instant::now())
+      .unwrap_or(false);
+
+    if expired {
+      log::warn!("light controller did not reply within {ACK_TIMEOUT:?}, reconnecting");
+
+      for waiting in pending.drain(..) {
+        resolve(waiting.reply, Outcome::Unanswered);
+      }
+
+      // Give the reading thread a chance to let go of the connection.
+      connection = None;
+      next_attempt = std::time::Instant::now() + crate::transport::READ_TIMEOUT;
+      continue;
+    }
+
+    // Periodically ask the light controller for its status, ahead of any waiting commands; like
+    // them, the request waits for the previous one to be replied to. The reply is handled along
+    // with the rest in `handle_replies`.
+    let framed = configuration.as_ref().map(|config| config.protocol) == Some(Protocol::Framed);
+
+    if let (Some(open), true, true) = (&connection, framed, pending.is_empty()) {
+      if next_status <= std::time::Instant::now() {
+        next_status = std::time::Instant::now() + STATUS_INTERVAL;
+
+        match Command::Status
+          .encode(Protocol::Framed)
+          .and_then(|bytes| open.send(bytes))
+        {
+          Ok(()) => pending.push_back(Pending {
+            status: true,
+            command: None,
+            reply: None,
+            deadline: std::time::Instant::now() + ACK_TIMEOUT,
+          }),
+          Err(error) => log::warn!("unable to request light controller status - {error}"),
+        }
+      }
+    }
+
+    // Write the next waiting command once the previous one has been replied to and the rate limit
+    // allows it; anything that arrives in the meantime gets a chance to supersede it.
+    if let (Some(open), Some(config), true) = (&connection, &configuration, pending.is_empty()) {
+      if next_write <= std::time::Instant::now() {
+        if let Some(next) = waiting.pop_front() {
+          next_write = std::time::Instant::now() + std::time::Duration::from_secs(1) / config.max_rate.max(1);
+
+          match open.send(next.bytes) {
+            Ok(()) => pending.push_back(Pending {
+              status: false,
+              command: Some(next.command).filter(|_| !next.restoring),
+              reply: next.reply,
+              deadline: std::time::Instant::now() + ACK_TIMEOUT,
+            }),
+            Err(error) => {
+              log::warn!("unable to write message - {error}");
+              resolve(next.reply, Outcome::Disconnected);
+            }
+          }
+        }
+      }
+    }
+
+    // Work out when we need to wake up if nothing else happens first.
+    let wake = [
+      pending.front().map(|oldest| oldest.deadline),
+      waiting
+        .front()
+        .filter(|_| connection.is_some() && pending.is_empty())
+        .map(|_| next_write),
+      connection
+        .as_ref()
+        .filter(|_| framed && pending.is_empty())
+        .map(|_| next_status),
+      configuration
+        .as_ref()
+        .filter(|_| connection.is_none())
+        .map(|_| next_attempt),
+    ]
+    .into_iter()
+    .flatten()
+    .min();
+
+    let requested = async { Event::Request(receiver.recv().await.ok()) };
+    let received = async {
+      match &connection {
+        Some(open) => Event::Received(open.receive().await),
+        None => std::future::pending().await,
+      }
+    };
+    let due = async {
+      match wake {
+        Some(wake) => {
+          async_std::task::sleep(wake.saturating_duration_since(std::time::Instant::now())).await;
+          Event::Due
+        }
+        None => std::future::pending().await,
       }
-      None => None,
     };
 
-    if let Some(ref mut con) = &mut connection {
-      let mut buffer = Vec::new();
-      let has_bytes = con.bytes_to_read().map(|amount| amount > 0).unwrap_or_default();
+    let event = requested.race(received).race(due).await;
+
+    match event {
+      Event::Request(None) => return Err(io::Error::other("message channel has been closed")),
+      Event::Request(Some(Request {
+        command: Command::Configure(config),
+        reply,
+        ..
+      })) => {
+        log::info!("received updated light-controller configuration to apply");
+        update_status(&status, |status| {
+          status.zones = config.zones.clone();
+          status.led_count = config.led_count;
+        })
+        .await;
+        configuration = Some(config);
+        reconnect_delay = MIN_RECONNECT_DELAY;
+
+        // Give the reading thread of any connection being replaced a chance to let go of it.
+        next_attempt = match connection.take() {
+          Some(_) => std::time::Instant::now() + crate::transport::READ_TIMEOUT,
+          None => std::time::Instant::now(),
+        };
+
+        resolve(reply, Outcome::Acknowledged);
+      }
+      Event::Request(Some(Request {
+        command,
+        reply,
+        requester,
+      })) => match (&connection, &configuration) {
+        (Some(_), Some(config)) => match command
+          .resolve(&config.zones)
+          .and_then(|command| command.encode(config.protocol).map(|bytes| (command, bytes)))
+        {
+          Ok((command, bytes)) => {
+            let (superseded, kept) = waiting
+              .drain(..)
+              .partition::<Vec<Waiting>, _>(|earlier| command.supersedes(&earlier.command));
+            waiting.extend(kept);
+
+            for earlier in superseded {
+              log::debug!("'{}' superseded by '{command}'", earlier.command);
+              resolve(earlier.reply, Outcome::Superseded);
+            }
+
+            let queued = waiting
+              .iter()
+              .filter(|earlier| !earlier.restoring && earlier.requester == requester)
+              .count();
 
-      if has_bytes {
-        let read_result = con.read_to_end(&mut buffer);
+            if queued >= config.max_rate.max(1) as usize {
+              log::warn!("refusing '{command}' from {requester:?}, {queued} of their commands already waiting");
+              resolve(reply, Outcome::RateLimited);
+            } else {
+              waiting.push_back(Waiting {
+                command,
+                bytes,
+                reply,
+                requester,
+                restoring: false,
+              });
+            }
+          }
+          Err(error) => {
+            log::warn!("unable to encode light command - {error}");
+            resolve(reply, Outcome::Invalid);
+          }
+        },
+        _ => {
+          log::warn!("dropping light command, no connection to light controller");
+          resolve(reply, Outcome::Disconnected);
+        }
+      },
+      Event::Received(Ok(bytes)) => {
+        inbound.extend_from_slice(&bytes);
+
+        if let (true, Some(config)) = (
+          handle_replies(&mut inbound, &status, &mut pending).await,
+          &configuration,
+        ) {
+          let state = status.read().await.state.clone();
 
-        if let Err(error) = read_result {
-          if error.kind() != std::io::ErrorKind::TimedOut {
-            log::warn!("failed - {error}");
-            break;
-          } else {
-            log::warn!("timeout after buffer read - {error} ({} bytes)", buffer.len());
+          if let Err(error) = states.send((config.name.clone(), state)).await {
+            log::warn!("unable to publish light state change - {error}");
           }
         }
+      }
+      Event::Received(Err(error)) => {
+        log::warn!("lost connection to light controller - {error}; reconnecting in {reconnect_delay:?}");
+        connection = None;
+        next_attempt = std::time::Instant::now() + reconnect_delay;
+      }
+      Event::Due => (),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{
+    handle_replies, run, BasicColor, Command, Effect, LightConfiguration, LightState, Outcome, Pending, Request,
+    SharedStatus, Zone, MAX_PAINT_COMMANDS,
+  };
+  use crate::transport::{Memory, Transport};
+  use async_std::channel;
+  use async_std::prelude::FutureExt;
+  use milton_protocol::CommandId;
+
+  /// Encodes a reply frame, as the light controller would send it.
+  fn frame(command: CommandId, payload: &[u8]) -> Vec<u8> {
+    let mut buffer = vec![0; milton_protocol::MAX_FRAME_LEN];
+    let size = milton_protocol::Frame::new(command, payload)
+      .encode(&mut buffer)
+      .expect("frame encodes");
+    buffer.truncate(size);
+    buffer
+  }
+
+  /// Returns the payload of a status report for the brightness.
+  fn status_report(brightness: u8) -> Vec<u8> {
+    let mut payload = vec![brightness];
+    payload.extend(1234u64.to_le_bytes());
+    payload.extend([0, CommandId::Off as u8]);
+    payload
+  }
+
+  /// Returns a pending command, along with the receiver its outcome is sent on.
+  fn pending(command: Command) -> (Pending, channel::Receiver<Outcome>) {
+    let (reply, outcome) = channel::bounded(1);
+    let pending = Pending {
+      status: false,
+      command: Some(command),
+      reply: Some(reply),
+      deadline: std::time::Instant::now() + super::ACK_TIMEOUT,
+    };
+    (pending, outcome)
+  }
+
+  /// Returns a pending status request.
+  fn status_request() -> Pending {
+    Pending {
+      status: true,
+      command: None,
+      reply: None,
+      deadline: std::time::Instant::now() + super::ACK_TIMEOUT,
+    }
+  }
+
+  /// Feeds the chunks to `handle_replies` one at a time, as though each arrived in its own read.
+  async fn feed(chunks: &[&[u8]], status: &SharedStatus, pending: &mut std::collections::VecDeque<Pending>) {
+    let mut inbound = Vec::new();
+
+    for chunk in chunks {
+      inbound.extend_from_slice(chunk);
+      handle_replies(&mut inbound, status, pending).await;
+    }
 
-        let contents = String::from_utf8(buffer);
-        log::debug!("read bytes - {contents:?}");
+    assert!(inbound.is_empty(), "unhandled bytes - {inbound:?}");
+  }
+
+  #[test]
+  fn frames_split_across_reads() {
+    async_std::task::block_on(async {
+      let status = SharedStatus::default();
+      let (first, outcome) = pending(Command::On);
+      let mut pending = std::collections::VecDeque::from([first]);
+      let ack = frame(CommandId::Ack, &[]);
+      let mut inbound = Vec::new();
+
+      for byte in &ack[..ack.len() - 1] {
+        inbound.push(*byte);
+        handle_replies(&mut inbound, &status, &mut pending).await;
+        assert_eq!(pending.len(), 1);
       }
 
-      if let Some(message) = bytes_to_send {
-        if let Err(error) = write!(con, "{message}") {
-          log::warn!("unable to write message - {error}");
-          force_reconnect = true;
+      inbound.push(ack[ack.len() - 1]);
+      handle_replies(&mut inbound, &status, &mut pending).await;
+      assert!(inbound.is_empty());
+      assert_eq!(outcome.try_recv(), Ok(Outcome::Acknowledged));
+    });
+  }
+
+  #[test]
+  fn text_split_across_reads() {
+    async_std::task::block_on(async {
+      let status = SharedStatus::default();
+      let (first, acknowledged) = pending(Command::On);
+      let (second, rejected) = pending(Command::Off);
+      let mut pending = std::collections::VecDeque::from([first, second]);
+
+      feed(&[b"o", b"k\r", b"\nfai", b"led\r\n"], &status, &mut pending).await;
+
+      assert_eq!(acknowledged.try_recv(), Ok(Outcome::Acknowledged));
+      assert_eq!(rejected.try_recv(), Ok(Outcome::Rejected));
+      assert!(pending.is_empty());
+    });
+  }
+
+  #[test]
+  fn status_reports_interleaved_with_replies() {
+    async_std::task::block_on(async {
+      let status = SharedStatus::default();
+      let (first, acknowledged) = pending(Command::On);
+      let (second, rejected) = pending(Command::Brightness(10));
+      let mut pending = std::collections::VecDeque::from([first, status_request(), second]);
+
+      let replies = [
+        frame(CommandId::Ack, &[]),
+        frame(CommandId::StatusReport, &status_report(42)),
+        frame(CommandId::Nack, &[]),
+      ]
+      .concat();
+      let chunks = replies.chunks(3).collect::<Vec<_>>();
+      feed(&chunks, &status, &mut pending).await;
+
+      assert_eq!(acknowledged.try_recv(), Ok(Outcome::Acknowledged));
+      assert_eq!(rejected.try_recv(), Ok(Outcome::Rejected));
+      assert!(pending.is_empty());
+
+      let status = status.read().await;
+      assert_eq!(status.firmware.as_ref().map(|firmware| firmware.brightness), Some(42));
+    });
+  }
+
+  #[test]
+  fn rejected_status_requests_keep_their_slot() {
+    async_std::task::block_on(async {
+      let status = SharedStatus::default();
+      let (command, outcome) = pending(Command::Off);
+      let mut pending = std::collections::VecDeque::from([status_request(), command]);
+
+      let replies = [frame(CommandId::Nack, &[]), frame(CommandId::Ack, &[])].concat();
+      feed(&[&replies[..4], &replies[4..9], &replies[9..]], &status, &mut pending).await;
+
+      assert_eq!(outcome.try_recv(), Ok(Outcome::Acknowledged));
+      assert!(pending.is_empty());
+    });
+  }
+
+  #[test]
+  fn corrupt_frames_are_skipped() {
+    async_std::task::block_on(async {
+      let status = SharedStatus::default();
+      let (command, outcome) = pending(Command::On);
+      let mut pending = std::collections::VecDeque::from([command]);
+
+      let mut corrupt = frame(CommandId::Nack, &[]);
+      corrupt[4] = CommandId::Ack as u8;
+      let replies = [corrupt, frame(CommandId::Ack, &[])].concat();
+      feed(&[&replies], &status, &mut pending).await;
+
+      assert_eq!(outcome.try_recv(), Ok(Outcome::Acknowledged));
+    });
+  }
+
+  #[test]
+  fn parses_inclusive_zones() {
+    assert_eq!(Zone::try_from("5..11".to_string()), Ok(Zone { first: 5, last: 11 }));
+    assert_eq!(Zone::try_from(" 3 .. 3 ".to_string()), Ok(Zone { first: 3, last: 3 }));
+    assert_eq!(
+      Zone::try_from("0..65534".to_string()),
+      Ok(Zone { first: 0, last: 65534 })
+    );
+  }
+
+  #[test]
+  fn refuses_invalid_zones() {
+    for input in ["11..5", "0..65535", "5", "5..", "..5", "a..b", "-1..4", "0..4..8"] {
+      assert!(Zone::try_from(input.to_string()).is_err(), "{input}");
+    }
+  }
+
+  #[test]
+  fn resolves_zones_into_exclusive_segments() {
+    let zones = std::collections::HashMap::from([("desk".to_string(), Zone { first: 5, last: 11 })]);
+    let red = Box::new(Command::BasicColor(BasicColor::Red));
+
+    assert_eq!(
+      Command::Zone("desk".to_string(), red.clone()).resolve(&zones).ok(),
+      Some(Command::Segment(5, 12, red.clone()))
+    );
+    assert_eq!(
+      Command::Transition(Box::new(Command::Zone("desk".to_string(), red.clone())), 500)
+        .resolve(&zones)
+        .ok(),
+      Some(Command::Transition(Box::new(Command::Segment(5, 12, red)), 500))
+    );
+    assert_eq!(Command::On.resolve(&zones).ok(), Some(Command::On));
+  }
+
+  #[test]
+  fn refuses_unknown_zones() {
+    let zones = std::collections::HashMap::from([("desk".to_string(), Zone { first: 0, last: 4 })]);
+    let error = Command::Zone("shelf".to_string(), Box::new(Command::On))
+      .resolve(&zones)
+      .expect_err("zone is unknown");
+
+    assert_eq!(error.to_string(), "unknown zone 'shelf'");
+
+    let error = Command::Zone(
+      "desk".to_string(),
+      Box::new(Command::Zone("desk".to_string(), Box::new(Command::On))),
+    )
+    .resolve(&zones)
+    .expect_err("zones do not nest");
+    assert!(error.to_string().contains("cannot be scoped to a zone"));
+  }
+
+  #[test]
+  fn light_state_round_trips_through_its_commands() {
+    let mut state = LightState::default();
+
+    for command in [
+      Command::Rgb(1, 2, 3),
+      Command::Brightness(10),
+      Command::Effect(Effect::Rainbow),
+      Command::Segment(0, 4, Box::new(Command::Rgb(4, 5, 6))),
+      Command::Segment(4, 8, Box::new(Command::Effect(Effect::Comet(7, 8, 9)))),
+      Command::Transition(Box::new(Command::Segment(0, 4, Box::new(Command::Off))), 500),
+      Command::Brightness(80),
+    ] {
+      state.apply(&command);
+    }
+
+    let commands = state.commands();
+    assert_eq!(
+      commands,
+      [
+        Command::Effect(Effect::Rainbow),
+        Command::Segment(4, 8, Box::new(Command::Effect(Effect::Comet(7, 8, 9)))),
+        Command::Segment(0, 4, Box::new(Command::Off)),
+        Command::Brightness(80),
+      ]
+    );
+
+    let mut restored = LightState::default();
+    commands.iter().for_each(|command| restored.apply(command));
+    assert_eq!(restored, state);
+    assert_eq!(restored.commands(), commands);
+  }
+
+  #[test]
+  fn normalized_light_states_are_trimmed() {
+    let paint = (0..MAX_PAINT_COMMANDS as u16 + 5)
+      .map(|index| Command::Fill(index, index + 1, 0, 0, 255))
+      .collect::<Vec<_>>();
+    let state = LightState {
+      color: Some(Command::Rgb(1, 2, 3)),
+      paint: paint.clone(),
+      zones: vec![
+        Command::Segment(0, 4, Box::new(Command::On)),
+        Command::Segment(0, 4, Box::new(Command::Off)),
+      ],
+      brightness: Some(50),
+    };
+
+    let normalized = state.normalized();
+    assert_eq!(normalized.color, state.color);
+    assert_eq!(normalized.paint, paint[5..]);
+    assert_eq!(normalized.zones, [Command::Segment(0, 4, Box::new(Command::Off))]);
+    assert_eq!(normalized.brightness, Some(50));
+    assert_eq!(normalized.normalized(), normalized);
+  }
+
+  #[test]
+  fn light_state_forgets_the_oldest_paint() {
+    let mut state = LightState::default();
+    state.apply(&Command::Off);
+
+    for index in 0..MAX_PAINT_COMMANDS as u16 + 5 {
+      state.apply(&Command::Fill(index, index + 1, 255, 0, 0));
+    }
+
+    let commands = state.commands();
+    assert_eq!(commands.len(), MAX_PAINT_COMMANDS + 1);
+    assert_eq!(commands.first(), Some(&Command::Off));
+    assert_eq!(commands.get(1), Some(&Command::Fill(5, 6, 255, 0, 0)));
+
+    let mut restored = LightState::default();
+    commands.iter().for_each(|command| restored.apply(command));
+    assert_eq!(restored, state);
+  }
+
+  /// Plays the part of a light controller on every link opened to the address, answering each frame
+  /// it is sent with the bytes returned by `answer`; the frames themselves are passed on to the
+  /// returned receiver. Stops once the receiver is dropped.
+  fn controller<F>(address: &str, answer: F) -> channel::Receiver<(CommandId, Vec<u8>)>
+  where
+    F: Fn(CommandId, &[u8]) -> Vec<u8> + Send + 'static,
+  {
+    let listener = Memory::listen(address).expect("address is free");
+    let (written, frames) = channel::unbounded();
+
+    std::thread::spawn(move || {
+      while !written.is_closed() {
+        let Ok(mut link) = listener.accept(crate::transport::READ_TIMEOUT) else {
+          continue;
+        };
+        let (mut inbound, mut chunk) = (Vec::new(), [0; 64]);
+
+        while let Ok(size) = link.read(&mut chunk) {
+          inbound.extend_from_slice(&chunk[..size]);
+
+          while let Ok((frame, size)) = milton_protocol::Frame::decode(&inbound) {
+            let reply = answer(frame.command, frame.payload);
+
+            if written.send_blocking((frame.command, frame.payload.to_vec())).is_err() {
+              return;
+            }
+
+            if link.write_all(&reply).is_err() {
+              break;
+            }
+
+            inbound.drain(..size);
+          }
         }
       }
+    });
+
+    frames
+  }
+
+  /// Answers the way up to date firmware does: identify requests with an identity, status requests
+  /// with a status report, and everything else with an ack.
+  fn firmware(command: CommandId, _: &[u8]) -> Vec<u8> {
+    match command {
+      CommandId::Identify => frame(CommandId::Identity, &[milton_protocol::VERSION, 60, 0, 30, 0, b'1']),
+      CommandId::Status => frame(CommandId::StatusReport, &status_report(100)),
+      _ => frame(CommandId::Ack, &[]),
+    }
+  }
+
+  /// Returns the configuration of a light controller reached over the in-memory link at the address.
+  fn configuration(address: &str) -> LightConfiguration {
+    toml::from_str(&format!(
+      "name = \"test\"\ntransport = \"memory\"\naddress = \"{address}\""
+    ))
+    .expect("valid configuration")
+  }
+
+  /// Starts a light runtime with the configuration, returning the channel that requests are sent on
+  /// along with its status.
+  async fn start(configuration: LightConfiguration) -> (channel::Sender<Request>, SharedStatus) {
+    let (sender, receiver) = channel::unbounded();
+    let (states, _) = channel::unbounded();
+    let status = SharedStatus::default();
+    async_std::task::spawn(run(receiver, status.clone(), states));
+
+    let (request, outcome) = Request::tracked(Command::Configure(configuration));
+    sender.send(request).await.expect("runtime running");
+    assert_eq!(outcome.recv().await, Ok(Outcome::Acknowledged));
+    (sender, status)
+  }
+
+  /// Waits up to two seconds for the status to satisfy the predicate.
+  async fn eventually<F>(status: &SharedStatus, predicate: F)
+  where
+    F: Fn(&super::ControllerStatus) -> bool,
+  {
+    for _ in 0..100 {
+      if predicate(&*status.read().await) {
+        return;
+      }
+
+      async_std::task::sleep(std::time::Duration::from_millis(20)).await;
     }
 
-    if std::time::Instant::now().duration_since(last_debug).as_secs() > 5 {
-      last_debug = std::time::Instant::now();
-      log::debug!("empty effect channel reads since last debug: {empty_reads}");
-      empty_reads = 0;
+    panic!("status never changed - {:?}", status.read().await);
+  }
+
+  /// Waits for the runtime to report being connected.
+  async fn connected(status: &SharedStatus) {
+    eventually(status, |status| status.connected).await;
+  }
+
+  /// Waits for the light state to settle on the color.
+  async fn color(status: &SharedStatus, color: Command) {
+    eventually(status, |status| status.state.color.as_ref() == Some(&color)).await;
+  }
+
+  /// Sends the command to the runtime, returning its outcome.
+  async fn send(sender: &channel::Sender<Request>, command: Command) -> channel::Receiver<Outcome> {
+    let (request, outcome) = Request::tracked(command);
+    sender.send(request).await.expect("runtime running");
+    outcome
+  }
+
+  #[test]
+  fn handshake_over_memory() {
+    async_std::task::block_on(async {
+      let frames = controller("handshake", firmware);
+      let (_sender, status) = start(configuration("handshake")).await;
+      connected(&status).await;
+
+      assert_eq!(frames.recv().await.map(|(command, _)| command), Ok(CommandId::Identify));
+      let identity = status.read().await.identity.clone().expect("identified");
+      assert_eq!(identity.firmware_version, "1");
+      assert_eq!((identity.max_led_count, identity.led_count), (60, 30));
+    });
+  }
+
+  #[test]
+  fn refuses_other_protocol_versions() {
+    async_std::task::block_on(async {
+      let _frames = controller("version", |command, payload| match command {
+        CommandId::Identify => frame(CommandId::Identity, &[milton_protocol::VERSION + 1, 60, 0, 30, 0]),
+        _ => firmware(command, payload),
+      });
+      let (_sender, status) = start(configuration("version")).await;
+
+      eventually(&status, |status| status.error.is_some()).await;
+
+      let status = status.read().await;
+      assert!(!status.connected);
+      assert!(status.error.as_deref().unwrap_or_default().contains("protocol version"));
+    });
+  }
+
+  #[test]
+  fn reconnects_over_memory() {
+    async_std::task::block_on(async {
+      let frames = controller("reconnect", firmware);
+      let (sender, status) = start(configuration("reconnect")).await;
+      connected(&status).await;
+
+      assert_eq!(frames.recv().await.map(|(command, _)| command), Ok(CommandId::Identify));
+
+      let (request, outcome) = Request::tracked(Command::Configure(configuration("reconnect")));
+      sender.send(request).await.expect("runtime running");
+      assert_eq!(outcome.recv().await, Ok(Outcome::Acknowledged));
+
+      let identified = frames.recv().timeout(std::time::Duration::from_secs(2)).await;
+      assert_eq!(
+        identified.map(|frame| frame.map(|(command, _)| command)),
+        Ok(Ok(CommandId::Identify))
+      );
+      connected(&status).await;
+      assert_eq!(send(&sender, Command::On).await.recv().await, Ok(Outcome::Acknowledged));
+    });
+  }
+
+  #[test]
+  fn acknowledged_commands_change_the_state() {
+    async_std::task::block_on(async {
+      let _frames = controller("ack", firmware);
+      let (sender, status) = start(configuration("ack")).await;
+      connected(&status).await;
+
+      assert_eq!(send(&sender, Command::On).await.recv().await, Ok(Outcome::Acknowledged));
+      color(&status, Command::On).await;
+    });
+  }
+
+  #[test]
+  fn rejected_commands_leave_the_state() {
+    async_std::task::block_on(async {
+      let _frames = controller("nack", |command, payload| match command {
+        CommandId::Off => frame(CommandId::Nack, &[]),
+        _ => firmware(command, payload),
+      });
+      let (sender, status) = start(configuration("nack")).await;
+      connected(&status).await;
+
+      assert_eq!(send(&sender, Command::On).await.recv().await, Ok(Outcome::Acknowledged));
+      color(&status, Command::On).await;
+      assert_eq!(send(&sender, Command::Off).await.recv().await, Ok(Outcome::Rejected));
+      assert_eq!(status.read().await.state.color, Some(Command::On));
+    });
+  }
+
+  /// Waits for the next frame written to the controller, skipping status requests.
+  async fn written(frames: &channel::Receiver<(CommandId, Vec<u8>)>) -> CommandId {
+    loop {
+      let next = frames.recv().timeout(std::time::Duration::from_secs(3)).await;
+
+      match next.expect("frame written in time").expect("controller running") {
+        (CommandId::Status, _) => continue,
+        (command, _) => return command,
+      }
     }
+  }
+
+  #[test]
+  fn unanswered_commands_time_out() {
+    async_std::task::block_on(async {
+      let frames = controller("timeout", |command, payload| match command {
+        CommandId::On => Vec::new(),
+        _ => firmware(command, payload),
+      });
+      let (sender, status) = start(configuration("timeout")).await;
+      connected(&status).await;
+
+      assert_eq!(send(&sender, Command::On).await.recv().await, Ok(Outcome::Unanswered));
+
+      // Without a reply, the link is no longer in step; the runtime reconnects to resync it.
+      for expected in [CommandId::Identify, CommandId::On, CommandId::Identify] {
+        assert_eq!(written(&frames).await, expected);
+      }
+
+      connected(&status).await;
+      assert_eq!(
+        send(&sender, Command::Off).await.recv().await,
+        Ok(Outcome::Acknowledged)
+      );
+    });
+  }
+
+  #[test]
+  fn late_replies_are_not_credited_to_the_next_command() {
+    async_std::task::block_on(async {
+      let frames = controller("late", |command, payload| match command {
+        CommandId::On => {
+          std::thread::sleep(super::ACK_TIMEOUT + std::time::Duration::from_millis(200));
+          frame(CommandId::Ack, &[])
+        }
+        CommandId::Off => frame(CommandId::Nack, &[]),
+        _ => firmware(command, payload),
+      });
+      let (sender, status) = start(configuration("late")).await;
+      connected(&status).await;
+
+      assert_eq!(send(&sender, Command::On).await.recv().await, Ok(Outcome::Unanswered));
+
+      for expected in [CommandId::Identify, CommandId::On, CommandId::Identify] {
+        assert_eq!(written(&frames).await, expected);
+      }
+
+      connected(&status).await;
+      assert_eq!(send(&sender, Command::Off).await.recv().await, Ok(Outcome::Rejected));
+      assert_eq!(written(&frames).await, CommandId::Off);
+    });
+  }
 
-    timer.next().await;
+  #[test]
+  fn waiting_commands_are_coalesced() {
+    async_std::task::block_on(async {
+      let frames = controller("coalesce", |command, payload| {
+        if command == CommandId::Rgb && payload == [1, 0, 0] {
+          std::thread::sleep(std::time::Duration::from_millis(200));
+        }
+
+        firmware(command, payload)
+      });
+      let (sender, status) = start(configuration("coalesce")).await;
+      connected(&status).await;
+
+      let first = send(&sender, Command::Rgb(1, 0, 0)).await;
+      async_std::task::sleep(std::time::Duration::from_millis(50)).await;
+      let second = send(&sender, Command::Rgb(2, 0, 0)).await;
+      let third = send(&sender, Command::Rgb(3, 0, 0)).await;
+
+      assert_eq!(first.recv().await, Ok(Outcome::Acknowledged));
+      assert_eq!(second.recv().await, Ok(Outcome::Superseded));
+      assert_eq!(third.recv().await, Ok(Outcome::Acknowledged));
+      color(&status, Command::Rgb(3, 0, 0)).await;
+
+      let written = std::iter::from_fn(|| frames.try_recv().ok())
+        .filter(|(command, _)| *command == CommandId::Rgb)
+        .map(|(_, payload)| payload)
+        .collect::<Vec<_>>();
+      assert_eq!(written, [vec![1, 0, 0], vec![3, 0, 0]]);
+    });
   }
 
-  Ok(())
+  #[test]
+  fn rate_limits_each_requester() {
+    async_std::task::block_on(async {
+      let _frames = controller("rate", |command, payload| {
+        if command == CommandId::Fill && payload.first() == Some(&1) {
+          std::thread::sleep(std::time::Duration::from_millis(200));
+        }
+
+        firmware(command, payload)
+      });
+      let (sender, status) = start(LightConfiguration {
+        max_rate: 2,
+        ..configuration("rate")
+      })
+      .await;
+      connected(&status).await;
+
+      /// Sends a fill of the single led at the index, which supersedes nothing, for the requester.
+      async fn fill(sender: &channel::Sender<Request>, index: u16, requester: &str) -> channel::Receiver<Outcome> {
+        let (request, outcome) = Request::tracked(Command::Fill(index, index + 1, 255, 0, 0));
+        sender
+          .send(request.requested_by(requester.to_string()))
+          .await
+          .expect("runtime running");
+        outcome
+      }
+
+      let written = fill(&sender, 1, "busy").await;
+      async_std::task::sleep(std::time::Duration::from_millis(50)).await;
+      let waiting = [fill(&sender, 2, "busy").await, fill(&sender, 3, "busy").await];
+      let refused = fill(&sender, 4, "busy").await;
+      let other = fill(&sender, 5, "quiet").await;
+
+      assert_eq!(refused.recv().await, Ok(Outcome::RateLimited));
+      assert_eq!(written.recv().await, Ok(Outcome::Acknowledged));
+
+      for outcome in waiting {
+        assert_eq!(outcome.recv().await, Ok(Outcome::Acknowledged));
+      }
+
+      assert_eq!(other.recv().await, Ok(Outcome::Acknowledged));
+    });
+  }
 }
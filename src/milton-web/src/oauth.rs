@@ -1,4 +1,4 @@
-use std::io::{Error, ErrorKind, Result};
+use std::io::{Error, Result};
 
 use serde::{Deserialize, Serialize};
 
@@ -88,12 +88,12 @@ impl AuthZeroConfig {
       .body_json(&self.auth_token_payload(code)?)
       .map_err(|error| {
         log::warn!("unable to serialize auth token payload - {}", error);
-        Error::new(ErrorKind::Other, "bad-token-serialize")
+        Error::other("bad-token-serialize")
       })?
       .await
       .map_err(|error| {
         log::warn!("unable to request token for code - {}", error);
-        Error::new(ErrorKind::Other, "bad-code-exchange-request")
+        Error::other("bad-code-exchange-request")
       })?;
 
     let tok = response
@@ -101,7 +101,7 @@ impl AuthZeroConfig {
       .await
       .map_err(|error| {
         log::warn!("unable to parse token exchange response - {}", error);
-        Error::new(ErrorKind::Other, "bad-code-exchange-request")
+        Error::other("bad-code-exchange-request")
       })
       .map(|body| body.access_token)?;
 
@@ -110,12 +110,12 @@ impl AuthZeroConfig {
       .await
       .map_err(|error| {
         log::warn!("unable to parse token exchange response - {}", error);
-        Error::new(ErrorKind::Other, "bad-code-exchange-request")
+        Error::other("bad-code-exchange-request")
       })?;
 
     res.body_json::<UserInfo>().await.map_err(|error| {
       log::warn!("unable to parse token exchange response - {}", error);
-      Error::new(ErrorKind::Other, "bad-code-exchange-request")
+      Error::other("bad-code-exchange-request")
     })
   }
 
@@ -130,11 +130,11 @@ impl AuthZeroConfig {
       .await
       .map_err(|error| {
         log::warn!("unable to parse user info response - {}", error);
-        Error::new(ErrorKind::Other, format!("{}", error))
+        Error::other(format!("{}", error))
       })?;
 
     if response.status() != surf::StatusCode::Ok {
-      return Err(Error::new(ErrorKind::Other, "not-ok-response"));
+      return Err(Error::other("not-ok-response"));
     }
 
     response
@@ -142,7 +142,7 @@ impl AuthZeroConfig {
       .await
       .map_err(|error| {
         log::warn!("unable to parse response - {}", error);
-        Error::new(ErrorKind::Other, format!("{}", error))
+        Error::other(format!("{}", error))
       })
   }
 
@@ -157,14 +157,14 @@ impl AuthZeroConfig {
       .await
       .map_err(|error| {
         log::warn!("unable to parse user info response - {}", error);
-        Error::new(ErrorKind::Other, format!("{}", error))
+        Error::other(format!("{}", error))
       })?;
 
     log::debug!("request for roles completed - {}", response.status());
 
     response.body_json::<Vec<UserRole>>().await.map_err(|error| {
       log::warn!("unable to parse user role response - {}", error);
-      Error::new(ErrorKind::Other, format!("{}", error))
+      Error::other(format!("{}", error))
     })
   }
 
@@ -174,16 +174,16 @@ impl AuthZeroConfig {
       .body_json(&self.manage_token_payload()?)
       .map_err(|error| {
         log::warn!("failed serializing management token payload - {}", error);
-        Error::new(ErrorKind::Other, "bad-management-payload")
+        Error::other("bad-management-payload")
       })?
       .await
       .map_err(|error| {
         log::warn!("failed management token response - {}", error);
-        Error::new(ErrorKind::Other, "bad-management-response")
+        Error::other("bad-management-response")
       })?;
 
     if response.status() != surf::StatusCode::Ok {
-      return Err(Error::new(ErrorKind::Other, "not-ok-response"));
+      return Err(Error::other("not-ok-response"));
     }
 
     response
@@ -191,7 +191,7 @@ impl AuthZeroConfig {
       .await
       .map_err(|error| {
         log::warn!("unable to parse response - {}", error);
-        Error::new(ErrorKind::Other, format!("{}", error))
+        Error::other(format!("{}", error))
       })
       .map(|b| b.access_token)
   }
@@ -238,7 +238,7 @@ impl AuthZeroConfig {
     )
     .map_err(|error| {
       log::warn!("unable to build redirect uri - {}", error);
-      Error::new(ErrorKind::Other, "bad-oauth-redirect-uri")
+      Error::other("bad-oauth-redirect-uri")
     })
     .map(|url| url.to_string())
   }
@@ -1,27 +1,456 @@
 #![allow(clippy::missing_docs_in_private_items)]
 
-//! These types represent the schema of misc. octoprint related json responses.
+//! These types represent the schema of misc. octoprint related json responses, along with the
+//! `OctoprintClient` used to fetch them.
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct OctoprintJobFile {
   pub name: Option<String>,
+  pub origin: Option<String>,
+  /// The size of the file, in bytes.
+  pub size: Option<u64>,
+  /// When the file was uploaded, as a unix timestamp.
+  pub date: Option<i64>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// How much filament a job needs, for a single tool.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OctoprintFilament {
+  /// In millimeters.
+  pub length: Option<f64>,
+  /// In cubic centimeters.
+  pub volume: Option<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct OctoprintJob {
   pub file: Option<OctoprintJobFile>,
+  /// In seconds.
+  pub estimated_print_time: Option<f64>,
+  /// In seconds.
+  pub average_print_time: Option<f64>,
+  /// In seconds.
+  pub last_print_time: Option<f64>,
+  /// Keyed by tool, e.g `tool0`.
+  pub filament: Option<std::collections::BTreeMap<String, OctoprintFilament>>,
+  /// The user that started the job.
+  pub user: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct OctoprintJobProgress {
   pub completion: Option<f64>,
+  /// How far into the file the printer is, in bytes.
+  pub filepos: Option<u64>,
+  /// In seconds.
+  pub print_time: Option<f64>,
+  /// In seconds.
+  pub print_time_left: Option<f64>,
+  /// How the time left was worked out, e.g `estimate`, `linear` or `analysis`.
+  pub print_time_left_origin: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct OctoprintJobResponse {
   pub job: Option<OctoprintJob>,
   pub progress: Option<OctoprintJobProgress>,
   pub state: Option<String>,
 }
+
+impl OctoprintJobResponse {
+  /// Returns when the job should finish, based on the time octoprint thinks is left; only known
+  /// while printing.
+  pub fn finishes_at(&self, now: chrono::DateTime<chrono::Utc>) -> Option<chrono::DateTime<chrono::Utc>> {
+    let left = self.progress.as_ref()?.print_time_left?;
+    let left = chrono::Duration::milliseconds((left.max(0.0) * 1000.0) as i64);
+    now.checked_add_signed(left)
+  }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OctoprintTemperature {
+  pub actual: Option<f64>,
+  pub target: Option<f64>,
+  pub offset: Option<f64>,
+}
+
+/// Temperatures are keyed by heater, e.g `tool0`, `tool1`, `bed` or `chamber`.
+pub type OctoprintTemperatures = std::collections::BTreeMap<String, OctoprintTemperature>;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OctoprintPrinterFlags {
+  pub operational: Option<bool>,
+  pub paused: Option<bool>,
+  pub printing: Option<bool>,
+  pub pausing: Option<bool>,
+  pub cancelling: Option<bool>,
+  pub sd_ready: Option<bool>,
+  pub error: Option<bool>,
+  pub ready: Option<bool>,
+  pub closed_or_error: Option<bool>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OctoprintPrinterState {
+  pub text: Option<String>,
+  pub flags: Option<OctoprintPrinterFlags>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OctoprintSdState {
+  pub ready: Option<bool>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OctoprintPrinterResponse {
+  pub temperature: Option<OctoprintTemperatures>,
+  pub sd: Option<OctoprintSdState>,
+  pub state: Option<OctoprintPrinterState>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OctoprintConnection {
+  pub state: Option<String>,
+  pub port: Option<String>,
+  pub baudrate: Option<u32>,
+  pub printer_profile: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OctoprintConnectionOptions {
+  pub ports: Option<Vec<String>>,
+  pub baudrates: Option<Vec<u32>>,
+  pub port_preference: Option<String>,
+  pub baudrate_preference: Option<u32>,
+  pub autoconnect: Option<bool>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OctoprintConnectionResponse {
+  pub current: Option<OctoprintConnection>,
+  pub options: Option<OctoprintConnectionOptions>,
+}
+
+/// The ways that talking to octoprint can fail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OctoprintError {
+  /// Octoprint could not be reached, or did not respond in time.
+  Unreachable(String),
+
+  /// Octoprint refused our api key.
+  Unauthorized,
+
+  /// Octoprint is not connected to the printer; printer related apis respond with a `409` until it
+  /// is.
+  NotConnected,
+
+  /// Octoprint responded with a status we did not expect.
+  BadStatus(u16),
+
+  /// Octoprint responded with something we could not parse.
+  BadPayload(String),
+}
+
+impl std::fmt::Display for OctoprintError {
+  fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Self::Unreachable(reason) => write!(formatter, "octoprint is unreachable - {reason}"),
+      Self::Unauthorized => write!(formatter, "octoprint refused the api key"),
+      Self::NotConnected => write!(formatter, "octoprint is not connected to the printer"),
+      Self::BadStatus(status) => write!(formatter, "bad octoprint response status - '{status}'"),
+      Self::BadPayload(reason) => write!(formatter, "invalid response from octoprint - {reason}"),
+    }
+  }
+}
+
+impl std::error::Error for OctoprintError {}
+
+/// A response from octoprint, along with when it was fetched.
+#[derive(Debug, Clone)]
+pub struct Snapshot<T> {
+  /// The response itself.
+  pub value: T,
+
+  /// When the response was fetched from octoprint.
+  pub updated: chrono::DateTime<chrono::Utc>,
+
+  /// Whether octoprint could not be reached the last time we tried, making this the last response
+  /// we know of rather than the current one.
+  pub stale: bool,
+}
+
+/// The outcome of the last attempt to fetch a resource, reused until it expires.
+#[derive(Debug)]
+struct Cached<T> {
+  /// When the attempt was made.
+  attempted: std::time::Instant,
+
+  /// What the attempt returned; while octoprint is unreachable, this is the last response we got
+  /// from it, marked as stale.
+  outcome: Result<Snapshot<T>, OctoprintError>,
+}
+
+/// The last outcome for a resource, shared by every clone of the client.
+type Cache<T> = std::sync::Arc<async_std::sync::Mutex<Option<Cached<T>>>>;
+
+/// Fetches resources from the octoprint api. Responses are reused for a while, so that many
+/// clients of our own api polling the printer share a single request to octoprint; when octoprint
+/// cannot be reached, the last response is returned instead, marked as stale.
+#[derive(Debug, Clone)]
+pub struct OctoprintClient {
+  /// The root of the octoprint api, e.g `http://192.168.2.27:5000`.
+  api_url: String,
+
+  /// The key sent with every request.
+  api_key: String,
+
+  /// How long to wait on a response before considering octoprint unreachable.
+  timeout: std::time::Duration,
+
+  /// How long a response (or failure) is reused for.
+  ttl: std::time::Duration,
+
+  /// The last outcome for `/api/job`.
+  job: Cache<OctoprintJobResponse>,
+
+  /// The last outcome for `/api/printer`.
+  printer: Cache<OctoprintPrinterResponse>,
+
+  /// The last outcome for `/api/connection`.
+  connection: Cache<OctoprintConnectionResponse>,
+}
+
+impl OctoprintClient {
+  /// Creates a client for the octoprint api rooted at `api_url`.
+  pub fn new(api_url: String, api_key: String, timeout: std::time::Duration, ttl: std::time::Duration) -> Self {
+    Self {
+      api_url,
+      api_key,
+      timeout,
+      ttl,
+      job: Default::default(),
+      printer: Default::default(),
+      connection: Default::default(),
+    }
+  }
+
+  /// Returns the current job.
+  pub async fn job(&self) -> Result<Snapshot<OctoprintJobResponse>, OctoprintError> {
+    cached(&self.job, self.ttl, self.get("/api/job")).await
+  }
+
+  /// Returns the state and temperatures of the printer.
+  pub async fn printer(&self) -> Result<Snapshot<OctoprintPrinterResponse>, OctoprintError> {
+    cached(&self.printer, self.ttl, self.get("/api/printer")).await
+  }
+
+  /// Returns the connection between octoprint and the printer.
+  pub async fn connection(&self) -> Result<Snapshot<OctoprintConnectionResponse>, OctoprintError> {
+    cached(&self.connection, self.ttl, self.get("/api/connection")).await
+  }
+
+  /// Fetches and parses a resource from the octoprint api, giving up after the timeout.
+  async fn get<T>(&self, path: &str) -> Result<T, OctoprintError>
+  where
+    T: serde::de::DeserializeOwned,
+  {
+    let request = async {
+      let mut res = surf::get(format!("{}{path}", self.api_url))
+        .header("X-Api-Key", &self.api_key)
+        .await
+        .map_err(|error| OctoprintError::Unreachable(error.to_string()))?;
+
+      check_status(res.status().into())?;
+
+      res
+        .body_json::<T>()
+        .await
+        .map_err(|error| OctoprintError::BadPayload(error.to_string()))
+    };
+
+    async_std::future::timeout(self.timeout, request)
+      .await
+      .map_err(|_| OctoprintError::Unreachable(format!("no response to '{path}' in {:?}", self.timeout)))?
+  }
+}
+
+/// Maps the status of a response from octoprint to the error it stands for, if any.
+fn check_status(status: u16) -> Result<(), OctoprintError> {
+  match status {
+    200 => Ok(()),
+    401 | 403 => Err(OctoprintError::Unauthorized),
+    409 => Err(OctoprintError::NotConnected),
+    status => Err(OctoprintError::BadStatus(status)),
+  }
+}
+
+/// Returns the last outcome in the cache if it is younger than the ttl, awaiting `fetch` otherwise.
+/// The cache stays locked while fetching, so that callers arriving in the meantime wait for (and
+/// share) the same request.
+async fn cached<T, F>(cache: &Cache<T>, ttl: std::time::Duration, fetch: F) -> Result<Snapshot<T>, OctoprintError>
+where
+  T: Clone,
+  F: std::future::Future<Output = Result<T, OctoprintError>>,
+{
+  let mut last = cache.lock().await;
+
+  if let Some(cached) = last.as_ref().filter(|cached| cached.attempted.elapsed() < ttl) {
+    return cached.outcome.clone();
+  }
+
+  let outcome = match (fetch.await, last.take()) {
+    (Ok(value), _) => Ok(Snapshot {
+      value,
+      updated: chrono::Utc::now(),
+      stale: false,
+    }),
+    (
+      Err(OctoprintError::Unreachable(reason)),
+      Some(Cached {
+        outcome: Ok(snapshot), ..
+      }),
+    ) => {
+      log::warn!(
+        "octoprint is unreachable ({reason}), using response from {}",
+        snapshot.updated
+      );
+      Ok(Snapshot {
+        stale: true,
+        ..snapshot
+      })
+    }
+    (Err(error), _) => Err(error),
+  };
+
+  *last = Some(Cached {
+    attempted: std::time::Instant::now(),
+    outcome: outcome.clone(),
+  });
+
+  outcome
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{cached, check_status, Cache, OctoprintError};
+
+  /// How long outcomes are reused for in these tests.
+  const TTL: std::time::Duration = std::time::Duration::from_millis(100);
+
+  /// Fetches through the cache, counting the fetches that were actually made.
+  async fn fetch(
+    cache: &Cache<u32>,
+    fetches: &std::cell::Cell<u32>,
+    outcome: Result<u32, OctoprintError>,
+  ) -> Result<super::Snapshot<u32>, OctoprintError> {
+    cached(cache, TTL, async {
+      fetches.set(fetches.get() + 1);
+      outcome
+    })
+    .await
+  }
+
+  #[test]
+  fn outcomes_are_reused_until_they_expire() {
+    async_std::task::block_on(async {
+      let (cache, fetches) = (Cache::default(), std::cell::Cell::new(0));
+
+      let first = fetch(&cache, &fetches, Ok(1)).await.expect("fetched");
+      let reused = fetch(&cache, &fetches, Ok(2)).await.expect("cached");
+      assert_eq!((first.value, reused.value, reused.updated), (1, 1, first.updated));
+      assert_eq!(fetches.get(), 1);
+
+      async_std::task::sleep(TTL).await;
+      let expired = fetch(&cache, &fetches, Ok(2)).await.expect("fetched");
+      assert_eq!((expired.value, expired.stale), (2, false));
+      assert_eq!(fetches.get(), 2);
+    });
+  }
+
+  #[test]
+  fn failures_are_reused_until_they_expire() {
+    async_std::task::block_on(async {
+      let (cache, fetches) = (Cache::default(), std::cell::Cell::new(0));
+
+      assert_eq!(
+        fetch(&cache, &fetches, Err(OctoprintError::Unauthorized)).await.err(),
+        Some(OctoprintError::Unauthorized)
+      );
+      assert_eq!(
+        fetch(&cache, &fetches, Ok(1)).await.err(),
+        Some(OctoprintError::Unauthorized)
+      );
+      assert_eq!(fetches.get(), 1);
+    });
+  }
+
+  #[test]
+  fn unreachable_octoprint_falls_back_to_stale_responses() {
+    async_std::task::block_on(async {
+      let (cache, fetches) = (Cache::default(), std::cell::Cell::new(0));
+      let unreachable = || Err(OctoprintError::Unreachable("timed out".to_string()));
+
+      let fresh = fetch(&cache, &fetches, Ok(1)).await.expect("fetched");
+      async_std::task::sleep(TTL).await;
+
+      let stale = fetch(&cache, &fetches, unreachable()).await.expect("stale response");
+      assert_eq!((stale.value, stale.updated, stale.stale), (1, fresh.updated, true));
+
+      // The stale response stands in for as long as octoprint stays unreachable.
+      async_std::task::sleep(TTL).await;
+      let still = fetch(&cache, &fetches, unreachable()).await.expect("stale response");
+      assert_eq!((still.value, still.updated, still.stale), (1, fresh.updated, true));
+
+      async_std::task::sleep(TTL).await;
+      let back = fetch(&cache, &fetches, Ok(2)).await.expect("fetched");
+      assert_eq!((back.value, back.stale), (2, false));
+      assert_eq!(fetches.get(), 4);
+    });
+  }
+
+  #[test]
+  fn only_unreachable_octoprint_falls_back() {
+    async_std::task::block_on(async {
+      let (cache, fetches) = (Cache::default(), std::cell::Cell::new(0));
+      fetch(&cache, &fetches, Ok(1)).await.expect("fetched");
+      async_std::task::sleep(TTL).await;
+
+      assert_eq!(
+        fetch(&cache, &fetches, Err(OctoprintError::NotConnected)).await.err(),
+        Some(OctoprintError::NotConnected)
+      );
+
+      // Without a response to fall back on, being unreachable is an error too.
+      async_std::task::sleep(TTL).await;
+      let unreachable = OctoprintError::Unreachable("refused".to_string());
+      assert_eq!(
+        fetch(&cache, &fetches, Err(unreachable.clone())).await.err(),
+        Some(unreachable)
+      );
+    });
+  }
+
+  #[test]
+  fn statuses_map_to_errors() {
+    let cases = [
+      (200, None),
+      (401, Some(OctoprintError::Unauthorized)),
+      (403, Some(OctoprintError::Unauthorized)),
+      (409, Some(OctoprintError::NotConnected)),
+      (404, Some(OctoprintError::BadStatus(404))),
+      (500, Some(OctoprintError::BadStatus(500))),
+      (204, Some(OctoprintError::BadStatus(204))),
+    ];
+
+    for (status, expected) in cases {
+      assert_eq!(check_status(status).err(), expected, "{status}");
+    }
+  }
+}
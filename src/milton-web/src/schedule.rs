@@ -0,0 +1,420 @@
+//! Time-based rules for the lights, written like `weekdays 07:30 scene=morning` or `23:00 off`. A
+//! rule is an optional set of days, a local time of day and an action, optionally followed by the
+//! name of the light controller it targets (e.g `22:00 brightness=40 target=dry-box`).
+//!
+//! Days are `daily` (the default), `weekdays`, `weekends` or a comma separated list of day names
+//! (e.g `mon,wed,fri`). Actions are `on`, `off`, `brightness=<0-255>`, `color=<color>` (any color
+//! accepted by the control api that does not contain spaces, e.g `#ff8800` or `darkorange`),
+//! `breathe=<color>`, `blink=<color>`, `comet=<color>`, `rainbow` and `scene=<name>`.
+
+use chrono::{Datelike, TimeZone};
+use serde::{Deserialize, Serialize};
+
+/// What a rule does when it fires; also used by the printer automation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Action {
+  /// Turns the lights on.
+  On,
+
+  /// Turns the lights off.
+  Off,
+
+  /// Sets the brightness of the lights.
+  Brightness(u8),
+
+  /// Sets the color of the lights.
+  Color(u8, u8, u8),
+
+  /// Starts an animated effect on the lights.
+  Effect(crate::lights::Effect),
+
+  /// Activates the saved scene with this name.
+  Scene(String),
+}
+
+impl std::fmt::Display for Action {
+  fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Self::On => write!(formatter, "on"),
+      Self::Off => write!(formatter, "off"),
+      Self::Brightness(level) => write!(formatter, "brightness={level}"),
+      Self::Color(red, green, blue) => write!(formatter, "color=#{red:02x}{green:02x}{blue:02x}"),
+      Self::Effect(effect) => write!(formatter, "{effect}"),
+      Self::Scene(name) => write!(formatter, "scene={name}"),
+    }
+  }
+}
+
+impl Action {
+  /// Parses a single action, e.g `scene=morning`.
+  fn parse(input: &str) -> Option<Self> {
+    use crate::lights::Effect;
+
+    match input.split_once('=') {
+      None if input == "on" => Some(Self::On),
+      None if input == "off" => Some(Self::Off),
+      None if input == "rainbow" => Some(Self::Effect(Effect::Rainbow)),
+      Some(("brightness", level)) => level.parse().ok().map(Self::Brightness),
+      Some(("color", color)) => crate::colors::parse(color).map(|(red, green, blue)| Self::Color(red, green, blue)),
+      Some(("breathe", color)) => {
+        crate::colors::parse(color).map(|(red, green, blue)| Self::Effect(Effect::Breathe(red, green, blue)))
+      }
+      Some(("blink", color)) => {
+        crate::colors::parse(color).map(|(red, green, blue)| Self::Effect(Effect::Blink(red, green, blue)))
+      }
+      Some(("comet", color)) => {
+        crate::colors::parse(color).map(|(red, green, blue)| Self::Effect(Effect::Comet(red, green, blue)))
+      }
+      Some(("scene", name)) if !name.is_empty() => Some(Self::Scene(name.to_string())),
+      _ => None,
+    }
+  }
+
+  /// Returns the light command for this action; scenes are made up of several commands, and are
+  /// looked up by whoever fires the rule instead.
+  pub fn command(&self) -> Option<crate::lights::Command> {
+    match self {
+      Self::On => Some(crate::lights::Command::On),
+      Self::Off => Some(crate::lights::Command::Off),
+      Self::Brightness(level) => Some(crate::lights::Command::Brightness(*level)),
+      Self::Color(red, green, blue) => Some(crate::lights::Command::Rgb(*red, *green, *blue)),
+      Self::Effect(effect) => Some(crate::lights::Command::Effect(effect.clone())),
+      Self::Scene(_) => None,
+    }
+  }
+}
+
+impl TryFrom<String> for Action {
+  type Error = String;
+
+  fn try_from(input: String) -> std::result::Result<Self, Self::Error> {
+    Self::parse(input.trim()).ok_or_else(|| format!("invalid light action '{input}'"))
+  }
+}
+
+impl From<Action> for String {
+  fn from(action: Action) -> Self {
+    action.to_string()
+  }
+}
+
+/// The short and long names of the days of the week, starting from monday.
+const DAY_NAMES: [(&str, &str); 7] = [
+  ("mon", "monday"),
+  ("tue", "tuesday"),
+  ("wed", "wednesday"),
+  ("thu", "thursday"),
+  ("fri", "friday"),
+  ("sat", "saturday"),
+  ("sun", "sunday"),
+];
+
+/// The days of the week a rule fires on, as a bit per day starting from monday.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Days(u8);
+
+impl Days {
+  /// Every day of the week.
+  const DAILY: Self = Self(0b111_1111);
+
+  /// Monday through friday.
+  const WEEKDAYS: Self = Self(0b001_1111);
+
+  /// Saturday and sunday.
+  const WEEKENDS: Self = Self(0b110_0000);
+
+  /// Returns true if the day is one of these days.
+  pub fn contains(&self, day: chrono::Weekday) -> bool {
+    self.0 & (1 << day.num_days_from_monday()) != 0
+  }
+
+  /// Parses `daily`, `weekdays`, `weekends` or a comma separated list of day names.
+  fn parse(input: &str) -> Option<Self> {
+    match input {
+      "daily" => return Some(Self::DAILY),
+      "weekdays" => return Some(Self::WEEKDAYS),
+      "weekends" => return Some(Self::WEEKENDS),
+      _ => (),
+    }
+
+    input.split(',').try_fold(Self(0), |days, name| {
+      let name = name.trim().to_lowercase();
+      let index = DAY_NAMES
+        .iter()
+        .position(|(short, long)| name == *short || name == *long)?;
+      Some(Self(days.0 | (1 << index)))
+    })
+  }
+}
+
+impl std::fmt::Display for Days {
+  fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match *self {
+      Self::DAILY => write!(formatter, "daily"),
+      Self::WEEKDAYS => write!(formatter, "weekdays"),
+      Self::WEEKENDS => write!(formatter, "weekends"),
+      Self(bits) => {
+        let names = DAY_NAMES
+          .iter()
+          .enumerate()
+          .filter(|(index, _)| bits & (1 << index) != 0)
+          .map(|(_, (short, _))| *short)
+          .collect::<Vec<&str>>();
+        write!(formatter, "{}", names.join(","))
+      }
+    }
+  }
+}
+
+/// A single time-based rule; see the module documentation for the syntax.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Rule {
+  /// The days of the week the rule fires on.
+  pub days: Days,
+
+  /// The local time of day the rule fires at.
+  pub time: chrono::NaiveTime,
+
+  /// What happens when the rule fires.
+  pub action: Action,
+
+  /// The name of the light controller the rule is for; the first one configured when absent.
+  pub target: Option<String>,
+}
+
+impl TryFrom<String> for Rule {
+  type Error = String;
+
+  fn try_from(input: String) -> std::result::Result<Self, Self::Error> {
+    let invalid = |reason: &str| format!("invalid schedule rule '{input}', {reason}");
+    let mut words = input.split_whitespace().peekable();
+
+    let days = match words.peek().and_then(|word| Days::parse(word)) {
+      Some(days) => {
+        words.next();
+        days
+      }
+      None => Days::DAILY,
+    };
+
+    let time = words
+      .next()
+      .and_then(|time| chrono::NaiveTime::parse_from_str(time, "%H:%M").ok())
+      .ok_or_else(|| invalid("expected a time like '07:30'"))?;
+
+    let action = words
+      .next()
+      .and_then(Action::parse)
+      .ok_or_else(|| invalid("expected an action like 'off' or 'scene=morning'"))?;
+
+    let target = match words.next().map(|word| word.split_once('=')) {
+      None => None,
+      Some(Some(("target", name))) if !name.is_empty() => Some(name.to_string()),
+      Some(_) => return Err(invalid("expected nothing but a 'target=<name>' after the action")),
+    };
+
+    if words.next().is_some() {
+      return Err(invalid("expected nothing after the target"));
+    }
+
+    Ok(Self {
+      days,
+      time,
+      action,
+      target,
+    })
+  }
+}
+
+impl std::str::FromStr for Rule {
+  type Err = String;
+
+  fn from_str(input: &str) -> std::result::Result<Self, Self::Err> {
+    Self::try_from(input.to_string())
+  }
+}
+
+impl From<Rule> for String {
+  fn from(rule: Rule) -> Self {
+    rule.to_string()
+  }
+}
+
+impl std::fmt::Display for Rule {
+  fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(formatter, "{} {} {}", self.days, self.time.format("%H:%M"), self.action)?;
+
+    if let Some(target) = &self.target {
+      write!(formatter, " target={target}")?;
+    }
+
+    Ok(())
+  }
+}
+
+impl Rule {
+  /// Returns the first time after `after` that this rule fires. Times that do not exist on a day
+  /// (skipped by a daylight saving change) are skipped, and times that occur twice fire the first
+  /// time.
+  pub fn next_after<Z>(&self, after: &chrono::DateTime<Z>) -> Option<chrono::DateTime<Z>>
+  where
+    Z: TimeZone,
+  {
+    let timezone = after.timezone();
+
+    (0..=7)
+      .filter_map(|offset| after.date_naive().checked_add_days(chrono::Days::new(offset)))
+      .filter(|date| self.days.contains(date.weekday()))
+      .filter_map(|date| timezone.from_local_datetime(&date.and_time(self.time)).earliest())
+      .find(|firing| firing > after)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Action, Days, Rule};
+  use chrono::{TimeZone, Weekday};
+
+  /// Returns the utc time on the day of january 2024; the first is a monday.
+  fn january(day: u32, hour: u32, minute: u32) -> chrono::DateTime<chrono::Utc> {
+    chrono::Utc
+      .with_ymd_and_hms(2024, 1, day, hour, minute, 0)
+      .single()
+      .expect("valid time")
+  }
+
+  #[test]
+  fn parses_rules() {
+    let rule = "weekdays 07:30 scene=morning target=dry-box"
+      .parse::<Rule>()
+      .expect("valid rule");
+
+    assert_eq!(rule.days, Days::WEEKDAYS);
+    assert_eq!(
+      rule.time,
+      chrono::NaiveTime::from_hms_opt(7, 30, 0).expect("valid time")
+    );
+    assert_eq!(rule.action, Action::Scene("morning".to_string()));
+    assert_eq!(rule.target.as_deref(), Some("dry-box"));
+    assert_eq!(rule.to_string(), "weekdays 07:30 scene=morning target=dry-box");
+  }
+
+  #[test]
+  fn rules_default_to_daily() {
+    let rule = "23:00 brightness=40".parse::<Rule>().expect("valid rule");
+
+    assert_eq!(rule.days, Days::DAILY);
+    assert_eq!(rule.action, Action::Brightness(40));
+    assert_eq!(rule.target, None);
+    assert_eq!(rule.to_string(), "daily 23:00 brightness=40");
+  }
+
+  #[test]
+  fn rejects_malformed_rules() {
+    let malformed = [
+      "",
+      "weekdays",
+      "25:00 on",
+      "7.30 on",
+      "07:30",
+      "07:30 dance",
+      "07:30 brightness=256",
+      "07:30 scene=",
+      "07:30 on target=",
+      "07:30 on dry-box",
+      "07:30 on target=dry-box again",
+      "mon,funday 07:30 on",
+    ];
+
+    for input in malformed {
+      assert!(input.parse::<Rule>().is_err(), "'{input}' parsed");
+    }
+  }
+
+  #[test]
+  fn parses_days() {
+    assert_eq!(Days::parse("daily"), Some(Days(0b111_1111)));
+    assert_eq!(Days::parse("weekdays"), Some(Days(0b001_1111)));
+    assert_eq!(Days::parse("weekends"), Some(Days(0b110_0000)));
+    assert_eq!(Days::parse("mon,Wednesday,fri"), Some(Days(0b001_0101)));
+    assert_eq!(Days::parse("mon,"), None);
+    assert_eq!(Days::parse("someday"), None);
+  }
+
+  #[test]
+  fn days_contain_their_weekdays() {
+    let days = Days::parse("tue,sun").expect("valid days");
+
+    assert!(days.contains(Weekday::Tue));
+    assert!(days.contains(Weekday::Sun));
+    assert!(!days.contains(Weekday::Mon));
+    assert!(!days.contains(Weekday::Sat));
+    assert!(Days::WEEKENDS.contains(Weekday::Sat));
+    assert!(!Days::WEEKDAYS.contains(Weekday::Sat));
+  }
+
+  #[test]
+  fn days_display_in_their_shortest_form() {
+    assert_eq!(
+      Days::parse("sat,sun").map(|days| days.to_string()),
+      Some("weekends".to_string())
+    );
+    assert_eq!(
+      Days::parse("friday,monday").map(|days| days.to_string()),
+      Some("mon,fri".to_string())
+    );
+  }
+
+  #[test]
+  fn fires_later_the_same_day() {
+    let rule = "daily 07:30 on".parse::<Rule>().expect("valid rule");
+
+    assert_eq!(rule.next_after(&january(1, 6, 0)), Some(january(1, 7, 30)));
+  }
+
+  #[test]
+  fn fires_the_next_matching_day() {
+    let rule = "weekdays 07:30 on".parse::<Rule>().expect("valid rule");
+
+    // Friday evening, through the weekend, to monday morning.
+    assert_eq!(rule.next_after(&january(5, 20, 0)), Some(january(8, 7, 30)));
+  }
+
+  #[test]
+  fn wraps_around_the_week() {
+    let rule = "mon 07:30 off".parse::<Rule>().expect("valid rule");
+
+    assert_eq!(rule.next_after(&january(1, 8, 0)), Some(january(8, 7, 30)));
+    assert_eq!(rule.next_after(&january(7, 23, 59)), Some(january(8, 7, 30)));
+  }
+
+  #[test]
+  fn does_not_fire_exactly_at_checked() {
+    let rule = "mon 07:30 off".parse::<Rule>().expect("valid rule");
+    let checked = january(1, 7, 30);
+
+    // The scheduler fired the rule when it last checked; it must not fire again until next week.
+    assert_eq!(rule.next_after(&checked), Some(january(8, 7, 30)));
+    assert_eq!(
+      rule.next_after(&(checked - chrono::Duration::seconds(1))),
+      Some(checked)
+    );
+  }
+
+  #[test]
+  fn fires_at_local_times() {
+    let rule = "daily 07:30 on".parse::<Rule>().expect("valid rule");
+    let offset = chrono::FixedOffset::east_opt(2 * 3600).expect("valid offset");
+    let after = offset
+      .with_ymd_and_hms(2024, 1, 1, 8, 0, 0)
+      .single()
+      .expect("valid time");
+
+    assert_eq!(
+      rule.next_after(&after).map(|firing| firing.with_timezone(&chrono::Utc)),
+      Some(january(2, 5, 30))
+    );
+  }
+}
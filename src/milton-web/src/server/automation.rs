@@ -0,0 +1,484 @@
+//! The printer automation polls the octoprint job api and performs a light action whenever the
+//! printer changes state, so that the state of the printer can be seen from across the room. What
+//! happens for each state is configured in the `[printer]` table of the configuration.
+//!
+//! While printing, the completion of the job can also be shown as a bar filling the strip (or a
+//! zone of it), rendered by the firmware's progress effect. The bar is only sent when it grows by a
+//! whole led, so a long print costs a handful of commands rather than one per poll.
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+use crate::octoprint::OctoprintJobResponse;
+use crate::schedule::Action;
+use crate::server::State;
+
+/// The states of the printer that light actions can be configured for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(try_from = "String")]
+pub enum PrinterState {
+  /// Connected and idle.
+  Operational,
+
+  /// Printing, including starting and finishing a print.
+  Printing,
+
+  /// Paused, or pausing, in the middle of a print.
+  Paused,
+
+  /// A print has been (or is being) cancelled; lasts until the next print.
+  Cancelled,
+
+  /// A print has completed; lasts until the next print.
+  Finished,
+
+  /// Octoprint reports an error.
+  Error,
+
+  /// The printer is not connected to octoprint, or octoprint cannot be reached.
+  Offline,
+}
+
+impl std::fmt::Display for PrinterState {
+  fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Self::Operational => write!(formatter, "operational"),
+      Self::Printing => write!(formatter, "printing"),
+      Self::Paused => write!(formatter, "paused"),
+      Self::Cancelled => write!(formatter, "cancelled"),
+      Self::Finished => write!(formatter, "finished"),
+      Self::Error => write!(formatter, "error"),
+      Self::Offline => write!(formatter, "offline"),
+    }
+  }
+}
+
+impl TryFrom<String> for PrinterState {
+  type Error = String;
+
+  fn try_from(input: String) -> std::result::Result<Self, String> {
+    match input.as_str() {
+      "operational" => Ok(Self::Operational),
+      "printing" => Ok(Self::Printing),
+      "paused" => Ok(Self::Paused),
+      "cancelled" => Ok(Self::Cancelled),
+      "finished" => Ok(Self::Finished),
+      "error" => Ok(Self::Error),
+      "offline" => Ok(Self::Offline),
+      _ => Err(format!("invalid printer state '{input}'")),
+    }
+  }
+}
+
+impl PrinterState {
+  /// Returns the state of the printer from a job response. Octoprint reports the end of a print as
+  /// the printer being operational again, so that is told apart using the state before it.
+  fn from_job(job: &OctoprintJobResponse, previous: Option<Self>) -> Self {
+    let state = job.state.as_deref().unwrap_or_default().to_lowercase();
+    let prefixed = |prefixes: &[&str]| prefixes.iter().any(|prefix| state.starts_with(prefix));
+
+    if state.contains("error") {
+      return Self::Error;
+    }
+
+    if state.is_empty() || prefixed(&["offline", "closed", "opening", "connecting", "detecting"]) {
+      return Self::Offline;
+    }
+
+    if prefixed(&["pausing", "paused"]) {
+      return Self::Paused;
+    }
+
+    if prefixed(&["cancelling"]) {
+      return Self::Cancelled;
+    }
+
+    if prefixed(&["printing", "starting", "finishing", "resuming"]) {
+      return Self::Printing;
+    }
+
+    let completion = job
+      .progress
+      .as_ref()
+      .and_then(|progress| progress.completion)
+      .unwrap_or_default();
+
+    match previous {
+      Some(Self::Printing | Self::Paused) if completion >= 100.0 => Self::Finished,
+      Some(Self::Printing | Self::Paused) => Self::Cancelled,
+      Some(previous @ (Self::Finished | Self::Cancelled)) => previous,
+      _ => Self::Operational,
+    }
+  }
+}
+
+/// The `[printer]` table of the configuration.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AutomationConfiguration {
+  /// How often, in seconds, the octoprint job api is polled.
+  #[serde(default = "default_poll_interval")]
+  poll_interval: u64,
+
+  /// The name of the light controller the actions are for; the first one configured when absent.
+  target: Option<String>,
+
+  /// The light action performed when the printer enters each state; states without one leave the
+  /// lights alone.
+  #[serde(default)]
+  states: BTreeMap<PrinterState, Action>,
+
+  /// How long, in seconds, the printer can stay in each state before the `timeout_action` is
+  /// performed (e.g to turn the lights off a while after a print has finished).
+  #[serde(default)]
+  timeouts: BTreeMap<PrinterState, u64>,
+
+  /// The light action performed once a state has timed out.
+  #[serde(default = "default_timeout_action")]
+  timeout_action: Action,
+
+  /// Shows the completion of the job on the lights while printing, when present.
+  progress: Option<ProgressConfiguration>,
+}
+
+/// The `[printer.progress]` table of the configuration.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProgressConfiguration {
+  /// The color of the filled part of the bar.
+  #[serde(default = "default_progress_color")]
+  color: crate::colors::Color,
+
+  /// The zone the bar is drawn in; the whole strip when absent.
+  zone: Option<String>,
+}
+
+/// The progress bar color used when none is configured.
+fn default_progress_color() -> crate::colors::Color {
+  crate::colors::Color(0, 255, 0)
+}
+
+/// How many leds the progress bar spans on the light controller named by `target`; zones and the
+/// whole strip are looked up in what the controller was configured with, falling back to what the
+/// firmware reported when it last connected for strips without a configured led count (configured
+/// counts are clamped to the firmware maximum, like the firmware does). Without a target, the only
+/// light controller is used.
+async fn progress_leds(state: &State, target: Option<&String>, zone: Option<&String>) -> Option<u16> {
+  let status = match target {
+    Some(target) => state.lights.get(target)?,
+    None if state.lights.len() == 1 => state.lights.values().next()?,
+    None => return None,
+  };
+
+  status_leds(&*status.read().await, zone)
+}
+
+/// How many leds of the light controller the progress bar spans; see `progress_leds`.
+fn status_leds(status: &crate::lights::ControllerStatus, zone: Option<&String>) -> Option<u16> {
+  match zone {
+    Some(zone) => status.zones.get(zone).map(|zone| zone.last - zone.first + 1),
+    None => match (status.led_count, status.identity.as_ref()) {
+      (Some(count), Some(identity)) => Some(count.min(identity.max_led_count)),
+      (Some(count), None) => Some(count),
+      (None, identity) => identity.map(|identity| identity.led_count),
+    },
+  }
+}
+
+/// Returns the whole percentage of the job that is complete along with the number of leds of the
+/// bar it lights, unless that is the number already `shown`. Without a known length, the bar is
+/// treated as a hundred leds long, so that it changes with every whole percentage.
+fn progress_update(completion: f64, leds: Option<u16>, shown: Option<u32>) -> Option<(u8, u32)> {
+  let percent = completion.clamp(0.0, 100.0).floor() as u8;
+  let lit = u32::from(percent) * u32::from(leds.unwrap_or(100)) / 100;
+  Some((percent, lit)).filter(|_| shown != Some(lit))
+}
+
+/// Sends the progress bar for the job if it has grown (or shrunk) by at least one led since it was
+/// last `shown`, returning the number of leds now lit. When the length of the bar is unknown, it is
+/// sent whenever the whole percentage changes instead.
+async fn show_progress(
+  state: &State,
+  config: &AutomationConfiguration,
+  progress: &ProgressConfiguration,
+  job: &OctoprintJobResponse,
+  shown: Option<u32>,
+) -> Option<u32> {
+  let completion = job.progress.as_ref().and_then(|progress| progress.completion)?;
+  let leds = progress_leds(state, config.target.as_ref(), progress.zone.as_ref()).await;
+  let Some((percent, lit)) = progress_update(completion, leds, shown) else {
+    return shown;
+  };
+
+  let crate::colors::Color(red, green, blue) = progress.color;
+  let command = crate::lights::Command::Effect(crate::lights::Effect::Progress(percent, red, green, blue));
+  let command = match &progress.zone {
+    Some(zone) => crate::lights::Command::Zone(zone.clone(), Box::new(command)),
+    None => command,
+  };
+  let command = match &config.target {
+    Some(target) => crate::lights::Command::Target(target.clone(), Box::new(command)),
+    None => command,
+  };
+
+  log::info!("printer is {percent}% through the job, showing {lit} of {leds:?} leds");
+  match state.send(super::effects::Effects::Lights(command.into())).await {
+    Ok(()) => Some(lit),
+    Err(error) => {
+      log::warn!("unable to send printer progress - {error}");
+      shown
+    }
+  }
+}
+
+/// How many polls in a row have to fail before the printer is considered `PrinterState::Offline`.
+const OFFLINE_AFTER_FAILURES: u32 = 3;
+
+/// The poll interval used when none is configured.
+fn default_poll_interval() -> u64 {
+  5
+}
+
+/// The timeout action used when none is configured.
+fn default_timeout_action() -> Action {
+  Action::Off
+}
+
+/// Polls octoprint forever, performing the configured light actions as the printer changes state.
+/// The first state seen is only recorded; the lights are already restored to whatever they were
+/// left at.
+pub async fn run(state: State, config: AutomationConfiguration) -> std::io::Result<()> {
+  log::info!("starting printer automation - {config:?}");
+  let interval = std::time::Duration::from_secs(config.poll_interval.max(1));
+  let mut previous = None;
+  let mut timeout = None;
+  let mut failures = 0;
+  let mut shown = None;
+
+  loop {
+    // A stale job is only what the printer was doing when octoprint was last reachable.
+    let job = match state.octoprint.job().await {
+      Ok(job) if !job.stale => {
+        failures = 0;
+        Some(job.value)
+      }
+      Ok(job) => {
+        failures += 1;
+        log::warn!(
+          "unable to poll printer state ({failures} in a row), last heard from octoprint at {}",
+          job.updated
+        );
+        None
+      }
+      Err(error) => {
+        failures += 1;
+        log::warn!("unable to poll printer state ({failures} in a row) - {error}");
+        None
+      }
+    };
+    let current = match (&job, previous) {
+      (Some(job), _) => PrinterState::from_job(job, previous),
+      (None, _) if failures >= OFFLINE_AFTER_FAILURES => PrinterState::Offline,
+      // A missed poll says nothing about the printer; it is left in the state it was last seen in
+      // until octoprint has been unreachable for a while.
+      (None, Some(previous)) => previous,
+      (None, None) => {
+        async_std::task::sleep(interval).await;
+        continue;
+      }
+    };
+
+    if let Some(previous) = previous.filter(|previous| *previous != current) {
+      // Whatever the action does to the lights, the bar has to be drawn again on top of it.
+      shown = None;
+
+      log::info!("printer went from {previous} to {current}");
+      timeout = config
+        .timeouts
+        .get(&current)
+        .map(|seconds| std::time::Instant::now() + std::time::Duration::from_secs(*seconds));
+
+      if let Some(action) = config.states.get(&current) {
+        let source = format!("printer {current}");
+        super::schedules::perform(&state, action, config.target.clone(), &source).await;
+      }
+    }
+
+    previous = Some(current);
+
+    if let (Some(progress), Some(job), PrinterState::Printing) = (&config.progress, &job, current) {
+      shown = show_progress(&state, &config, progress, job, shown).await;
+    }
+
+    if timeout
+      .map(|deadline| deadline <= std::time::Instant::now())
+      .unwrap_or(false)
+    {
+      log::info!("printer has been {current} for too long, performing timeout action");
+      let source = format!("printer {current} timeout");
+      super::schedules::perform(&state, &config.timeout_action, config.target.clone(), &source).await;
+      timeout = None;
+    }
+
+    let wait = timeout
+      .map(|deadline| deadline.saturating_duration_since(std::time::Instant::now()))
+      .map(|remaining| remaining.min(interval))
+      .unwrap_or(interval);
+
+    async_std::task::sleep(wait).await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{progress_update, status_leds, PrinterState};
+  use crate::lights::{ControllerStatus, Identity, Zone};
+  use crate::octoprint::OctoprintJobResponse;
+
+  /// Returns a job response in the state, with the completion.
+  fn job(state: &str, completion: Option<f64>) -> OctoprintJobResponse {
+    serde_json::from_value(serde_json::json!({
+      "job": null,
+      "progress": { "completion": completion },
+      "state": state,
+    }))
+    .expect("valid job response")
+  }
+
+  #[test]
+  fn printer_states_from_jobs() {
+    use PrinterState::*;
+
+    let cases = [
+      ("Printing", Some(12.5), Some(Operational), Printing),
+      ("Starting print from SD", None, Some(Operational), Printing),
+      ("Finishing", Some(100.0), Some(Printing), Printing),
+      ("Resuming", Some(40.0), Some(Paused), Printing),
+      ("Operational", Some(100.0), Some(Printing), Finished),
+      ("Operational", Some(99.9), Some(Printing), Cancelled),
+      ("Operational", Some(100.0), Some(Paused), Finished),
+      ("Operational", Some(40.0), Some(Paused), Cancelled),
+      ("Operational", None, Some(Printing), Cancelled),
+      ("Operational", Some(100.0), Some(Finished), Finished),
+      ("Operational", Some(40.0), Some(Cancelled), Cancelled),
+      ("Operational", Some(100.0), Some(Offline), Operational),
+      ("Operational", Some(100.0), None, Operational),
+      ("Pausing", Some(40.0), Some(Printing), Paused),
+      ("Paused", Some(40.0), Some(Paused), Paused),
+      ("Cancelling", Some(40.0), Some(Printing), Cancelled),
+      ("Error", None, Some(Printing), Error),
+      ("Offline after error", None, Some(Printing), Error),
+      ("Error: Failed to autodetect serial port", None, None, Error),
+      ("Offline", None, Some(Operational), Offline),
+      ("Closed", None, Some(Operational), Offline),
+      ("Detecting serial connection", None, None, Offline),
+      ("", None, Some(Operational), Offline),
+    ];
+
+    for (state, completion, previous, expected) in cases {
+      assert_eq!(
+        PrinterState::from_job(&job(state, completion), previous),
+        expected,
+        "'{state}' at {completion:?} after {previous:?}"
+      );
+    }
+  }
+
+  /// Returns the status of a light controller with a `bar` zone of ten leds, that was configured
+  /// with the led count and identified itself as driving 60 of up to 120 leds, when `identified`.
+  fn status(led_count: Option<u16>, identified: bool) -> ControllerStatus {
+    ControllerStatus {
+      zones: [(
+        "bar".to_string(),
+        Zone::try_from("10..19".to_string()).expect("valid zone"),
+      )]
+      .into(),
+      led_count,
+      identity: identified.then(|| Identity {
+        firmware_version: "1".to_string(),
+        protocol_version: milton_protocol::VERSION,
+        max_led_count: 120,
+        led_count: 60,
+      }),
+      ..ControllerStatus::default()
+    }
+  }
+
+  /// Polls a job going from 0% to 100% in tenths of a percent, returning the completion at every
+  /// update along with the number of leds it lit.
+  fn updates(leds: Option<u16>) -> Vec<(f64, u32)> {
+    let mut shown = None;
+    let mut updates = Vec::new();
+
+    for tenths in 0..=1000 {
+      let completion = f64::from(tenths) / 10.0;
+
+      if let Some((_, lit)) = progress_update(completion, leds, shown) {
+        shown = Some(lit);
+        updates.push((completion, lit));
+      }
+    }
+
+    updates
+  }
+
+  #[test]
+  fn progress_spans_the_zone_or_strip() {
+    let bar = "bar".to_string();
+    let missing = "missing".to_string();
+    let cases = [
+      (None, true, Some(&bar), Some(10)),
+      (None, false, Some(&bar), Some(10)),
+      (Some(30), true, Some(&bar), Some(10)),
+      (None, true, Some(&missing), None),
+      (None, true, None, Some(60)),
+      (Some(30), true, None, Some(30)),
+      (Some(500), true, None, Some(120)),
+      (Some(30), false, None, Some(30)),
+      (None, false, None, None),
+    ];
+
+    for (led_count, identified, zone, expected) in cases {
+      assert_eq!(
+        status_leds(&status(led_count, identified), zone),
+        expected,
+        "{zone:?} with {led_count:?} configured, identified: {identified}"
+      );
+    }
+  }
+
+  #[test]
+  fn progress_is_only_shown_on_whole_leds() {
+    let bar = "bar".to_string();
+
+    for (led_count, identified, zone) in [
+      (None, true, Some(&bar)),
+      (None, false, Some(&bar)),
+      (None, true, None),
+      (Some(30), false, None),
+      (Some(150), true, None),
+      (None, false, None),
+    ] {
+      let leds = status_leds(&status(led_count, identified), zone);
+      let length = u32::from(leds.unwrap_or(100));
+      let updates = updates(leds);
+
+      // The bar only ever grows, ending with the whole of it lit; shorter bars than a hundred leds
+      // light every one of them in turn, while longer ones can only change once per percent.
+      let lit = updates.iter().map(|(_, lit)| *lit).collect::<Vec<_>>();
+      assert!(lit.windows(2).all(|pair| pair[0] < pair[1]), "{leds:?} leds - {lit:?}");
+      assert_eq!(lit.last(), Some(&length), "{leds:?} leds");
+
+      if length <= 100 {
+        assert_eq!(lit, (0..=length).collect::<Vec<_>>(), "{leds:?} leds");
+      }
+
+      for (completion, lit) in updates {
+        let percent = completion.floor() as u32;
+        assert_eq!(percent * length / 100, lit, "{completion}% of {leds:?} leds");
+        assert!(
+          percent == 0 || (percent - 1) * length / 100 < lit,
+          "{completion}% of {leds:?} leds"
+        );
+      }
+    }
+  }
+}
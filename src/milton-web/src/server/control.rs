@@ -1,11 +1,14 @@
 use serde::{Deserialize, Serialize};
 use tide::{Request, Response, Result};
 
-use crate::{octoprint::OctoprintJobResponse, server::State};
+use crate::{octoprint, server::State};
 
 /// The stream endpoint will use this as the http multi-part boundary for its mjpg stream.
 const MJPG_BOUNDARY: &str = "mjpg-boundary-do-not-cross";
 
+/// How long a control request will wait for its command to be resolved by the light runtime.
+const OUTCOME_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
 /// Requests to the control api will receive this type serialized as json.
 #[derive(Debug, Serialize)]
 struct ControlResponse {
@@ -40,6 +43,98 @@ struct ColorControlQuery {
   color: crate::lights::BasicColor,
 }
 
+/// Arbitrary colors are provided as strings; see `crate::colors::parse` for the supported formats.
+#[derive(Debug, Deserialize)]
+struct ArbitraryColorControlQuery {
+  /// The color to set, e.g `#ff8800`, `rgb(255, 136, 0)`, `hsv(32, 100%, 100%)` or `darkorange`.
+  color: String,
+}
+
+/// Brightness is controlled independently of color; the firmware keeps whatever color it has.
+#[derive(Debug, Deserialize)]
+struct BrightnessControlQuery {
+  /// The brightness level, from 0 (off) to 255 (full).
+  level: u8,
+}
+
+/// Sets a contiguous range of leds to a single color.
+#[derive(Debug, Deserialize)]
+struct FillControlQuery {
+  /// The index of the first led to set.
+  start: u16,
+
+  /// The index of the led after the last one to set.
+  end: u16,
+
+  /// The color to set, parsed the same way as `ArbitraryColorControlQuery`.
+  color: String,
+}
+
+/// A single led, addressed by its index.
+#[derive(Debug, Deserialize)]
+struct PixelControl {
+  /// The index of the led, starting from zero.
+  index: u16,
+
+  /// The color to set, parsed the same way as `ArbitraryColorControlQuery`.
+  color: String,
+}
+
+/// Sets individual leds, leaving the others alone.
+#[derive(Debug, Deserialize)]
+struct PixelsControlQuery {
+  /// The leds to set; at most `milton_protocol::MAX_PIXELS` of them.
+  pixels: Vec<PixelControl>,
+}
+
+/// Sets every led, starting from the first, to a list of colors.
+#[derive(Debug, Deserialize)]
+struct FrameControlQuery {
+  /// The color of each led; at most `milton_protocol::MAX_FRAME_PIXELS` of them.
+  colors: Vec<String>,
+}
+
+/// The names of the animated effects supported by the light controller.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum EffectName {
+  /// Fade a color in and out.
+  Breathe,
+
+  /// Cycle through the color wheel.
+  Rainbow,
+
+  /// A lit led with a fading tail travelling along the strip.
+  Comet,
+
+  /// Turn a color on and off.
+  Blink,
+
+  /// Fill the strip proportionally to `percent`.
+  Progress,
+}
+
+/// Effects are selected by name; most of them are rendered in a color, which is parsed the same
+/// way as `ArbitraryColorControlQuery`.
+#[derive(Debug, Deserialize)]
+struct EffectControlQuery {
+  /// The effect to start.
+  effect: EffectName,
+
+  /// The color used by the effect, if any. Defaults to white.
+  color: Option<String>,
+
+  /// The fill percentage (0-100) used by the progress effect.
+  percent: Option<u8>,
+}
+
+/// Activates a scene saved with `PUT /lights/scenes/:name`.
+#[derive(Debug, Deserialize)]
+struct SceneControlQuery {
+  /// The name of the scene.
+  name: String,
+}
+
 /// This type is used to represent the various json payloads supported by the "direct" control api
 /// route.
 #[derive(Debug, Deserialize)]
@@ -50,6 +145,54 @@ enum ControlQuery {
 
   /// Will control basic color.
   BasicColor(ColorControlQuery),
+
+  /// Will control arbitrary color.
+  Color(ArbitraryColorControlQuery),
+
+  /// Will control brightness.
+  Brightness(BrightnessControlQuery),
+
+  /// Will start an animated effect.
+  Effect(EffectControlQuery),
+
+  /// Will set a range of leds.
+  Fill(FillControlQuery),
+
+  /// Will set individual leds.
+  Pixels(PixelsControlQuery),
+
+  /// Will set every led.
+  Frame(FrameControlQuery),
+
+  /// Will activate a saved scene.
+  Scene(SceneControlQuery),
+}
+
+/// Parses a color provided in a control query, mapping failures to the `bad-color` error.
+fn parse_color(color: &str) -> std::result::Result<(u8, u8, u8), tide::Error> {
+  crate::colors::parse(color).ok_or_else(|| {
+    log::warn!("unable to parse color '{color}'");
+    tide::Error::from_str(422, "bad-color")
+  })
+}
+
+/// Every control query can optionally be faded into over some amount of time.
+#[derive(Debug, Deserialize)]
+struct ControlRequest {
+  /// The actual control query.
+  #[serde(flatten)]
+  query: ControlQuery,
+
+  /// How long, in milliseconds, the lights should take to transition into the requested state.
+  transition_ms: Option<u32>,
+
+  /// The name of a zone from the light controller configuration to scope a color, brightness or
+  /// effect to.
+  zone: Option<String>,
+
+  /// The name of the light controller to send the command to; the first one configured is used
+  /// when absent.
+  target: Option<String>,
 }
 
 /// Accessing the snapshot endpoint is something that we'd like octoprint to be able to do, in
@@ -60,21 +203,6 @@ struct SnapshotUrlQuery {
   token: Option<String>,
 }
 
-// TODO: this will be useful once we're able to control specific colors. blocked by firmware.
-// fn parse_hex(input: &String) -> Option<(u8, u8, u8)> {
-//   let mut results = (1..input.len())
-//     .step_by(2)
-//     .map(|i| u8::from_str_radix(&input[i..i + 2], 16).ok())
-//     .flatten()
-//     .collect::<Vec<u8>>();
-//
-//   results
-//     .pop()
-//     .zip(results.pop())
-//     .zip(results.pop())
-//     .map(|((r, g), b)| (r, g, b))
-// }
-
 /// ROUTE: return jpeg snapshot
 pub async fn snapshot(request: Request<State>) -> Result {
   // TODO: replace this with a more robust application auth token storage + validation system.
@@ -197,6 +325,23 @@ pub async fn stream(request: Request<State>) -> Result {
   Ok(response)
 }
 
+/// The current job, as returned by octoprint, along with when it should be done.
+#[derive(Debug, Serialize)]
+struct JobQueryResponse {
+  /// The response from the octoprint job api.
+  #[serde(flatten)]
+  job: octoprint::OctoprintJobResponse,
+
+  /// When the job should finish, if it is printing.
+  finishes_at: Option<chrono::DateTime<chrono::Utc>>,
+
+  /// When the job was fetched from octoprint.
+  updated: chrono::DateTime<chrono::Utc>,
+
+  /// Whether octoprint is currently unreachable, making this the last job we know of.
+  stale: bool,
+}
+
 /// ROUTE: fetches current job information from octoprint api
 pub async fn query(req: Request<State>) -> Result {
   super::authority(&req).await.ok_or_else(|| {
@@ -204,26 +349,17 @@ pub async fn query(req: Request<State>) -> Result {
     tide::Error::from_str(404, "not-found")
   })?;
 
-  let mut res = surf::get(format!("{}/api/job", &req.state().config.octoprint_api_url))
-    .header("X-Api-Key", &req.state().config.octoprint_api_key)
-    .await
-    .map_err(|error| {
-      log::warn!("unable to issue request to octoprint - {}", error);
-      tide::Error::from_str(500, "bad-config")
-    })?;
-
-  if res.status() != surf::StatusCode::Ok {
-    log::warn!("bad octoprint response status - '{:?}'", res.status());
-    return Err(tide::Error::from_str(500, "bad-config"));
-  }
-
-  let infos = res.body_json::<OctoprintJobResponse>().await.map_err(|error| {
-    log::warn!("invalid response from octoprint - {}", error);
-    tide::Error::from_str(500, "bad-config")
-  })?;
+  let infos = req.state().octoprint.job().await.map_err(super::printer::unavailable)?;
 
   log::info!("requested octoprint current job info - {:?}", infos);
-  tide::Body::from_json(&infos).map(|bod| Response::builder(200).body(bod).build())
+  let query = JobQueryResponse {
+    // The time left is as of when the job was fetched, which may have been a while ago.
+    finishes_at: infos.value.finishes_at(infos.updated),
+    job: infos.value,
+    updated: infos.updated,
+    stale: infos.stale,
+  };
+  tide::Body::from_json(&query).map(|bod| Response::builder(200).body(bod).build())
 }
 
 /// ROUTE: sends command to heartbeat/light controls.
@@ -241,35 +377,231 @@ pub async fn command(mut req: Request<State>) -> Result {
 
   timer = std::time::Instant::now();
 
-  let query = req.body_json::<ControlQuery>().await.map_err(|error| {
+  let ControlRequest {
+    query,
+    transition_ms,
+    zone,
+    target,
+  } = req.body_json::<ControlRequest>().await.map_err(|error| {
     log::warn!("unable to parse control payload - {}", error);
     tide::Error::from_str(422, "bad-payload")
   })?;
 
-  log::debug!("received control request - {:?}", query);
+  log::debug!(
+    "received control request - {:?} (transition {:?}, zone {:?}, target {:?})",
+    query,
+    transition_ms,
+    zone,
+    target
+  );
 
-  let effect = match query {
-    ControlQuery::BasicColor(ColorControlQuery { color }) => {
-      super::effects::Effects::Lights(crate::lights::Command::BasicColor(color))
+  if let Some(name) = target.as_ref().filter(|name| !req.state().lights.contains_key(*name)) {
+    log::warn!("control request for unknown light controller '{name}'");
+    return Err(tide::Error::from_str(422, "bad-target"));
+  }
+
+  let command = match query {
+    ControlQuery::BasicColor(ColorControlQuery { color }) => crate::lights::Command::BasicColor(color),
+    ControlQuery::Color(ArbitraryColorControlQuery { color }) => {
+      let (red, green, blue) = parse_color(&color)?;
+      crate::lights::Command::Rgb(red, green, blue)
+    }
+    ControlQuery::Brightness(BrightnessControlQuery { level }) => crate::lights::Command::Brightness(level),
+    ControlQuery::Effect(EffectControlQuery { effect, color, percent }) => {
+      let (red, green, blue) = match color {
+        Some(color) => parse_color(&color)?,
+        None => (255, 255, 255),
+      };
+
+      crate::lights::Command::Effect(match effect {
+        EffectName::Breathe => crate::lights::Effect::Breathe(red, green, blue),
+        EffectName::Rainbow => crate::lights::Effect::Rainbow,
+        EffectName::Comet => crate::lights::Effect::Comet(red, green, blue),
+        EffectName::Blink => crate::lights::Effect::Blink(red, green, blue),
+        EffectName::Progress => {
+          let percent = percent.filter(|percent| *percent <= 100).ok_or_else(|| {
+            log::warn!("invalid progress percentage '{percent:?}'");
+            tide::Error::from_str(422, "bad-percent")
+          })?;
+          crate::lights::Effect::Progress(percent, red, green, blue)
+        }
+      })
+    }
+    ControlQuery::Fill(FillControlQuery { start, end, color }) => {
+      if start > end {
+        log::warn!("invalid fill range {start}..{end}");
+        return Err(tide::Error::from_str(422, "bad-range"));
+      }
+
+      let (red, green, blue) = parse_color(&color)?;
+      crate::lights::Command::Fill(start, end, red, green, blue)
+    }
+    ControlQuery::Pixels(PixelsControlQuery { pixels }) => {
+      if pixels.is_empty() || pixels.len() > milton_protocol::MAX_PIXELS {
+        log::warn!("invalid pixel count {}", pixels.len());
+        return Err(tide::Error::from_str(422, "bad-pixel-count"));
+      }
+
+      let pixels = pixels
+        .iter()
+        .map(|PixelControl { index, color }| parse_color(color).map(|(red, green, blue)| (*index, red, green, blue)))
+        .collect::<std::result::Result<Vec<_>, tide::Error>>()?;
+      crate::lights::Command::Pixels(pixels)
+    }
+    ControlQuery::Frame(FrameControlQuery { colors }) => {
+      if colors.is_empty() || colors.len() > milton_protocol::MAX_FRAME_PIXELS {
+        log::warn!("invalid frame length {}", colors.len());
+        return Err(tide::Error::from_str(422, "bad-pixel-count"));
+      }
+
+      let colors = colors
+        .iter()
+        .map(|color| parse_color(color))
+        .collect::<std::result::Result<Vec<_>, tide::Error>>()?;
+      crate::lights::Command::Frame(colors)
     }
     ControlQuery::State(target_state) => {
       if target_state.on {
-        super::effects::Effects::Lights(crate::lights::Command::On)
+        crate::lights::Command::On
       } else {
-        super::effects::Effects::Lights(crate::lights::Command::Off)
+        crate::lights::Command::Off
+      }
+    }
+    ControlQuery::Scene(SceneControlQuery { name }) => {
+      if zone.is_some() {
+        log::warn!("unable to scope scene '{name}' to a zone");
+        return Err(tide::Error::from_str(422, "bad-zone"));
       }
+
+      let commands = scene_commands(req.state(), &name, transition_ms, target).await?;
+      return send_commands(req.state(), commands, super::requester(&req), timer).await;
+    }
+  };
+
+  let command = match zone {
+    Some(zone) if command.is_zonable() => crate::lights::Command::Zone(zone, Box::new(command)),
+    Some(zone) => {
+      log::warn!("unable to scope {command:?} to zone '{zone}'");
+      return Err(tide::Error::from_str(422, "bad-zone"));
     }
+    None => command,
   };
 
-  if let Err(error) = req.state().send(effect).await {
-    log::warn!("unable to send control effect - {error}");
-    return Ok(tide::Response::new(500));
+  let command = match transition_ms {
+    Some(duration) if duration > 0 => crate::lights::Command::Transition(Box::new(command), duration),
+    _ => command,
+  };
+
+  let command = match target {
+    Some(name) => crate::lights::Command::Target(name, Box::new(command)),
+    None => command,
+  };
+
+  send_commands(req.state(), vec![command], super::requester(&req), timer).await
+}
+
+/// Returns the commands that activate the saved scene, targeting the light controller each of them
+/// is for; only the light controller named by `target` is included when it is provided.
+pub(super) async fn scene_commands(
+  state: &State,
+  name: &str,
+  transition_ms: Option<u32>,
+  target: Option<String>,
+) -> std::result::Result<Vec<crate::lights::Command>, tide::Error> {
+  let scene = state
+    .scene(name)
+    .await
+    .map_err(|error| {
+      log::warn!("unable to load scene '{name}' - {error}");
+      tide::Error::from_str(500, "scenes-unavailable")
+    })?
+    .ok_or_else(|| {
+      log::warn!("control request for unknown scene '{name}'");
+      tide::Error::from_str(404, "scene-not-found")
+    })?;
+
+  let mut commands = vec![];
+
+  for (controller, lights) in scene.lights {
+    if target.as_ref().map(|target| *target != controller).unwrap_or(false) {
+      continue;
+    }
+
+    // Scenes outlive configuration; light controllers that have since been removed are skipped.
+    if !state.lights.contains_key(&controller) {
+      log::warn!("scene '{name}' names unknown light controller '{controller}', skipping");
+      continue;
+    }
+
+    commands.extend(lights.commands().into_iter().map(|command| {
+      let command = match transition_ms {
+        Some(duration) if duration > 0 => crate::lights::Command::Transition(Box::new(command), duration),
+        _ => command,
+      };
+
+      crate::lights::Command::Target(controller.clone(), Box::new(command))
+    }));
   }
 
-  log::debug!(
-    "sent control effect in {} millis",
-    std::time::Instant::now().duration_since(timer).as_millis()
-  );
+  if commands.is_empty() {
+    log::warn!("scene '{name}' has nothing to send");
+    return Err(tide::Error::from_str(422, "bad-scene"));
+  }
+
+  Ok(commands)
+}
+
+/// Sends the commands to the light runtimes on behalf of the requester and waits for every one of
+/// them to be resolved; the first command that was not acknowledged decides the error returned.
+async fn send_commands(
+  state: &State,
+  commands: Vec<crate::lights::Command>,
+  requester: String,
+  timer: std::time::Instant,
+) -> Result {
+  let mut outcomes = Vec::with_capacity(commands.len());
+
+  for command in commands {
+    let (request, outcome) = crate::lights::Request::tracked(command);
+    let request = request.requested_by(requester.clone());
+
+    if let Err(error) = state.send(super::effects::Effects::Lights(request)).await {
+      log::warn!("unable to send control effect - {error}");
+      return Ok(tide::Response::new(500));
+    }
+
+    outcomes.push(outcome);
+  }
+
+  for outcome in outcomes {
+    // The light runtime resolves every command within its own acknowledgement timeout; this outer
+    // timeout only guards against the command never making it to the runtime.
+    let outcome = async_std::future::timeout(OUTCOME_TIMEOUT, outcome.recv())
+      .await
+      .map_err(|_| {
+        log::warn!("light command was never resolved");
+        tide::Error::from_str(504, "lights-unanswered")
+      })?
+      .map_err(|error| {
+        log::warn!("light command dropped before being resolved - {error}");
+        tide::Error::from_str(500, "lights-unavailable")
+      })?;
+
+    log::debug!(
+      "light command resolved as {outcome:?} in {} millis",
+      std::time::Instant::now().duration_since(timer).as_millis()
+    );
+
+    match outcome {
+      // A superseded command was never written, but the lights look as though it had been.
+      crate::lights::Outcome::Acknowledged | crate::lights::Outcome::Superseded => (),
+      crate::lights::Outcome::Rejected => return Err(tide::Error::from_str(502, "lights-rejected")),
+      crate::lights::Outcome::Disconnected => return Err(tide::Error::from_str(503, "lights-disconnected")),
+      crate::lights::Outcome::Unanswered => return Err(tide::Error::from_str(504, "lights-unanswered")),
+      crate::lights::Outcome::Invalid => return Err(tide::Error::from_str(422, "bad-command")),
+      crate::lights::Outcome::RateLimited => return Err(tide::Error::from_str(429, "lights-rate-limited")),
+    }
+  }
 
   tide::Body::from_json(&ControlResponse::default()).map(|bod| Response::builder(200).body(bod).build())
 }
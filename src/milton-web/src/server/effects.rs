@@ -1,7 +1,225 @@
+//! Side effects created by web requests are published on a `Bus`, and handled by whoever has
+//! subscribed to it (the light runtimes, the audit log, etc...). Every subscriber receives every
+//! effect, in the order they were published, on a buffer of its own.
+
+use async_std::channel;
+use std::io::Result;
+
 /// This enumerated type represents all of the "outbound" effects that can be created from a web
 /// request.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Effects {
   /// `Lights` effects are used to control the led strip; sent to `pio-lights` firmware.
-  Lights(crate::lights::Command),
+  Lights(crate::lights::Request),
+}
+
+impl std::fmt::Display for Effects {
+  fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Effects::Lights(request) => write!(formatter, "lights {}", request.command),
+    }
+  }
+}
+
+/// What happens to an effect published while a subscriber's buffer is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+  /// The publisher waits until the subscriber makes room. Nothing is lost, but a subscriber that
+  /// stops receiving holds up every publisher; meant for subscribers that must see every effect,
+  /// like the light runtimes.
+  Wait,
+
+  /// The effect is skipped for this subscriber (and logged), leaving publishers and the other
+  /// subscribers unaffected; meant for subscribers that only observe, like the audit log.
+  Drop,
+}
+
+/// A subscriber, as seen by the bus.
+#[derive(Debug, Clone)]
+struct Subscriber {
+  /// Used when logging about the subscriber.
+  name: String,
+
+  /// The sending half of the subscriber's buffer.
+  sender: channel::Sender<Effects>,
+
+  /// What to do when the buffer is full.
+  overflow: Overflow,
+}
+
+/// Fans every published effect out to each of its subscribers. Clones share the same subscribers.
+#[derive(Debug, Clone, Default)]
+pub struct Bus {
+  /// Everyone currently subscribed; subscribers that have dropped their receiver are removed the
+  /// next time an effect is published.
+  subscribers: std::sync::Arc<std::sync::RwLock<Vec<Subscriber>>>,
+}
+
+impl Bus {
+  /// Subscribes to every effect published from now on, buffering up to `capacity` of them.
+  pub fn subscribe<N>(&self, name: N, capacity: usize, overflow: Overflow) -> channel::Receiver<Effects>
+  where
+    N: Into<String>,
+  {
+    let (sender, receiver) = channel::bounded(capacity.max(1));
+    let subscriber = Subscriber {
+      name: name.into(),
+      sender,
+      overflow,
+    };
+
+    match self.subscribers.write() {
+      Ok(mut subscribers) => subscribers.push(subscriber),
+      Err(poisoned) => poisoned.into_inner().push(subscriber),
+    }
+
+    receiver
+  }
+
+  /// Returns a snapshot of the current subscribers, so that the lock is not held while publishing.
+  fn subscribers(&self) -> Vec<Subscriber> {
+    match self.subscribers.read() {
+      Ok(subscribers) => subscribers.clone(),
+      Err(poisoned) => poisoned.into_inner().clone(),
+    }
+  }
+
+  /// Publishes the effect to every subscriber, according to each subscriber's overflow policy. An
+  /// error is returned if nobody was subscribed to receive it.
+  pub async fn publish(&self, effect: Effects) -> Result<()> {
+    let mut delivered = 0;
+    let mut closed = false;
+
+    for subscriber in self.subscribers() {
+      let sent = match subscriber.overflow {
+        Overflow::Wait => subscriber.sender.send(effect.clone()).await.map_err(|_| true),
+        Overflow::Drop => subscriber
+          .sender
+          .try_send(effect.clone())
+          .map_err(|error| error.is_closed()),
+      };
+
+      match sent {
+        Ok(()) => delivered += 1,
+        Err(true) => {
+          log::debug!("effect subscriber '{}' has gone away", subscriber.name);
+          closed = true;
+        }
+        Err(false) => log::warn!("effect subscriber '{}' is full, skipping '{effect}'", subscriber.name),
+      }
+    }
+
+    if closed {
+      let mut subscribers = match self.subscribers.write() {
+        Ok(subscribers) => subscribers,
+        Err(poisoned) => poisoned.into_inner(),
+      };
+      subscribers.retain(|subscriber| !subscriber.sender.is_closed());
+    }
+
+    if delivered == 0 {
+      return Err(std::io::Error::other(format!(
+        "nobody subscribed to receive '{effect}'"
+      )));
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Bus, Effects, Overflow};
+  use crate::lights::Command;
+  use async_std::prelude::FutureExt;
+
+  /// Returns an effect that sets the brightness to the level.
+  fn brightness(level: u8) -> Effects {
+    Effects::Lights(Command::Brightness(level).into())
+  }
+
+  /// Returns the brightness level of an effect created by `brightness`.
+  fn level(effect: Effects) -> Option<u8> {
+    match effect {
+      Effects::Lights(request) => match request.command {
+        Command::Brightness(level) => Some(level),
+        _ => None,
+      },
+    }
+  }
+
+  #[test]
+  fn full_drop_subscribers_do_not_hold_anyone_up() {
+    async_std::task::block_on(async {
+      let bus = Bus::default();
+      let audit = bus.subscribe("audit", 1, Overflow::Drop);
+      let lights = bus.subscribe("lights", 4, Overflow::Wait);
+
+      for value in 1..=3 {
+        let published = bus
+          .publish(brightness(value))
+          .timeout(std::time::Duration::from_secs(1))
+          .await;
+        assert!(matches!(published, Ok(Ok(()))), "publishing {value} blocked or failed");
+      }
+
+      assert_eq!(audit.try_recv().ok().and_then(level), Some(1));
+      assert!(audit.try_recv().is_err());
+
+      let received = std::iter::from_fn(|| lights.try_recv().ok().and_then(level)).collect::<Vec<_>>();
+      assert_eq!(received, [1, 2, 3]);
+    });
+  }
+
+  #[test]
+  fn wait_subscribers_receive_everything_in_order() {
+    async_std::task::block_on(async {
+      let bus = Bus::default();
+      let lights = bus.subscribe("lights", 1, Overflow::Wait);
+
+      let receiving = async_std::task::spawn(async move {
+        let mut received = Vec::new();
+
+        while let Ok(effect) = lights.recv().await {
+          received.extend(level(effect));
+        }
+
+        received
+      });
+
+      for value in 0..50 {
+        bus.publish(brightness(value)).await.expect("delivered");
+      }
+
+      drop(bus);
+      assert_eq!(receiving.await, (0..50).collect::<Vec<_>>());
+    });
+  }
+
+  #[test]
+  fn closed_subscribers_are_pruned() {
+    async_std::task::block_on(async {
+      let bus = Bus::default();
+      let audit = bus.subscribe("audit", 1, Overflow::Drop);
+      let lights = bus.subscribe("lights", 1, Overflow::Wait);
+      drop(audit);
+
+      bus.publish(brightness(1)).await.expect("delivered to lights");
+      assert_eq!(bus.subscribers().len(), 1);
+      assert_eq!(lights.try_recv().ok().and_then(level), Some(1));
+
+      drop(lights);
+      assert!(bus.publish(brightness(2)).await.is_err());
+      assert!(bus.subscribers().is_empty());
+    });
+  }
+
+  #[test]
+  fn publishing_without_subscribers_fails() {
+    async_std::task::block_on(async {
+      let bus = Bus::default();
+      let error = bus.publish(brightness(1)).await.expect_err("nobody subscribed");
+      assert!(error.to_string().starts_with("nobody subscribed"), "{error}");
+    });
+  }
 }
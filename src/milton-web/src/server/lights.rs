@@ -0,0 +1,37 @@
+use tide::{Request, Response, Result};
+
+use crate::server::State;
+
+/// ROUTE: returns everything we know about each light controller, by name; whether we are
+/// connected, what it said about itself when we connected and its most recent status report.
+pub async fn status(request: Request<State>) -> Result {
+  super::authority(&request).await.ok_or_else(|| {
+    log::warn!("unauthorized attempt to query light controller status");
+    tide::Error::from_str(404, "not-found")
+  })?;
+
+  let mut status = std::collections::BTreeMap::new();
+
+  for (name, controller) in &request.state().lights {
+    status.insert(name.clone(), controller.read().await.clone());
+  }
+
+  tide::Body::from_json(&status).map(|bod| Response::builder(200).body(bod).build())
+}
+
+/// ROUTE: returns how the lights of each light controller should currently look, by name; this is
+/// what is sent to the light controller whenever it (re)connects.
+pub async fn state(request: Request<State>) -> Result {
+  super::authority(&request).await.ok_or_else(|| {
+    log::warn!("unauthorized attempt to query light state");
+    tide::Error::from_str(404, "not-found")
+  })?;
+
+  let mut states = std::collections::BTreeMap::new();
+
+  for (name, controller) in &request.state().lights {
+    states.insert(name.clone(), controller.read().await.state.clone());
+  }
+
+  tide::Body::from_json(&states).map(|bod| Response::builder(200).body(bod).build())
+}
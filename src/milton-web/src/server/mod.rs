@@ -3,7 +3,6 @@
 
 use std::io::{Error, ErrorKind, Result};
 
-use async_std::channel::Sender;
 use serde::{Deserialize, Serialize};
 use tide::{http::Cookie, Request, Response};
 #[cfg(feature = "camera")]
@@ -18,10 +17,20 @@ mod sec;
 
 /// Routes and types related to authentication.
 pub mod auth;
+/// Light automation driven by the state of the printer.
+pub mod automation;
 /// Routes and types related to system control.
 pub mod control;
-
-/// General type definition for side effects.
+/// Routes related to the light controller itself.
+pub mod lights;
+/// Routes related to the printer, proxied from octoprint.
+pub mod printer;
+/// Routes and types related to saved light scenes.
+pub mod scenes;
+/// Routes and the runner for time-based light schedules.
+pub mod schedules;
+
+/// General type definition for side effects, and the bus they are published on.
 pub mod effects;
 
 #[cfg(feature = "camera")]
@@ -46,6 +55,14 @@ pub struct Configuration {
   /// API key for octoprint. (e.g abcdef)
   octoprint_api_key: String,
 
+  /// How long, in milliseconds, to wait on octoprint before considering it unreachable.
+  #[serde(default = "default_octoprint_timeout_ms")]
+  octoprint_timeout_ms: u64,
+
+  /// How long, in milliseconds, responses from octoprint are reused for.
+  #[serde(default = "default_octoprint_cache_ms")]
+  octoprint_cache_ms: u64,
+
   /// The location to send users _back_ to after successful oauth exchanges.
   auth_complete_uri: String,
 
@@ -61,6 +78,20 @@ pub struct Configuration {
   /// The key with our redis instance where we will store tokens.
   token_store: String,
 
+  /// The key with our redis instance where we will store the light state of each light
+  /// controller, by name.
+  #[serde(default = "default_light_state_store")]
+  light_state_store: String,
+
+  /// The key with our redis instance where we will store light scenes, by name.
+  #[serde(default = "default_scene_store")]
+  scene_store: String,
+
+  /// The key with our redis instance where we will store light schedules saved through the api, by
+  /// name.
+  #[serde(default = "default_schedule_store")]
+  schedule_store: String,
+
   /// The domain we're hosting from; used for cookies.
   domain: String,
 
@@ -73,11 +104,42 @@ pub struct Configuration {
   octoprint_stream_token: Option<String>,
 }
 
+/// The octoprint timeout used when none is configured.
+fn default_octoprint_timeout_ms() -> u64 {
+  5000
+}
+
+/// How long octoprint responses are reused for when not configured.
+fn default_octoprint_cache_ms() -> u64 {
+  2000
+}
+
+/// The redis key used for light states when none is configured.
+fn default_light_state_store() -> String {
+  "milton:light-state".into()
+}
+
+/// The redis key used for light scenes when none is configured.
+fn default_scene_store() -> String {
+  "milton:scenes".into()
+}
+
+/// The redis key used for light schedules when none is configured.
+fn default_schedule_store() -> String {
+  "milton:schedules".into()
+}
+
 /// The builder-pattern impl for our shared `State` type.
 #[derive(Default, Clone)]
 pub struct StateBuilder {
-  /// Outbound channel for side effects.
-  sender: Option<Sender<effects::Effects>>,
+  /// The bus that side effects are published on.
+  effects: Option<effects::Bus>,
+
+  /// The status of each light controller, shared with their runtimes.
+  lights: Option<crate::lights::SharedStatuses>,
+
+  /// The light schedules from the configuration.
+  schedules: Option<std::collections::BTreeMap<String, crate::schedule::Rule>>,
 
   /// Auth0 config.
   oauth: Option<oauth::AuthZeroConfig>,
@@ -103,9 +165,21 @@ impl StateBuilder {
     self
   }
 
-  /// Populates the side effect channel.
-  pub fn sender(mut self, chan: Sender<effects::Effects>) -> Self {
-    self.sender = Some(chan);
+  /// Populates the side effect bus.
+  pub fn effects(mut self, bus: effects::Bus) -> Self {
+    self.effects = Some(bus);
+    self
+  }
+
+  /// Populates the status of each light controller.
+  pub fn lights(mut self, statuses: crate::lights::SharedStatuses) -> Self {
+    self.lights = Some(statuses);
+    self
+  }
+
+  /// Populates the light schedules from the configuration; there are none when left out.
+  pub fn schedules(mut self, rules: std::collections::BTreeMap<String, crate::schedule::Rule>) -> Self {
+    self.schedules = Some(rules);
     self
   }
 
@@ -117,18 +191,27 @@ impl StateBuilder {
 
   /// Validates and returns a `State` instance.
   pub fn build(self) -> Result<State> {
-    let sender = self
-      .sender
-      .ok_or_else(|| Error::new(ErrorKind::Other, "missing sender"))?;
-    let oauth = self
-      .oauth
-      .ok_or_else(|| Error::new(ErrorKind::Other, "missing oauth config"))?;
+    let effects = self.effects.ok_or_else(|| Error::other("missing effect bus"))?;
+    let oauth = self.oauth.ok_or_else(|| Error::other("missing oauth config"))?;
+    let lights = self
+      .lights
+      .ok_or_else(|| Error::other("missing light controller status"))?;
     let config = self
       .config
       .ok_or_else(|| Error::new(ErrorKind::NotFound, "no ui config found"))?;
 
+    let octoprint = crate::octoprint::OctoprintClient::new(
+      config.octoprint_api_url.clone(),
+      config.octoprint_api_key.clone(),
+      std::time::Duration::from_millis(config.octoprint_timeout_ms),
+      std::time::Duration::from_millis(config.octoprint_cache_ms),
+    );
+
     Ok(State {
-      sender,
+      effects,
+      octoprint,
+      lights,
+      schedules: schedules::Schedules::new(self.schedules.unwrap_or_default()),
       oauth,
       config,
 
@@ -162,9 +245,18 @@ struct VideoState {
 /// this is `clone`-able.
 #[derive(Clone)]
 pub struct State {
-  /// The outbound effect channel that will be used to send side effects from web requests to a
-  /// central effect manager.
-  sender: Sender<effects::Effects>,
+  /// The bus that side effects from web requests are published on, for whoever has subscribed to
+  /// handle them.
+  effects: effects::Bus,
+
+  /// What we know about each light controller, kept up to date by their runtimes.
+  lights: crate::lights::SharedStatuses,
+
+  /// The light schedules from the configuration, and the signal for changes to the saved ones.
+  schedules: schedules::Schedules,
+
+  /// Fetches (and caches) the state of the printer from octoprint.
+  octoprint: crate::octoprint::OctoprintClient,
 
   /// General configuration. Should probably be cleaned up.
   pub(crate) config: Configuration,
@@ -251,6 +343,128 @@ impl State {
     None
   }
 
+  /// Returns the light state last saved for the light controller, if there is one.
+  pub async fn saved_light_state(&self, name: &str) -> Option<crate::lights::LightState> {
+    let command = kramer::Command::Hashes::<&str, &str>(kramer::HashCommand::Get(
+      &self.config.light_state_store,
+      Some(kramer::Arity::One(name)),
+    ));
+
+    match self.command(command).await {
+      Ok(kramer::Response::Item(kramer::ResponseValue::String(content))) => serde_json::from_str(&content)
+        .map_err(|error| log::warn!("unable to parse saved light state of '{name}' - {error}"))
+        .ok(),
+      Ok(_) => None,
+      Err(error) => {
+        log::warn!("unable to load saved light state of '{name}' - {error}");
+        None
+      }
+    }
+  }
+
+  /// Saves the light state of the light controller, so that it can be restored after a restart.
+  pub async fn save_light_state(&self, name: &str, state: &crate::lights::LightState) -> Result<()> {
+    let serialized = serde_json::to_string(state)?;
+    let command = kramer::Command::Hashes::<&str, &str>(kramer::HashCommand::Set(
+      &self.config.light_state_store,
+      kramer::Arity::One((name, &serialized)),
+      kramer::Insertion::Always,
+    ));
+
+    self.command(command).await.map(|_| ())
+  }
+
+  /// Returns the light scene saved with the name, if there is one.
+  pub(crate) async fn scene(&self, name: &str) -> Result<Option<scenes::Scene>> {
+    let command = kramer::Command::Hashes::<&str, &str>(kramer::HashCommand::Get(
+      &self.config.scene_store,
+      Some(kramer::Arity::One(name)),
+    ));
+
+    match self.command(command).await? {
+      kramer::Response::Item(kramer::ResponseValue::String(content)) => Ok(Some(serde_json::from_str(&content)?)),
+      _ => Ok(None),
+    }
+  }
+
+  /// Saves the light scene with the name, replacing any scene already saved with it.
+  pub(crate) async fn save_scene(&self, name: &str, scene: &scenes::Scene) -> Result<()> {
+    let serialized = serde_json::to_string(scene)?;
+    let command = kramer::Command::Hashes::<&str, &str>(kramer::HashCommand::Set(
+      &self.config.scene_store,
+      kramer::Arity::One((name, &serialized)),
+      kramer::Insertion::Always,
+    ));
+
+    self.command(command).await.map(|_| ())
+  }
+
+  /// Deletes the light scene saved with the name, returning false if there was no such scene.
+  pub(crate) async fn delete_scene(&self, name: &str) -> Result<bool> {
+    let command = kramer::Command::Hashes::<&str, &str>(kramer::HashCommand::Del(
+      &self.config.scene_store,
+      kramer::Arity::One(name),
+    ));
+
+    match self.command(command).await? {
+      kramer::Response::Item(kramer::ResponseValue::Integer(deleted)) => Ok(deleted > 0),
+      response => Err(Error::other(format!("unexpected response from redis - {response:?}"))),
+    }
+  }
+
+  /// Returns every light schedule saved through the api, by name. Saved rules that can no longer be
+  /// parsed are skipped.
+  pub(crate) async fn saved_schedules(&self) -> Result<std::collections::BTreeMap<String, crate::schedule::Rule>> {
+    let command = kramer::Command::Hashes::<&str, &str>(kramer::HashCommand::Get(&self.config.schedule_store, None));
+
+    let values = match self.command(command).await? {
+      kramer::Response::Array(values) => values,
+      kramer::Response::Item(kramer::ResponseValue::Empty) => vec![],
+      response => return Err(Error::other(format!("unexpected response from redis - {response:?}"))),
+    };
+
+    let mut rules = std::collections::BTreeMap::new();
+    let mut values = values.into_iter();
+
+    while let (Some(kramer::ResponseValue::String(name)), Some(kramer::ResponseValue::String(rule))) =
+      (values.next(), values.next())
+    {
+      match rule.parse::<crate::schedule::Rule>() {
+        Ok(rule) => {
+          rules.insert(name, rule);
+        }
+        Err(error) => log::warn!("skipping saved schedule '{name}' - {error}"),
+      }
+    }
+
+    Ok(rules)
+  }
+
+  /// Saves the light schedule with the name, replacing any schedule already saved with it.
+  pub(crate) async fn save_schedule(&self, name: &str, rule: &crate::schedule::Rule) -> Result<()> {
+    let serialized = rule.to_string();
+    let command = kramer::Command::Hashes::<&str, &str>(kramer::HashCommand::Set(
+      &self.config.schedule_store,
+      kramer::Arity::One((name, &serialized)),
+      kramer::Insertion::Always,
+    ));
+
+    self.command(command).await.map(|_| ())
+  }
+
+  /// Deletes the light schedule saved with the name, returning false if there was no such schedule.
+  pub(crate) async fn delete_schedule(&self, name: &str) -> Result<bool> {
+    let command = kramer::Command::Hashes::<&str, &str>(kramer::HashCommand::Del(
+      &self.config.schedule_store,
+      kramer::Arity::One(name),
+    ));
+
+    match self.command(command).await? {
+      kramer::Response::Item(kramer::ResponseValue::Integer(deleted)) => Ok(deleted > 0),
+      response => Err(Error::other(format!("unexpected response from redis - {response:?}"))),
+    }
+  }
+
   /// Returns the authority level based on the session data provided by our cookie. This is
   /// verified against our external oauth (auth0) provider.
   pub(crate) async fn authority<T>(&self, id: T) -> Option<Authority>
@@ -267,13 +481,9 @@ impl State {
   }
 
   /// Incoming web requests have the ability to create side effects that are handled elsewhere.
-  /// This method wraps the inner `channel` send.
+  /// This method wraps publishing them on the effect bus.
   pub(crate) async fn send(&self, effect: effects::Effects) -> Result<()> {
-    self
-      .sender
-      .send(effect)
-      .await
-      .map_err(|error| Error::new(ErrorKind::Other, error))
+    self.effects.publish(effect).await
   }
 }
 
@@ -296,6 +506,14 @@ fn claims(request: &Request<State>) -> Option<sec::Claims> {
   sec::Claims::decode(&cook.value(), &request.state().config.jwt_secret).ok()
 }
 
+/// Returns who is behind a request, so that light commands can be rate limited per requester; the
+/// user of the session, or `automated-admin` for requests authorized by admin token.
+pub(crate) fn requester(request: &Request<State>) -> String {
+  claims(request)
+    .map(|claims| claims.oid)
+    .unwrap_or_else(|| "automated-admin".to_string())
+}
+
 /// The minimal url query structure we need to deserialize into for automated admin authorization
 /// status.
 #[derive(Deserialize)]
@@ -344,9 +562,13 @@ pub(crate) async fn authority(request: &Request<State>) -> Option<Authority> {
   None
 }
 
+/// The json-serializable response structure for our heartbeat endpoint.
 #[derive(Serialize)]
 struct Heartbeat<'a> {
+  /// The current time.
   time: chrono::DateTime<chrono::Utc>,
+
+  /// The compile time version value.
   version: &'a String,
 }
 
@@ -495,6 +717,19 @@ where
   app.at("/control/video-stream").get(control::stream);
   app.at("/control/video-snapshot").get(control::snapshot);
 
+  app.at("/printer/status").get(printer::status);
+  app.at("/printer/temperatures").get(printer::temperatures);
+
+  app.at("/lights/status").get(lights::status);
+  app.at("/lights/state").get(lights::state);
+  app.at("/lights/scenes/:name").get(scenes::get);
+  app.at("/lights/scenes/:name").put(scenes::put);
+  app.at("/lights/scenes/:name").delete(scenes::delete);
+  app.at("/schedules").get(schedules::list);
+  app.at("/schedules/upcoming").get(schedules::upcoming);
+  app.at("/schedules/:name").put(schedules::put);
+  app.at("/schedules/:name").delete(schedules::delete);
+
   app.at("/auth/start").get(auth::start);
   app.at("/auth/end").get(auth::end);
   app.at("/auth/complete").get(auth::complete);
@@ -0,0 +1,100 @@
+use serde::Serialize;
+use tide::{Request, Response, Result};
+
+use crate::octoprint::{self, OctoprintError};
+use crate::server::State;
+
+/// The state of the printer and its connection to octoprint.
+#[derive(Debug, Serialize)]
+struct PrinterStatus {
+  /// The port and baudrate octoprint is connected to the printer with, if it is.
+  connection: Option<octoprint::OctoprintConnection>,
+
+  /// What the printer is doing; absent while octoprint is not connected to the printer.
+  state: Option<octoprint::OctoprintPrinterState>,
+
+  /// The state of the printer's sd card; absent while octoprint is not connected to the printer.
+  sd: Option<octoprint::OctoprintSdState>,
+
+  /// When this was fetched from octoprint.
+  updated: chrono::DateTime<chrono::Utc>,
+
+  /// Whether octoprint is currently unreachable, making this the last status we know of.
+  stale: bool,
+}
+
+/// The temperatures of the printer's heaters.
+#[derive(Debug, Serialize)]
+struct PrinterTemperatures {
+  /// The actual and target temperature of each heater, by name (e.g `tool0` and `bed`).
+  temperatures: octoprint::OctoprintTemperatures,
+
+  /// When these were fetched from octoprint.
+  updated: chrono::DateTime<chrono::Utc>,
+
+  /// Whether octoprint is currently unreachable, making these the last temperatures we know of.
+  stale: bool,
+}
+
+/// Maps a failure to talk to octoprint to a route error.
+pub(super) fn unavailable(error: OctoprintError) -> tide::Error {
+  log::warn!("{error}");
+
+  match error {
+    OctoprintError::Unreachable(_) => tide::Error::from_str(503, "octoprint-unreachable"),
+    OctoprintError::NotConnected => tide::Error::from_str(409, "printer-not-connected"),
+    OctoprintError::Unauthorized => tide::Error::from_str(502, "octoprint-unauthorized"),
+    OctoprintError::BadStatus(_) | OctoprintError::BadPayload(_) => {
+      tide::Error::from_str(502, "octoprint-bad-response")
+    }
+  }
+}
+
+/// ROUTE: returns the state of the printer, along with how octoprint is connected to it.
+pub async fn status(request: Request<State>) -> Result {
+  super::authority(&request).await.ok_or_else(|| {
+    log::warn!("unauthorized attempt to query printer status");
+    tide::Error::from_str(404, "not-found")
+  })?;
+
+  let client = &request.state().octoprint;
+  let (connection, printer) = futures::future::join(client.connection(), client.printer()).await;
+  let connection = connection.map_err(unavailable)?;
+
+  let status = match printer {
+    Ok(printer) => PrinterStatus {
+      connection: connection.value.current,
+      state: printer.value.state,
+      sd: printer.value.sd,
+      updated: connection.updated.min(printer.updated),
+      stale: connection.stale || printer.stale,
+    },
+    Err(OctoprintError::NotConnected) => PrinterStatus {
+      connection: connection.value.current,
+      state: None,
+      sd: None,
+      updated: connection.updated,
+      stale: connection.stale,
+    },
+    Err(error) => return Err(unavailable(error)),
+  };
+
+  tide::Body::from_json(&status).map(|bod| Response::builder(200).body(bod).build())
+}
+
+/// ROUTE: returns the actual and target temperature of each of the printer's heaters.
+pub async fn temperatures(request: Request<State>) -> Result {
+  super::authority(&request).await.ok_or_else(|| {
+    log::warn!("unauthorized attempt to query printer temperatures");
+    tide::Error::from_str(404, "not-found")
+  })?;
+
+  let printer = request.state().octoprint.printer().await.map_err(unavailable)?;
+  let temperatures = PrinterTemperatures {
+    temperatures: printer.value.temperature.unwrap_or_default(),
+    updated: printer.updated,
+    stale: printer.stale,
+  };
+
+  tide::Body::from_json(&temperatures).map(|bod| Response::builder(200).body(bod).build())
+}
@@ -0,0 +1,124 @@
+//! Scenes are named presets of how the lights should look (e.g "camera", "night" or "print-done"),
+//! saved in redis and activated with a `"kind": "scene"` control request.
+
+use serde::{Deserialize, Serialize};
+use tide::{Request, Response, Result};
+
+use crate::server::State;
+
+/// A saved preset of how the lights should look, by light controller name. Activating a scene sends
+/// each light controller in it the commands that bring it to its light state.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Scene {
+  /// The light state of each light controller in the scene; light controllers left out are left
+  /// alone when the scene is activated.
+  pub lights: std::collections::BTreeMap<String, crate::lights::LightState>,
+}
+
+/// The json payload accepted when saving a scene.
+#[derive(Debug, Default, Deserialize)]
+struct SceneRequest {
+  /// The light state of each light controller in the scene; when absent, the scene is saved with
+  /// the current light state of every light controller.
+  lights: Option<std::collections::BTreeMap<String, crate::lights::LightState>>,
+}
+
+/// Returns the name of the scene from the request url.
+fn scene_name(request: &Request<State>) -> Result<String> {
+  request
+    .param("name")
+    .map(|name| name.to_string())
+    .map_err(|_| tide::Error::from_str(404, "scene-not-found"))
+}
+
+/// Maps failures to reach redis to the `scenes-unavailable` error.
+fn unavailable(error: std::io::Error) -> tide::Error {
+  log::warn!("unable to access scene store - {error}");
+  tide::Error::from_str(500, "scenes-unavailable")
+}
+
+/// ROUTE: returns the scene saved with the name.
+pub async fn get(request: Request<State>) -> Result {
+  super::authority(&request).await.ok_or_else(|| {
+    log::warn!("unauthorized attempt to query light scene");
+    tide::Error::from_str(404, "not-found")
+  })?;
+
+  let name = scene_name(&request)?;
+  let scene = request
+    .state()
+    .scene(&name)
+    .await
+    .map_err(unavailable)?
+    .ok_or_else(|| tide::Error::from_str(404, "scene-not-found"))?;
+
+  tide::Body::from_json(&scene).map(|bod| Response::builder(200).body(bod).build())
+}
+
+/// ROUTE: saves a scene with the name, replacing any scene already saved with it. The scene is
+/// either provided in the payload or, when the payload is empty, taken from the current light
+/// state of every light controller.
+pub async fn put(mut request: Request<State>) -> Result {
+  super::authority(&request).await.ok_or_else(|| {
+    log::warn!("unauthorized attempt to save light scene");
+    tide::Error::from_str(404, "not-found")
+  })?;
+
+  let name = scene_name(&request)?;
+  let body = request.body_string().await?;
+  let payload = match body.trim() {
+    "" => SceneRequest::default(),
+    body => serde_json::from_str::<SceneRequest>(body).map_err(|error| {
+      log::warn!("unable to parse scene payload - {error}");
+      tide::Error::from_str(422, "bad-payload")
+    })?,
+  };
+
+  let state = request.state();
+
+  let lights = match payload.lights {
+    Some(lights) => {
+      if let Some(unknown) = lights.keys().find(|controller| !state.lights.contains_key(*controller)) {
+        log::warn!("scene '{name}' names unknown light controller '{unknown}'");
+        return Err(tide::Error::from_str(422, "bad-target"));
+      }
+
+      lights
+        .into_iter()
+        .map(|(controller, lights)| (controller, lights.normalized()))
+        .collect()
+    }
+    None => {
+      let mut lights = std::collections::BTreeMap::new();
+
+      for (controller, status) in &state.lights {
+        lights.insert(controller.clone(), status.read().await.state.clone());
+      }
+
+      lights
+    }
+  };
+
+  let scene = Scene { lights };
+  state.save_scene(&name, &scene).await.map_err(unavailable)?;
+  log::info!("saved light scene '{name}'");
+
+  tide::Body::from_json(&scene).map(|bod| Response::builder(200).body(bod).build())
+}
+
+/// ROUTE: deletes the scene saved with the name.
+pub async fn delete(request: Request<State>) -> Result {
+  super::authority(&request).await.ok_or_else(|| {
+    log::warn!("unauthorized attempt to delete light scene");
+    tide::Error::from_str(404, "not-found")
+  })?;
+
+  let name = scene_name(&request)?;
+
+  if !request.state().delete_scene(&name).await.map_err(unavailable)? {
+    return Err(tide::Error::from_str(404, "scene-not-found"));
+  }
+
+  log::info!("deleted light scene '{name}'");
+  Ok(Response::new(204))
+}
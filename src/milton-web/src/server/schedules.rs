@@ -0,0 +1,359 @@
+//! Schedules are named time-based rules (see `crate::schedule`) that send light commands through
+//! the effect bus when they fire. Rules come from the `[schedules]` table of the configuration, or
+//! are saved in redis through the api; configured rules cannot be changed through the api.
+
+use async_std::prelude::FutureExt;
+use serde::{Deserialize, Serialize};
+use tide::{Request, Response, Result};
+
+use crate::schedule::{Action, Rule};
+use crate::server::State;
+
+/// The furthest ahead, in hours, that upcoming firings can be listed.
+const MAX_UPCOMING_HOURS: u32 = 24 * 7;
+
+/// The configured rules, along with the signal used to wake the scheduler when saved rules change.
+#[derive(Debug, Clone)]
+pub struct Schedules {
+  /// The rules from the configuration, by name.
+  configured: std::sync::Arc<std::collections::BTreeMap<String, Rule>>,
+
+  /// Notified whenever a rule is saved or deleted through the api.
+  changed: async_std::channel::Sender<()>,
+
+  /// Received on by the scheduler.
+  changes: async_std::channel::Receiver<()>,
+}
+
+impl Schedules {
+  /// Creates the schedules with the rules from the configuration.
+  pub fn new(configured: std::collections::BTreeMap<String, Rule>) -> Self {
+    let (changed, changes) = async_std::channel::bounded(1);
+
+    Self {
+      configured: std::sync::Arc::new(configured),
+      changed,
+      changes,
+    }
+  }
+
+  /// Wakes the scheduler up to pick up a change to the saved rules.
+  fn notify(&self) {
+    // A notification that is already waiting will pick this change up too.
+    if self.changed.try_send(()).is_err() {
+      log::debug!("scheduler already notified of a schedule change");
+    }
+  }
+}
+
+/// A rule, along with where it came from.
+#[derive(Debug, Clone, Serialize)]
+struct Schedule {
+  /// The name of the rule.
+  name: String,
+
+  /// The rule itself.
+  rule: Rule,
+
+  /// Whether the rule comes from the configuration (and so cannot be changed through the api).
+  configured: bool,
+
+  /// The next time the rule fires.
+  next: Option<chrono::DateTime<chrono::Local>>,
+}
+
+/// Returns every rule, configured rules first; saved rules that share a name with a configured one
+/// are ignored.
+async fn schedules(state: &State) -> std::io::Result<Vec<Schedule>> {
+  let now = chrono::Local::now();
+  let saved = state.saved_schedules().await?;
+
+  let configured = state.schedules.configured.iter().map(|(name, rule)| (name, rule, true));
+  let saved = saved
+    .iter()
+    .filter(|(name, _)| !state.schedules.configured.contains_key(*name))
+    .map(|(name, rule)| (name, rule, false));
+
+  Ok(
+    configured
+      .chain(saved)
+      .map(|(name, rule, configured)| Schedule {
+        name: name.clone(),
+        rule: rule.clone(),
+        configured,
+        next: rule.next_after(&now),
+      })
+      .collect(),
+  )
+}
+
+/// Sends the light commands for an action through the effect bus, targeting the light controller
+/// named by `target` (or the first one configured); `source` describes who performed the action, for
+/// logging. Nobody waits on the outcome of the commands, which is only logged.
+pub(super) async fn perform(state: &State, action: &Action, target: Option<String>, source: &str) {
+  let commands = match action.command() {
+    Some(command) => vec![match target {
+      Some(target) => crate::lights::Command::Target(target, Box::new(command)),
+      None => command,
+    }],
+    None => match action {
+      Action::Scene(scene) => match super::control::scene_commands(state, scene, None, target).await {
+        Ok(commands) => commands,
+        Err(error) => {
+          log::warn!("unable to activate scene '{scene}' for {source} - {error}");
+          return;
+        }
+      },
+      _ => {
+        log::warn!("{source} has no commands for '{action}'");
+        return;
+      }
+    },
+  };
+
+  for command in commands {
+    let (request, outcome) = crate::lights::Request::tracked(command);
+    let request = request.requested_by(source.to_string());
+
+    if let Err(error) = state.send(super::effects::Effects::Lights(request)).await {
+      log::warn!("unable to send light command for {source} - {error}");
+      continue;
+    }
+
+    let source = source.to_string();
+    async_std::task::spawn(async move {
+      match outcome.recv().await {
+        Ok(crate::lights::Outcome::Acknowledged | crate::lights::Outcome::Superseded) => (),
+        Ok(outcome) => log::warn!("light command for {source} resolved as {outcome:?}"),
+        Err(error) => log::warn!("light command for {source} dropped - {error}"),
+      }
+    });
+  }
+}
+
+/// The scheduler; sleeps until the next rule fires, or until the saved rules change. Rules that
+/// were due while the server was not running are not fired.
+pub async fn run(state: State) -> std::io::Result<()> {
+  log::info!("starting light scheduler");
+  let mut checked = chrono::Local::now();
+
+  loop {
+    let rules = match schedules(&state).await {
+      Ok(rules) => rules,
+      Err(error) => {
+        // Saved rules may be unavailable for a while; the configured rules still fire.
+        log::warn!("unable to load saved schedules - {error}");
+        state
+          .schedules
+          .configured
+          .iter()
+          .map(|(name, rule)| Schedule {
+            name: name.clone(),
+            rule: rule.clone(),
+            configured: true,
+            next: None,
+          })
+          .collect()
+      }
+    };
+
+    let now = chrono::Local::now();
+
+    for schedule in &rules {
+      if schedule
+        .rule
+        .next_after(&checked)
+        .map(|firing| firing <= now)
+        .unwrap_or(false)
+      {
+        log::info!("schedule '{}' fired - {}", schedule.name, schedule.rule);
+        let source = format!("schedule '{}'", schedule.name);
+        perform(&state, &schedule.rule.action, schedule.rule.target.clone(), &source).await;
+      }
+    }
+
+    checked = now;
+
+    let wake = rules
+      .iter()
+      .filter_map(|schedule| schedule.rule.next_after(&now))
+      .min()
+      .and_then(|firing| (firing - now).to_std().ok());
+
+    let changed = async {
+      if state.schedules.changes.recv().await.is_err() {
+        log::warn!("schedule change channel closed");
+        async_std::future::pending::<()>().await;
+      }
+    };
+    let due = async {
+      match wake {
+        Some(duration) => async_std::task::sleep(duration).await,
+        None => async_std::future::pending().await,
+      }
+    };
+
+    changed.race(due).await;
+  }
+}
+
+/// Returns the name of the rule from the request url.
+fn schedule_name(request: &Request<State>) -> Result<String> {
+  request
+    .param("name")
+    .map(|name| name.to_string())
+    .map_err(|_| tide::Error::from_str(404, "schedule-not-found"))
+}
+
+/// Maps failures to reach redis to the `schedules-unavailable` error.
+fn unavailable(error: std::io::Error) -> tide::Error {
+  log::warn!("unable to access schedule store - {error}");
+  tide::Error::from_str(500, "schedules-unavailable")
+}
+
+/// ROUTE: returns every rule, soonest to fire first.
+pub async fn list(request: Request<State>) -> Result {
+  super::authority(&request).await.ok_or_else(|| {
+    log::warn!("unauthorized attempt to query schedules");
+    tide::Error::from_str(404, "not-found")
+  })?;
+
+  let mut rules = schedules(request.state()).await.map_err(unavailable)?;
+  rules.sort_by_key(|schedule| schedule.next);
+
+  tide::Body::from_json(&rules).map(|bod| Response::builder(200).body(bod).build())
+}
+
+/// The url query accepted when listing upcoming firings.
+#[derive(Debug, Deserialize)]
+struct UpcomingQuery {
+  /// How far ahead, in hours, to list firings; defaults to a day.
+  hours: Option<u32>,
+}
+
+/// A single time a rule will fire.
+#[derive(Debug, Serialize)]
+struct Firing {
+  /// When the rule fires.
+  at: chrono::DateTime<chrono::Local>,
+
+  /// The name of the rule.
+  name: String,
+
+  /// The rule itself.
+  rule: Rule,
+}
+
+/// ROUTE: returns every time a rule fires over the next day (or `?hours=`), in order.
+pub async fn upcoming(request: Request<State>) -> Result {
+  super::authority(&request).await.ok_or_else(|| {
+    log::warn!("unauthorized attempt to query upcoming schedules");
+    tide::Error::from_str(404, "not-found")
+  })?;
+
+  let query = request.query::<UpcomingQuery>().map_err(|error| {
+    log::warn!("unable to parse upcoming schedule query - {error}");
+    tide::Error::from_str(422, "bad-payload")
+  })?;
+  let hours = query.hours.unwrap_or(24).min(MAX_UPCOMING_HOURS);
+  let now = chrono::Local::now();
+  let until = now + chrono::Duration::hours(i64::from(hours));
+
+  let mut firings = vec![];
+
+  for schedule in schedules(request.state()).await.map_err(unavailable)? {
+    let mut after = now;
+
+    while let Some(at) = schedule.rule.next_after(&after).filter(|at| *at <= until) {
+      firings.push(Firing {
+        at,
+        name: schedule.name.clone(),
+        rule: schedule.rule.clone(),
+      });
+      after = at;
+    }
+  }
+
+  firings.sort_by_key(|firing| firing.at);
+
+  tide::Body::from_json(&firings).map(|bod| Response::builder(200).body(bod).build())
+}
+
+/// The json payload accepted when saving a rule.
+#[derive(Debug, Deserialize)]
+struct ScheduleRequest {
+  /// The rule, e.g `weekdays 07:30 scene=morning`.
+  rule: String,
+}
+
+/// ROUTE: saves a rule with the name, replacing any rule already saved with it.
+pub async fn put(mut request: Request<State>) -> Result {
+  super::authority(&request).await.ok_or_else(|| {
+    log::warn!("unauthorized attempt to save schedule");
+    tide::Error::from_str(404, "not-found")
+  })?;
+
+  let name = schedule_name(&request)?;
+  let ScheduleRequest { rule } = request.body_json::<ScheduleRequest>().await.map_err(|error| {
+    log::warn!("unable to parse schedule payload - {error}");
+    tide::Error::from_str(422, "bad-payload")
+  })?;
+
+  let rule = rule.parse::<Rule>().map_err(|error| {
+    log::warn!("{error}");
+    tide::Error::from_str(422, "bad-rule")
+  })?;
+
+  let state = request.state();
+
+  if state.schedules.configured.contains_key(&name) {
+    log::warn!("attempt to replace configured schedule '{name}'");
+    return Err(tide::Error::from_str(409, "schedule-configured"));
+  }
+
+  if let Some(target) = rule
+    .target
+    .as_ref()
+    .filter(|target| !state.lights.contains_key(*target))
+  {
+    log::warn!("schedule '{name}' targets unknown light controller '{target}'");
+    return Err(tide::Error::from_str(422, "bad-target"));
+  }
+
+  state.save_schedule(&name, &rule).await.map_err(unavailable)?;
+  state.schedules.notify();
+  log::info!("saved schedule '{name}' - {rule}");
+
+  let schedule = Schedule {
+    next: rule.next_after(&chrono::Local::now()),
+    name,
+    rule,
+    configured: false,
+  };
+
+  tide::Body::from_json(&schedule).map(|bod| Response::builder(200).body(bod).build())
+}
+
+/// ROUTE: deletes the rule saved with the name.
+pub async fn delete(request: Request<State>) -> Result {
+  super::authority(&request).await.ok_or_else(|| {
+    log::warn!("unauthorized attempt to delete schedule");
+    tide::Error::from_str(404, "not-found")
+  })?;
+
+  let name = schedule_name(&request)?;
+  let state = request.state();
+
+  if state.schedules.configured.contains_key(&name) {
+    log::warn!("attempt to delete configured schedule '{name}'");
+    return Err(tide::Error::from_str(409, "schedule-configured"));
+  }
+
+  if !state.delete_schedule(&name).await.map_err(unavailable)? {
+    return Err(tide::Error::from_str(404, "schedule-not-found"));
+  }
+
+  state.schedules.notify();
+  log::info!("deleted schedule '{name}'");
+  Ok(Response::new(204))
+}
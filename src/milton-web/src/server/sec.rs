@@ -46,7 +46,7 @@ impl Claims {
     jsonwebtoken::decode::<Self>(token.as_str(), &key, &validation)
       .map_err(|error| {
         log::warn!("unable to decode token - {}", error);
-        std::io::Error::new(std::io::ErrorKind::Other, "bad-jwt")
+        std::io::Error::other("bad-jwt")
       })
       .map(|data| data.claims)
   }
@@ -61,7 +61,7 @@ impl Claims {
 
     jsonwebtoken::encode(header, &self, &secret).map_err(|error| {
       log::warn!("unable to encode token - {}", error);
-      std::io::Error::new(std::io::ErrorKind::Other, "bad-jwt")
+      std::io::Error::other("bad-jwt")
     })
   }
 }
@@ -0,0 +1,333 @@
+//! The links that bytes travel over between the light runtime and a light controller. Light
+//! controllers are usually attached over usb serial, but can also be reached over tcp (an esp32 on
+//! the LAN, or a serial port shared by a ser2net bridge), or in memory when there is no hardware at
+//! all. Reconnecting is the same as opening; the light runtime drops a transport that fails and
+//! opens a new one.
+//!
+//! Transports block; the light runtime reads from and writes to them on dedicated threads.
+
+use serde::Deserialize;
+use std::io::{self, Read, Result, Write};
+
+/// How long a read waits for bytes before giving up; this bounds how long a reading thread takes
+/// to notice that its connection has been dropped.
+pub const READ_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// How long to wait for a tcp connection to be established, or for a write to be accepted.
+const TCP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Enumerates the supported transports, selected by the `transport` field of a light controller's
+/// configuration.
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+  /// A serial port, found by `device` or `usb`.
+  #[default]
+  Serial,
+
+  /// A tcp connection to `address`, e.g `192.168.1.20:3333`.
+  Tcp,
+
+  /// An in-memory link to whoever is listening at `address`; see `Memory::listen`.
+  Memory,
+}
+
+/// A bidirectional byte stream to a light controller.
+pub trait Transport: Send {
+  /// Waits up to `READ_TIMEOUT` for bytes, reading whatever arrives into the buffer and returning
+  /// the number of bytes read; zero means nothing arrived in time.
+  fn read(&mut self, buffer: &mut [u8]) -> Result<usize>;
+
+  /// Writes every byte to the light controller.
+  fn write_all(&mut self, bytes: &[u8]) -> Result<()>;
+
+  /// Returns another handle to the same link, so that reads and writes can happen on different
+  /// threads.
+  fn try_clone(&self) -> Result<Box<dyn Transport>>;
+}
+
+/// Timeouts are reported as errors by the standard library; for transports, they just mean that
+/// nothing arrived.
+fn nothing_on_timeout(result: Result<usize>) -> Result<usize> {
+  match result {
+    Err(error) if matches!(error.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock) => Ok(0),
+    other => other,
+  }
+}
+
+/// A light controller attached to a serial port.
+pub struct Serial {
+  /// The underlying serial port.
+  port: Box<dyn serialport::SerialPort>,
+}
+
+impl Serial {
+  /// Opens the serial port at the path.
+  pub fn open(path: &str, baud: u32) -> Result<Self> {
+    let port = serialport::new(path, baud).timeout(READ_TIMEOUT).open()?;
+    Ok(Self { port })
+  }
+}
+
+impl Transport for Serial {
+  fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+    nothing_on_timeout(self.port.read(buffer))
+  }
+
+  fn write_all(&mut self, bytes: &[u8]) -> Result<()> {
+    self.port.write_all(bytes)
+  }
+
+  fn try_clone(&self) -> Result<Box<dyn Transport>> {
+    Ok(Box::new(Self {
+      port: self.port.try_clone()?,
+    }))
+  }
+}
+
+/// A light controller reachable over tcp.
+pub struct Tcp {
+  /// The underlying stream.
+  stream: std::net::TcpStream,
+}
+
+impl Tcp {
+  /// Connects to the address, e.g `192.168.1.20:3333`.
+  pub fn open(address: &str) -> Result<Self> {
+    let address = std::net::ToSocketAddrs::to_socket_addrs(address)?
+      .next()
+      .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("unable to resolve '{address}'")))?;
+    let stream = std::net::TcpStream::connect_timeout(&address, TCP_TIMEOUT)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+    Ok(Self { stream })
+  }
+}
+
+impl Transport for Tcp {
+  fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+    match self.stream.read(buffer) {
+      Ok(0) if !buffer.is_empty() => Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "light controller closed the connection",
+      )),
+      other => nothing_on_timeout(other),
+    }
+  }
+
+  fn write_all(&mut self, bytes: &[u8]) -> Result<()> {
+    self.stream.write_all(bytes)
+  }
+
+  fn try_clone(&self) -> Result<Box<dyn Transport>> {
+    Ok(Box::new(Self {
+      stream: self.stream.try_clone()?,
+    }))
+  }
+}
+
+/// One direction of an in-memory link.
+#[derive(Debug, Default)]
+struct Pipe {
+  /// Bytes written but not yet read.
+  bytes: std::collections::VecDeque<u8>,
+
+  /// Set once either end of the link is dropped.
+  closed: bool,
+}
+
+/// One direction of an in-memory link, along with the signal used to wake a reader waiting on it.
+#[derive(Debug, Default)]
+struct SharedPipe {
+  /// The pipe itself.
+  pipe: std::sync::Mutex<Pipe>,
+
+  /// Notified whenever bytes are written or the pipe is closed.
+  written: std::sync::Condvar,
+}
+
+impl SharedPipe {
+  /// Locks the pipe; a pipe whose lock was poisoned is treated as closed.
+  fn lock(&self) -> Result<std::sync::MutexGuard<'_, Pipe>> {
+    self
+      .pipe
+      .lock()
+      .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "in-memory link poisoned"))
+  }
+
+  /// Marks the pipe as closed, waking anyone waiting to read from it.
+  fn close(&self) {
+    if let Ok(mut pipe) = self.pipe.lock() {
+      pipe.closed = true;
+    }
+
+    self.written.notify_all();
+  }
+}
+
+/// Both directions of one end of an in-memory link. The link is closed once every handle to either
+/// end has been dropped.
+#[derive(Debug)]
+struct Link {
+  /// The bytes sent to this end.
+  inbound: std::sync::Arc<SharedPipe>,
+
+  /// The bytes sent from this end.
+  outbound: std::sync::Arc<SharedPipe>,
+}
+
+impl Drop for Link {
+  fn drop(&mut self) {
+    self.inbound.close();
+    self.outbound.close();
+  }
+}
+
+/// Either end of an in-memory link. The end handed to the light runtime is opened with `connect`,
+/// the other end, accepted from the `MemoryListener` returned by `listen`, plays the part of the
+/// light controller.
+#[derive(Debug, Clone)]
+pub struct Memory {
+  /// The link, shared by every handle to this end.
+  link: std::sync::Arc<Link>,
+}
+
+/// Hands the light controller end of every link opened to an address to its listener.
+type Acceptor = std::sync::mpsc::Sender<Memory>;
+
+/// The listeners waiting for in-memory links, by address.
+static LISTENERS: std::sync::Mutex<Option<std::collections::HashMap<String, Acceptor>>> = std::sync::Mutex::new(None);
+
+/// Locks the listeners; a lock that was poisoned is reported as an error.
+fn listeners() -> Result<std::sync::MutexGuard<'static, Option<std::collections::HashMap<String, Acceptor>>>> {
+  LISTENERS
+    .lock()
+    .map_err(|_| io::Error::other("in-memory listeners poisoned"))
+}
+
+impl Memory {
+  /// Starts listening for in-memory links at the address. Every `connect` to the address opens a
+  /// new link, so the light runtime can reconnect for as long as the listener is kept around.
+  pub fn listen(address: &str) -> Result<MemoryListener> {
+    let (acceptor, accepted) = std::sync::mpsc::channel();
+    let mut listeners = listeners()?;
+    let listeners = listeners.get_or_insert_with(Default::default);
+
+    if listeners.contains_key(address) {
+      return Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!("already listening at '{address}'"),
+      ));
+    }
+
+    listeners.insert(address.to_string(), acceptor);
+
+    Ok(MemoryListener {
+      address: address.to_string(),
+      accepted,
+    })
+  }
+
+  /// Opens a new in-memory link to whoever is listening at the address.
+  pub fn connect(address: &str) -> Result<Self> {
+    let (inbound, outbound) = (
+      std::sync::Arc::<SharedPipe>::default(),
+      std::sync::Arc::<SharedPipe>::default(),
+    );
+    let controller = Self {
+      link: std::sync::Arc::new(Link {
+        inbound: outbound.clone(),
+        outbound: inbound.clone(),
+      }),
+    };
+
+    listeners()?
+      .as_ref()
+      .and_then(|listeners| listeners.get(address))
+      .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("nothing listening at '{address}'")))?
+      .send(controller)
+      .map_err(|_| {
+        io::Error::new(
+          io::ErrorKind::ConnectionRefused,
+          format!("nothing accepting at '{address}'"),
+        )
+      })?;
+
+    Ok(Self {
+      link: std::sync::Arc::new(Link { inbound, outbound }),
+    })
+  }
+}
+
+/// Accepts the in-memory links opened to an address; stops listening once dropped.
+#[derive(Debug)]
+pub struct MemoryListener {
+  /// The address being listened at.
+  address: String,
+
+  /// The light controller end of every link opened to the address, in the order they were opened.
+  accepted: std::sync::mpsc::Receiver<Memory>,
+}
+
+impl MemoryListener {
+  /// Waits up to the timeout for the next link to be opened, returning the end that plays the part
+  /// of the light controller.
+  pub fn accept(&self, timeout: std::time::Duration) -> Result<Memory> {
+    self.accepted.recv_timeout(timeout).map_err(|error| match error {
+      std::sync::mpsc::RecvTimeoutError::Timeout => io::Error::new(io::ErrorKind::TimedOut, "no in-memory link opened"),
+      std::sync::mpsc::RecvTimeoutError::Disconnected => io::Error::other("in-memory listener closed"),
+    })
+  }
+}
+
+impl Drop for MemoryListener {
+  fn drop(&mut self) {
+    if let Ok(mut listeners) = listeners() {
+      if let Some(listeners) = listeners.as_mut() {
+        listeners.remove(&self.address);
+      }
+    }
+  }
+}
+
+impl Transport for Memory {
+  fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+    let inbound = &self.link.inbound;
+    let (mut pipe, _) = inbound
+      .written
+      .wait_timeout_while(inbound.lock()?, READ_TIMEOUT, |pipe| {
+        pipe.bytes.is_empty() && !pipe.closed
+      })
+      .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "in-memory link poisoned"))?;
+
+    if pipe.bytes.is_empty() && pipe.closed {
+      return Err(io::Error::new(io::ErrorKind::BrokenPipe, "in-memory link closed"));
+    }
+
+    let size = buffer.len().min(pipe.bytes.len());
+
+    for (slot, byte) in buffer.iter_mut().zip(pipe.bytes.drain(..size)) {
+      *slot = byte;
+    }
+
+    Ok(size)
+  }
+
+  fn write_all(&mut self, bytes: &[u8]) -> Result<()> {
+    let outbound = &self.link.outbound;
+    let mut pipe = outbound.lock()?;
+
+    if pipe.closed {
+      return Err(io::Error::new(io::ErrorKind::BrokenPipe, "in-memory link closed"));
+    }
+
+    pipe.bytes.extend(bytes);
+    outbound.written.notify_all();
+    Ok(())
+  }
+
+  fn try_clone(&self) -> Result<Box<dyn Transport>> {
+    Ok(Box::new(self.clone()))
+  }
+}